@@ -39,6 +39,8 @@ mod instruction_parser {
 
 mod code_parser {
     pub fn parse_comp(comp: &str) -> String {
+        // Commutative operations are accepted with their operands in either
+        // order, as hand-written code may use e.g. M=M+D
         let parsed_comp = match comp {
             "0" => "0101010",
             "1" => "0111111",
@@ -58,16 +60,16 @@ mod code_parser {
             "D-1" => "0001110",
             "A-1" => "0110010",
             "M-1" => "1110010",
-            "D+A" => "0000010",
-            "D+M" => "1000010",
+            "D+A" | "A+D" => "0000010",
+            "D+M" | "M+D" => "1000010",
             "D-A" => "0010011",
             "D-M" => "1010011",
             "A-D" => "0000111",
             "M-D" => "1000111",
-            "D&A" => "0000000",
-            "D&M" => "1000000",
-            "D|A" => "0010101",
-            "D|M" => "1010101",
+            "D&A" | "A&D" => "0000000",
+            "D&M" | "M&D" => "1000000",
+            "D|A" | "A|D" => "0010101",
+            "D|M" | "M|D" => "1010101",
             _ => panic!("Couldn't parse comp instruction: {}", comp),
         };
         String::from(parsed_comp)
@@ -109,6 +111,9 @@ mod symbol_table {
     pub struct SymbolTable {
         table: HashMap<String, u16>,
         mem_counter: u16,
+        // The symbols added while assembling, in the order they were added
+        pub labels: Vec<(String, u16)>,
+        pub variables: Vec<(String, u16)>,
     }
 
     impl SymbolTable {
@@ -140,6 +145,8 @@ mod symbol_table {
                     (String::from("R15"), 15),
                 ]),
                 mem_counter: 16,
+                labels: Vec::new(),
+                variables: Vec::new(),
             }
         }
 
@@ -147,6 +154,7 @@ mod symbol_table {
             // Used in first pass for label symbols: given a label name and
             // instruction number, store it in the symbol table.
             self.table.insert(String::from(name), value);
+            self.labels.push((String::from(name), value));
         }
 
         pub fn maybe_add_and_return(&mut self, name: &str) -> u16 {
@@ -156,6 +164,7 @@ mod symbol_table {
             // value stored in the table for the symbol name.
             if !self.table.contains_key(name) {
                 self.table.insert(String::from(name), self.mem_counter);
+                self.variables.push((String::from(name), self.mem_counter));
                 self.mem_counter += 1;
             }
             self.table[name]
@@ -189,12 +198,36 @@ pub fn write_lines(outfile: &str, binary_output: &Vec<String>) {
         .expect(&format!("Failed to write hack output to {}", outfile));
 }
 
+#[derive(Debug, Default)]
+pub struct Assembly {
+    pub hack: Vec<String>,
+    // Label symbols and their ROM address, in order of definition
+    pub labels: Vec<(String, u16)>,
+    // Variable symbols and their RAM address, in order of allocation
+    pub variables: Vec<(String, u16)>,
+}
+
 pub fn assemble(infile: &str) -> Vec<String> {
     let lines = read_lines(infile);
+    let binary_output = assemble_lines(&lines).hack;
+    binary_output
+}
+
+pub fn assemble_lines(lines: &[String]) -> Assembly {
+    // Assembles lines of Hack assembly held in memory, such as the output of
+    // the VM translator. Comments and whitespace are ignored.
+    let lines: Vec<String> = lines
+        .iter()
+        .filter_map(|line| strip_comment_and_whitespace(line))
+        .collect();
     let mut symbol_table = SymbolTable::initialize();
     set_label_symbols(&mut symbol_table, &lines);
-    let binary_output = parse_instructions(&lines, &mut symbol_table);
-    binary_output
+    let hack = parse_instructions(&lines, &mut symbol_table);
+    Assembly {
+        hack,
+        labels: symbol_table.labels,
+        variables: symbol_table.variables,
+    }
 }
 
 fn set_label_symbols(symbol_table: &mut SymbolTable, lines: &Vec<String>) {
@@ -250,7 +283,9 @@ fn parse_c_instruction(instruction: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::instruction_parser;
+    use super::{code_parser, instruction_parser};
+    use std::env;
+    use std::fs::read_to_string;
 
     #[test]
     fn test_parse_valid_instruction() {
@@ -273,6 +308,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_commuted_comp() {
+        for (comp, commuted) in [("D+M", "M+D"), ("D&A", "A&D"), ("D|M", "M|D")] {
+            assert_eq!(
+                code_parser::parse_comp(comp),
+                code_parser::parse_comp(commuted)
+            );
+        }
+    }
+
     #[test]
     #[should_panic]
     fn test_parse_invalid_instruction() {
@@ -299,4 +344,51 @@ mod tests {
             assert_eq!(sanitized_line, test.1);
         }
     }
+
+    #[test]
+    fn test_assemble_matches_reference_output() {
+        // Compares against the output of the Python assembler
+        let project_dir = env::current_dir().unwrap().join("..");
+        for (asm_file, hack_file) in [
+            ("add/Add.asm", "Add.hack"),
+            ("max/Max.asm", "Max.hack"),
+            ("rect/Rect.asm", "Rect.hack"),
+            ("pong/Pong.asm", "Pong.hack"),
+        ] {
+            let hack = super::assemble(project_dir.join(asm_file).to_str().unwrap());
+            let expected =
+                read_to_string(project_dir.join("assembler/hack_output").join(hack_file)).unwrap();
+            assert_eq!(hack, expected.lines().collect::<Vec<&str>>(), "{asm_file}");
+        }
+    }
+
+    #[test]
+    fn test_assemble_lines_symbols() {
+        let lines: Vec<String> = [
+            "// Counts down from 3",
+            "@3",
+            "D=A",
+            "@count",
+            "M=D",
+            "(LOOP)",
+            "@count",
+            "MD=M-1   // decrement",
+            "@LOOP",
+            "D;JGT",
+            "(END)",
+            "@END",
+            "0;JMP",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let assembly = super::assemble_lines(&lines);
+        assert_eq!(assembly.hack.len(), 10);
+        assert_eq!(assembly.hack[2], "0000000000010000");
+        assert_eq!(
+            assembly.labels,
+            [(String::from("LOOP"), 4), (String::from("END"), 8)]
+        );
+        assert_eq!(assembly.variables, [(String::from("count"), 16)]);
+    }
 }
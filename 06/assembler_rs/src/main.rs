@@ -1,7 +1,7 @@
-mod assembler;
-
 use std::env;
 
+use assembler_rs::assembler;
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() != 3 {
@@ -0,0 +1,3 @@
+mod os;
+pub mod test_script;
+pub mod vm_emulator;
@@ -0,0 +1,87 @@
+use std::env;
+use std::path::Path;
+use std::process;
+
+use vm_emulator_rs::test_script::run_test_script;
+use vm_emulator_rs::vm_emulator::{EmulatorOptions, VmEmulator, ARG, LCL, SP, THAT, THIS};
+use vm_translator_rs::vm_translator::vm_files;
+
+const USAGE: &str =
+    "Usage: vm_emulator_rs [--steps N] [--no-builtins] [--divide-by-zero-error] <infile, directory or VME test script>";
+const DEFAULT_STEPS: usize = 1_000_000;
+
+fn main() {
+    let mut steps = DEFAULT_STEPS;
+    let mut options = EmulatorOptions::default();
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-builtins" => options.builtins = false,
+            "--divide-by-zero-error" => options.divide_by_zero_error = true,
+            "--steps" => {
+                steps = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage_error())
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => usage_error(),
+        }
+    }
+    let path = Path::new(path.as_deref().unwrap_or_else(|| usage_error()));
+
+    if path.extension().is_some_and(|ext| ext == "tst") {
+        match run_test_script(path) {
+            Ok(()) => println!("End of script - Comparison ended successfully"),
+            Err(reason) => {
+                eprintln!("{reason}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut emulator = match VmEmulator::load(&vm_files(path), &options) {
+        Ok(emulator) => emulator,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{error}");
+            }
+            eprintln!("Loading failed with {} error(s)", errors.len());
+            process::exit(1);
+        }
+    };
+    // Programs without Sys.init start at their first instruction
+    let _ = emulator.bootstrap();
+    let executed = emulator.run(steps);
+    if emulator.is_halted() {
+        println!("Program halted after {executed} steps");
+    } else {
+        println!("Program still running after {executed} steps");
+    }
+    if let Some(error) = emulator.runtime_error() {
+        println!("Runtime error: {error}");
+    }
+    if let Some(error_code) = emulator.os_error() {
+        println!("Sys.error called with error code {error_code}");
+    }
+    for (name, pointer) in [
+        ("SP", SP),
+        ("LCL", LCL),
+        ("ARG", ARG),
+        ("THIS", THIS),
+        ("THAT", THAT),
+    ] {
+        println!("{name}: {}", emulator.ram[pointer]);
+    }
+    let sp = emulator.ram[SP] as u16 as usize;
+    if (256..2048).contains(&sp) {
+        println!("Stack: {:?}", &emulator.ram[256..sp]);
+    }
+}
+
+fn usage_error() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2);
+}
@@ -0,0 +1,1047 @@
+// Native implementations of the Jack OS classes, with the semantics of the
+// project 12 implementations in 12/*.jack: they lay out the heap, strings,
+// font and screen in RAM exactly as the compiled classes would. Whenever a
+// class calls into another OS class, the call goes through the emulator, so
+// a class loaded from a .vm file can replace its builtin counterpart.
+use vm_translator_rs::vm_translator::parser::{parse_instruction, ParsedVMInstruction};
+
+use crate::vm_emulator::{address, VmEmulator};
+
+const HEAP_BASE: i16 = 2048;
+const HEAP_SIZE: i16 = 14335;
+const SCREEN: i16 = 16384;
+const KEYBOARD: i16 = 24576;
+const NEW_LINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+const DOUBLE_QUOTE: i16 = 34;
+const MINUS: i16 = 45;
+const ZERO: i16 = 48;
+// Error code of division by zero in the course's reference OS
+const DIVIDE_BY_ZERO: i16 = 3;
+const OUT_OF_HEAP: i16 = 1;
+
+// The number of static variables of each class in 12/*.jack that has any,
+// in file name order
+pub const CLASS_STATICS: [(&str, u16); 4] =
+    [("Keyboard", 1), ("Memory", 1), ("Output", 4), ("Screen", 2)];
+
+/// The static variables of the builtin classes.
+#[derive(Default)]
+pub struct OsState {
+    // Screen
+    color: bool,
+    screen_bits: i16,
+    // Output
+    char_maps: i16,
+    row: i16,
+    col: i16,
+    output_bits: i16,
+    // Keyboard
+    keyboard: i16,
+    // A key that readChar has seen pressed but not yet released, and the line
+    // that readLine is reading, kept while they wait for input
+    pressed_key: Option<i16>,
+    line: Option<i16>,
+    // Sys
+    pub error_code: Option<i16>,
+    // EmulatorOptions::divide_by_zero_error
+    pub divide_by_zero_error: bool,
+}
+
+type Native = fn(&mut VmEmulator, &[i16]) -> Option<i16>;
+
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub n_args: u16,
+    pub run: Native,
+}
+
+pub fn builtin(function: &str) -> Option<Builtin> {
+    let (n_args, run): (u16, Native) = match function {
+        "Math.init" => (0, math_init),
+        "Math.abs" => (1, math_abs),
+        "Math.multiply" => (2, math_multiply),
+        "Math.divide" => (2, math_divide),
+        "Math.sqrt" => (1, math_sqrt),
+        "Math.max" => (2, math_max),
+        "Math.min" => (2, math_min),
+        "Memory.init" => (0, memory_init),
+        "Memory.peek" => (1, memory_peek),
+        "Memory.poke" => (2, memory_poke),
+        "Memory.alloc" => (1, memory_alloc),
+        "Memory.deAlloc" => (1, memory_dealloc),
+        "Array.new" => (1, array_new),
+        "Array.dispose" => (1, array_dispose),
+        "String.new" => (1, string_new),
+        "String.dispose" => (1, string_dispose),
+        "String.length" => (1, string_length),
+        "String.charAt" => (2, string_char_at),
+        "String.setCharAt" => (3, string_set_char_at),
+        "String.appendChar" => (2, string_append_char),
+        "String.eraseLastChar" => (1, string_erase_last_char),
+        "String.intValue" => (1, string_int_value),
+        "String.setInt" => (2, string_set_int),
+        "String.newLine" => (0, |_, _| Some(NEW_LINE)),
+        "String.backSpace" => (0, |_, _| Some(BACKSPACE)),
+        "String.doubleQuote" => (0, |_, _| Some(DOUBLE_QUOTE)),
+        "Output.init" => (0, output_init),
+        "Output.moveCursor" => (2, output_move_cursor),
+        "Output.printChar" => (1, output_print_char),
+        "Output.printString" => (1, output_print_string),
+        "Output.printInt" => (1, output_print_int),
+        "Output.println" => (0, output_println),
+        "Output.backSpace" => (0, output_back_space),
+        "Screen.init" => (0, screen_init),
+        "Screen.clearScreen" => (0, screen_clear_screen),
+        "Screen.setColor" => (1, screen_set_color),
+        "Screen.drawPixel" => (2, screen_draw_pixel),
+        "Screen.drawLine" => (4, screen_draw_line),
+        "Screen.drawRectangle" => (4, screen_draw_rectangle),
+        "Screen.drawCircle" => (3, screen_draw_circle),
+        "Keyboard.init" => (0, keyboard_init),
+        "Keyboard.keyPressed" => (0, keyboard_key_pressed),
+        "Keyboard.readChar" => (0, keyboard_read_char),
+        "Keyboard.readLine" => (1, keyboard_read_line),
+        "Keyboard.readInt" => (1, keyboard_read_int),
+        "Sys.halt" => (0, sys_halt),
+        "Sys.error" => (1, sys_error),
+        "Sys.wait" => (1, sys_wait),
+        _ => return None,
+    };
+    Some(Builtin { n_args, run })
+}
+
+/// Sys.init as compiled from Sys.jack. It stays VM code rather than a
+/// builtin so that Main.main runs step by step like any other function.
+pub fn sys_init() -> Vec<(usize, ParsedVMInstruction)> {
+    let classes = ["Memory", "Math", "Screen", "Output", "Keyboard", "Main"];
+    let mut lines = vec![String::from("function Sys.init 0")];
+    for class in classes {
+        let function = if class == "Main" { "main" } else { "init" };
+        lines.push(format!("call {class}.{function} 0"));
+        lines.push(String::from("pop temp 0"));
+    }
+    lines.extend(["call Sys.halt 0", "pop temp 0", "push constant 0", "return"].map(String::from));
+    lines
+        .iter()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, parse_instruction(line).unwrap()))
+        .collect()
+}
+
+fn truth(condition: bool) -> i16 {
+    if condition {
+        -1
+    } else {
+        0
+    }
+}
+
+fn peek(emulator: &VmEmulator, address_value: i16) -> i16 {
+    emulator.ram[address(address_value)]
+}
+
+fn poke(emulator: &mut VmEmulator, address_value: i16, value: i16) {
+    emulator.ram[address(address_value)] = value;
+}
+
+fn powers_of_two(emulator: &mut VmEmulator) -> Option<i16> {
+    // The 16-entry bit mask arrays built by Screen.init and Output.init
+    let bits = emulator.call_function("Array.new", &[16])?;
+    let mut value: i16 = 1;
+    for i in 0..16 {
+        poke(emulator, bits.wrapping_add(i), value);
+        value = value.wrapping_add(value);
+    }
+    Some(bits)
+}
+
+// Math
+
+fn math_init(_: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    Some(0)
+}
+
+fn abs(x: i16) -> i16 {
+    if x < 0 {
+        x.wrapping_neg()
+    } else {
+        x
+    }
+}
+
+fn math_abs(_: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    Some(abs(args[0]))
+}
+
+fn math_multiply(_: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    // Shifting and adding over all 16 bits of y gives the product modulo 2^16
+    Some(args[0].wrapping_mul(args[1]))
+}
+
+fn divide_abs(x: i16, y: i16) -> i16 {
+    if y > x || y < 0 {
+        return 0;
+    }
+    let q = divide_abs(x, y.wrapping_add(y));
+    let q2 = q.wrapping_add(q);
+    if x.wrapping_sub(q2.wrapping_mul(y)) < y {
+        q2
+    } else {
+        q2.wrapping_add(1)
+    }
+}
+
+fn divide(x: i16, y: i16) -> i16 {
+    // abs(-32768) is still negative, so dividing it yields 0 as in Math.jack
+    let result = divide_abs(abs(x), abs(y));
+    if (x > 0 && y > 0) || (x < 0 && y < 0) {
+        result
+    } else {
+        result.wrapping_neg()
+    }
+}
+
+fn math_divide(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    if args[1] == 0 {
+        if !emulator.os.divide_by_zero_error {
+            // Math.jack recurses until the stack overflows, so the call
+            // never completes: it is retried on every step, like a builtin
+            // waiting for input.
+            return None;
+        }
+        emulator.call_function("Sys.error", &[DIVIDE_BY_ZERO])?;
+        return Some(0);
+    }
+    Some(divide(args[0], args[1]))
+}
+
+fn math_sqrt(_: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let x = args[0];
+    if x == 0 || x == 1 {
+        return Some(x);
+    }
+    // Binary search, bounded by 181 = floor(sqrt(32767))
+    let mut left: i16 = 1;
+    let mut right = x.min(181);
+    let mut result = 0;
+    while left < right.wrapping_add(1) {
+        let mid = divide(left.wrapping_add(right), 2);
+        if mid.wrapping_mul(mid) > x {
+            right = mid.wrapping_sub(1);
+        } else {
+            left = mid.wrapping_add(1);
+            result = mid;
+        }
+    }
+    Some(result)
+}
+
+fn math_max(_: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    Some(args[0].max(args[1]))
+}
+
+fn math_min(_: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    Some(args[0].min(args[1]))
+}
+
+// Memory
+
+fn memory_init(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    // A single free block of [next, size] covering the whole heap
+    poke(emulator, HEAP_BASE, 0);
+    poke(emulator, HEAP_BASE + 1, HEAP_SIZE);
+    Some(0)
+}
+
+fn memory_peek(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    Some(peek(emulator, args[0]))
+}
+
+fn memory_poke(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    poke(emulator, args[0], args[1]);
+    Some(0)
+}
+
+fn memory_alloc(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    // First fit, carving the block (plus a two word header) off the end of
+    // the first free block that is large enough
+    let size = args[0];
+    let needed_size = size.wrapping_add(2);
+    let mut free_list = HEAP_BASE;
+    loop {
+        let cur_size = peek(emulator, free_list.wrapping_add(1));
+        if cur_size >= needed_size {
+            let new_size = cur_size.wrapping_sub(needed_size);
+            poke(emulator, free_list.wrapping_add(1), new_size);
+            let base = free_list.wrapping_add(new_size).wrapping_add(4);
+            poke(emulator, base.wrapping_sub(1), size);
+            poke(emulator, base.wrapping_sub(2), 0);
+            return Some(base);
+        }
+        let next_block = peek(emulator, free_list);
+        if next_block == 0 {
+            emulator.call_function("Sys.error", &[OUT_OF_HEAP])?;
+            return Some(0);
+        }
+        free_list = next_block;
+    }
+}
+
+fn memory_dealloc(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    // The block's header becomes a free list node appended to the list
+    let mut free_list = HEAP_BASE;
+    while peek(emulator, free_list) != 0 {
+        free_list = peek(emulator, free_list);
+    }
+    poke(emulator, free_list, args[0].wrapping_sub(2));
+    Some(0)
+}
+
+// Array
+
+fn array_new(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    emulator.call_function("Memory.alloc", &[args[0]])
+}
+
+fn array_dispose(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    emulator.call_function("Memory.deAlloc", &[args[0]])?;
+    Some(0)
+}
+
+// String: an object of two fields, the character array and the length
+
+fn chars(emulator: &VmEmulator, this: i16) -> i16 {
+    peek(emulator, this)
+}
+
+fn length(emulator: &VmEmulator, this: i16) -> i16 {
+    peek(emulator, this.wrapping_add(1))
+}
+
+fn set_length(emulator: &mut VmEmulator, this: i16, length: i16) {
+    poke(emulator, this.wrapping_add(1), length);
+}
+
+fn string_new(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let max_length = args[0];
+    let this = emulator.call_function("Memory.alloc", &[2])?;
+    let chars = if max_length > 0 {
+        emulator.call_function("Array.new", &[max_length])?
+    } else {
+        0
+    };
+    poke(emulator, this, chars);
+    set_length(emulator, this, 0);
+    Some(this)
+}
+
+fn string_dispose(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let this = args[0];
+    let chars = chars(emulator, this);
+    if chars > 0 {
+        emulator.call_function("Memory.deAlloc", &[chars])?;
+    }
+    emulator.call_function("Memory.deAlloc", &[this])?;
+    Some(0)
+}
+
+fn string_length(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    Some(length(emulator, args[0]))
+}
+
+fn string_char_at(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    Some(peek(
+        emulator,
+        chars(emulator, args[0]).wrapping_add(args[1]),
+    ))
+}
+
+fn string_set_char_at(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let address_value = chars(emulator, args[0]).wrapping_add(args[1]);
+    poke(emulator, address_value, args[2]);
+    Some(0)
+}
+
+fn append_char(emulator: &mut VmEmulator, this: i16, c: i16) {
+    // Like String.jack, this does not check the maximum length
+    let length = length(emulator, this);
+    poke(emulator, chars(emulator, this).wrapping_add(length), c);
+    set_length(emulator, this, length.wrapping_add(1));
+}
+
+fn string_append_char(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    append_char(emulator, args[0], args[1]);
+    Some(args[0])
+}
+
+fn string_erase_last_char(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let length = length(emulator, args[0]);
+    set_length(emulator, args[0], length.wrapping_sub(1));
+    Some(0)
+}
+
+fn string_int_value(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    // Like String.jack, every character after an optional minus sign is
+    // treated as a digit
+    let this = args[0];
+    let chars = chars(emulator, this);
+    let is_negative = peek(emulator, chars) == MINUS;
+    let mut idx = if is_negative { 1 } else { 0 };
+    let mut value: i16 = 0;
+    while idx < length(emulator, this) {
+        let cur_digit = peek(emulator, chars.wrapping_add(idx)).wrapping_sub(ZERO);
+        value = emulator
+            .call_function("Math.multiply", &[value, 10])?
+            .wrapping_add(cur_digit);
+        idx += 1;
+    }
+    Some(if is_negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn set_int(emulator: &mut VmEmulator, this: i16, val: i16) -> Option<()> {
+    // As in String.jack, the recursion drops the sign, so only single digit
+    // negative values keep their minus sign
+    let is_negative = val < 0;
+    let val = if is_negative {
+        emulator.call_function("Math.abs", &[val])?
+    } else {
+        val
+    };
+    let tens = emulator.call_function("Math.divide", &[val, 10])?;
+    let tens = emulator.call_function("Math.multiply", &[tens, 10])?;
+    let last_digit = val.wrapping_sub(tens).wrapping_add(ZERO);
+    if val < 10 {
+        set_length(emulator, this, 0);
+        if is_negative {
+            append_char(emulator, this, MINUS);
+        }
+    } else {
+        let rest = emulator.call_function("Math.divide", &[val, 10])?;
+        set_int(emulator, this, rest)?;
+    }
+    append_char(emulator, this, last_digit);
+    Some(())
+}
+
+fn string_set_int(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    set_int(emulator, args[0], args[1])?;
+    Some(0)
+}
+
+fn new_string(emulator: &mut VmEmulator, text: &str) -> Option<i16> {
+    // Builds a string constant the way compiled Jack code does
+    let mut string = emulator.call_function("String.new", &[text.len() as i16])?;
+    for c in text.chars() {
+        string = emulator.call_function("String.appendChar", &[string, c as i16])?;
+    }
+    Some(string)
+}
+
+// Output: a 23 x 64 grid of 11 x 8 pixel characters
+
+// Character bitmaps from Output.initMap, in creation order: the character
+// code followed by its 11 rows, least significant bit leftmost.
+#[rustfmt::skip]
+const FONT: [(i16, [i16; 11]); 96] = [
+    (0, [63, 63, 63, 63, 63, 63, 63, 63, 63, 0, 0]),
+    (32, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+    (33, [12, 30, 30, 30, 12, 12, 0, 12, 12, 0, 0]),
+    (34, [54, 54, 20, 0, 0, 0, 0, 0, 0, 0, 0]),
+    (35, [0, 18, 18, 63, 18, 18, 63, 18, 18, 0, 0]),
+    (36, [12, 30, 51, 3, 30, 48, 51, 30, 12, 12, 0]),
+    (37, [0, 0, 35, 51, 24, 12, 6, 51, 49, 0, 0]),
+    (38, [12, 30, 30, 12, 54, 27, 27, 27, 54, 0, 0]),
+    (39, [12, 12, 6, 0, 0, 0, 0, 0, 0, 0, 0]),
+    (40, [24, 12, 6, 6, 6, 6, 6, 12, 24, 0, 0]),
+    (41, [6, 12, 24, 24, 24, 24, 24, 12, 6, 0, 0]),
+    (42, [0, 0, 0, 51, 30, 63, 30, 51, 0, 0, 0]),
+    (43, [0, 0, 0, 12, 12, 63, 12, 12, 0, 0, 0]),
+    (44, [0, 0, 0, 0, 0, 0, 0, 12, 12, 6, 0]),
+    (45, [0, 0, 0, 0, 0, 63, 0, 0, 0, 0, 0]),
+    (46, [0, 0, 0, 0, 0, 0, 0, 12, 12, 0, 0]),
+    (47, [0, 0, 32, 48, 24, 12, 6, 3, 1, 0, 0]),
+    (48, [12, 30, 51, 51, 51, 51, 51, 30, 12, 0, 0]),
+    (49, [12, 14, 15, 12, 12, 12, 12, 12, 63, 0, 0]),
+    (50, [30, 51, 48, 24, 12, 6, 3, 51, 63, 0, 0]),
+    (51, [30, 51, 48, 48, 28, 48, 48, 51, 30, 0, 0]),
+    (52, [16, 24, 28, 26, 25, 63, 24, 24, 60, 0, 0]),
+    (53, [63, 3, 3, 31, 48, 48, 48, 51, 30, 0, 0]),
+    (54, [28, 6, 3, 3, 31, 51, 51, 51, 30, 0, 0]),
+    (55, [63, 49, 48, 48, 24, 12, 12, 12, 12, 0, 0]),
+    (56, [30, 51, 51, 51, 30, 51, 51, 51, 30, 0, 0]),
+    (57, [30, 51, 51, 51, 62, 48, 48, 24, 14, 0, 0]),
+    (58, [0, 0, 12, 12, 0, 0, 12, 12, 0, 0, 0]),
+    (59, [0, 0, 12, 12, 0, 0, 12, 12, 6, 0, 0]),
+    (60, [0, 0, 24, 12, 6, 3, 6, 12, 24, 0, 0]),
+    (61, [0, 0, 0, 63, 0, 0, 63, 0, 0, 0, 0]),
+    (62, [0, 0, 3, 6, 12, 24, 12, 6, 3, 0, 0]),
+    (64, [30, 51, 51, 59, 59, 59, 27, 3, 30, 0, 0]),
+    (63, [30, 51, 51, 24, 12, 12, 0, 12, 12, 0, 0]),
+    (65, [12, 30, 51, 51, 63, 51, 51, 51, 51, 0, 0]),
+    (66, [31, 51, 51, 51, 31, 51, 51, 51, 31, 0, 0]),
+    (67, [28, 54, 35, 3, 3, 3, 35, 54, 28, 0, 0]),
+    (68, [15, 27, 51, 51, 51, 51, 51, 27, 15, 0, 0]),
+    (69, [63, 51, 35, 11, 15, 11, 35, 51, 63, 0, 0]),
+    (70, [63, 51, 35, 11, 15, 11, 3, 3, 3, 0, 0]),
+    (71, [28, 54, 35, 3, 59, 51, 51, 54, 44, 0, 0]),
+    (72, [51, 51, 51, 51, 63, 51, 51, 51, 51, 0, 0]),
+    (73, [30, 12, 12, 12, 12, 12, 12, 12, 30, 0, 0]),
+    (74, [60, 24, 24, 24, 24, 24, 27, 27, 14, 0, 0]),
+    (75, [51, 51, 51, 27, 15, 27, 51, 51, 51, 0, 0]),
+    (76, [3, 3, 3, 3, 3, 3, 35, 51, 63, 0, 0]),
+    (77, [33, 51, 63, 63, 51, 51, 51, 51, 51, 0, 0]),
+    (78, [51, 51, 55, 55, 63, 59, 59, 51, 51, 0, 0]),
+    (79, [30, 51, 51, 51, 51, 51, 51, 51, 30, 0, 0]),
+    (80, [31, 51, 51, 51, 31, 3, 3, 3, 3, 0, 0]),
+    (81, [30, 51, 51, 51, 51, 51, 63, 59, 30, 48, 0]),
+    (82, [31, 51, 51, 51, 31, 27, 51, 51, 51, 0, 0]),
+    (83, [30, 51, 51, 6, 28, 48, 51, 51, 30, 0, 0]),
+    (84, [63, 63, 45, 12, 12, 12, 12, 12, 30, 0, 0]),
+    (85, [51, 51, 51, 51, 51, 51, 51, 51, 30, 0, 0]),
+    (86, [51, 51, 51, 51, 51, 30, 30, 12, 12, 0, 0]),
+    (87, [51, 51, 51, 51, 51, 63, 63, 63, 18, 0, 0]),
+    (88, [51, 51, 30, 30, 12, 30, 30, 51, 51, 0, 0]),
+    (89, [51, 51, 51, 51, 30, 12, 12, 12, 30, 0, 0]),
+    (90, [63, 51, 49, 24, 12, 6, 35, 51, 63, 0, 0]),
+    (91, [30, 6, 6, 6, 6, 6, 6, 6, 30, 0, 0]),
+    (92, [0, 0, 1, 3, 6, 12, 24, 48, 32, 0, 0]),
+    (93, [30, 24, 24, 24, 24, 24, 24, 24, 30, 0, 0]),
+    (94, [8, 28, 54, 0, 0, 0, 0, 0, 0, 0, 0]),
+    (95, [0, 0, 0, 0, 0, 0, 0, 0, 0, 63, 0]),
+    (96, [6, 12, 24, 0, 0, 0, 0, 0, 0, 0, 0]),
+    (97, [0, 0, 0, 14, 24, 30, 27, 27, 54, 0, 0]),
+    (98, [3, 3, 3, 15, 27, 51, 51, 51, 30, 0, 0]),
+    (99, [0, 0, 0, 30, 51, 3, 3, 51, 30, 0, 0]),
+    (100, [48, 48, 48, 60, 54, 51, 51, 51, 30, 0, 0]),
+    (101, [0, 0, 0, 30, 51, 63, 3, 51, 30, 0, 0]),
+    (102, [28, 54, 38, 6, 15, 6, 6, 6, 15, 0, 0]),
+    (103, [0, 0, 30, 51, 51, 51, 62, 48, 51, 30, 0]),
+    (104, [3, 3, 3, 27, 55, 51, 51, 51, 51, 0, 0]),
+    (105, [12, 12, 0, 14, 12, 12, 12, 12, 30, 0, 0]),
+    (106, [48, 48, 0, 56, 48, 48, 48, 48, 51, 30, 0]),
+    (107, [3, 3, 3, 51, 27, 15, 15, 27, 51, 0, 0]),
+    (108, [14, 12, 12, 12, 12, 12, 12, 12, 30, 0, 0]),
+    (109, [0, 0, 0, 29, 63, 43, 43, 43, 43, 0, 0]),
+    (110, [0, 0, 0, 29, 51, 51, 51, 51, 51, 0, 0]),
+    (111, [0, 0, 0, 30, 51, 51, 51, 51, 30, 0, 0]),
+    (112, [0, 0, 0, 30, 51, 51, 51, 31, 3, 3, 0]),
+    (113, [0, 0, 0, 30, 51, 51, 51, 62, 48, 48, 0]),
+    (114, [0, 0, 0, 29, 55, 51, 3, 3, 7, 0, 0]),
+    (115, [0, 0, 0, 30, 51, 6, 24, 51, 30, 0, 0]),
+    (116, [4, 6, 6, 15, 6, 6, 6, 54, 28, 0, 0]),
+    (117, [0, 0, 0, 27, 27, 27, 27, 27, 54, 0, 0]),
+    (118, [0, 0, 0, 51, 51, 51, 51, 30, 12, 0, 0]),
+    (119, [0, 0, 0, 51, 51, 51, 63, 63, 18, 0, 0]),
+    (120, [0, 0, 0, 51, 30, 12, 12, 30, 51, 0, 0]),
+    (121, [0, 0, 0, 51, 51, 51, 62, 48, 24, 15, 0]),
+    (122, [0, 0, 0, 63, 27, 12, 6, 51, 63, 0, 0]),
+    (123, [56, 12, 12, 12, 7, 12, 12, 12, 56, 0, 0]),
+    (124, [12, 12, 12, 12, 12, 12, 12, 12, 12, 0, 0]),
+    (125, [7, 12, 12, 12, 56, 12, 12, 12, 7, 0, 0]),
+    (126, [38, 45, 25, 0, 0, 0, 0, 0, 0, 0, 0]),
+];
+
+fn output_init(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    let char_maps = emulator.call_function("Array.new", &[127])?;
+    emulator.os.char_maps = char_maps;
+    for (c, rows) in FONT {
+        let map = emulator.call_function("Array.new", &[11])?;
+        poke(emulator, char_maps.wrapping_add(c), map);
+        for (i, row) in (0..).zip(rows) {
+            poke(emulator, map.wrapping_add(i), row);
+        }
+    }
+    emulator.os.row = 0;
+    emulator.os.col = 0;
+    emulator.os.output_bits = powers_of_two(emulator)?;
+    Some(0)
+}
+
+fn print_char_at_cursor(emulator: &mut VmEmulator, c: i16) -> Option<()> {
+    // Draws every pixel of the character's frame, so the screen color is left
+    // as that of the frame's last pixel
+    let c = if !(32..=126).contains(&c) { 0 } else { c };
+    let char_map = peek(emulator, emulator.os.char_maps.wrapping_add(c));
+    for i in 0..11 {
+        for j in 0..8 {
+            let row_bits = peek(emulator, char_map.wrapping_add(i));
+            let bit = peek(emulator, emulator.os.output_bits.wrapping_add(j));
+            emulator.call_function("Screen.setColor", &[truth(row_bits & bit != 0)])?;
+            let (row, col) = (emulator.os.row, emulator.os.col);
+            let x = emulator.call_function("Math.multiply", &[col, 8])?;
+            let y = emulator.call_function("Math.multiply", &[row, 11])?;
+            emulator.call_function("Screen.drawPixel", &[x.wrapping_add(j), y.wrapping_add(i)])?;
+        }
+    }
+    Some(())
+}
+
+fn move_cursor(emulator: &mut VmEmulator, i: i16, j: i16) -> Option<()> {
+    // Also erases the character at the new position
+    emulator.os.row = i;
+    emulator.os.col = j;
+    print_char_at_cursor(emulator, 32)
+}
+
+fn println(emulator: &mut VmEmulator) -> Option<()> {
+    if emulator.os.row == 22 {
+        move_cursor(emulator, 0, 0)
+    } else {
+        move_cursor(emulator, emulator.os.row.wrapping_add(1), 0)
+    }
+}
+
+fn print_char(emulator: &mut VmEmulator, c: i16) -> Option<()> {
+    print_char_at_cursor(emulator, c)?;
+    if emulator.os.col == 63 {
+        println(emulator)
+    } else {
+        move_cursor(emulator, emulator.os.row, emulator.os.col.wrapping_add(1))
+    }
+}
+
+fn print_string(emulator: &mut VmEmulator, s: i16) -> Option<()> {
+    let mut i = 0;
+    while i < emulator.call_function("String.length", &[s])? {
+        let c = emulator.call_function("String.charAt", &[s, i])?;
+        print_char(emulator, c)?;
+        i += 1;
+    }
+    Some(())
+}
+
+fn output_move_cursor(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    move_cursor(emulator, args[0], args[1])?;
+    Some(0)
+}
+
+fn output_print_char(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    print_char(emulator, args[0])?;
+    Some(0)
+}
+
+fn output_print_string(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    print_string(emulator, args[0])?;
+    Some(0)
+}
+
+fn output_print_int(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let integer = emulator.call_function("String.new", &[8])?;
+    emulator.call_function("String.setInt", &[integer, args[0]])?;
+    print_string(emulator, integer)?;
+    emulator.call_function("String.dispose", &[integer])?;
+    Some(0)
+}
+
+fn output_println(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    println(emulator)?;
+    Some(0)
+}
+
+fn output_back_space(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    if emulator.os.col > 0 {
+        move_cursor(emulator, emulator.os.row, emulator.os.col - 1)?;
+    }
+    Some(0)
+}
+
+// Screen: 256 rows of 512 pixels, 16 pixels per word
+
+fn screen_init(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    emulator.os.color = true;
+    emulator.os.screen_bits = powers_of_two(emulator)?;
+    Some(0)
+}
+
+fn screen_clear_screen(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    let color = emulator.os.color;
+    emulator.os.color = false;
+    for x in 0..512 {
+        for y in 0..256 {
+            draw_pixel(emulator, x, y)?;
+        }
+    }
+    emulator.os.color = color;
+    Some(0)
+}
+
+fn screen_set_color(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    emulator.os.color = args[0] != 0;
+    Some(0)
+}
+
+fn draw_pixel(emulator: &mut VmEmulator, x: i16, y: i16) -> Option<()> {
+    let div_result = emulator.call_function("Math.divide", &[x, 16])?;
+    let row_offset = emulator.call_function("Math.multiply", &[y, 32])?;
+    let address_value = div_result.wrapping_add(row_offset).wrapping_add(SCREEN);
+    let bit = x.wrapping_sub(emulator.call_function("Math.multiply", &[div_result, 16])?);
+    let mask = peek(emulator, emulator.os.screen_bits.wrapping_add(bit));
+    let word = emulator.call_function("Memory.peek", &[address_value])?;
+    let word = if emulator.os.color {
+        word | mask
+    } else {
+        word & !mask
+    };
+    emulator.call_function("Memory.poke", &[address_value, word])?;
+    Some(())
+}
+
+fn screen_draw_pixel(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    draw_pixel(emulator, args[0], args[1])?;
+    Some(0)
+}
+
+fn draw_vertical_line(emulator: &mut VmEmulator, x: i16, mut y1: i16, y2: i16) -> Option<()> {
+    draw_pixel(emulator, x, y1)?;
+    while y1 < y2 {
+        y1 += 1;
+        draw_pixel(emulator, x, y1)?;
+    }
+    Some(())
+}
+
+fn draw_horizontal_line(emulator: &mut VmEmulator, y: i16, mut x1: i16, x2: i16) -> Option<()> {
+    draw_pixel(emulator, x1, y)?;
+    while x1 < x2 {
+        x1 += 1;
+        draw_pixel(emulator, x1, y)?;
+    }
+    Some(())
+}
+
+fn draw_sloped_line(
+    emulator: &mut VmEmulator,
+    x: i16,
+    y: i16,
+    dx: i16,
+    dy: i16,
+    downwards: bool,
+) -> Option<()> {
+    // Screen.jack's draw_pos_sloped_line (downwards, dy > 0) and
+    // draw_neg_sloped_line (upwards, dy < 0), walking from (x, y) towards
+    // (x + dx, y + dy)
+    let (mut a, mut b, mut d): (i16, i16, i16) = (0, 0, 0);
+    draw_pixel(emulator, x, y)?;
+    while a < dx && (if downwards { b < dy } else { b > dy }) {
+        if downwards {
+            if d > 0 {
+                a += 1;
+                d = d.wrapping_sub(dy);
+            } else {
+                b += 1;
+                d = d.wrapping_add(dx);
+            }
+        } else if d > 0 {
+            b -= 1;
+            d = d.wrapping_sub(dx);
+        } else {
+            a += 1;
+            d = d.wrapping_sub(dy);
+        }
+        draw_pixel(emulator, x.wrapping_add(a), y.wrapping_add(b))?;
+    }
+    Some(())
+}
+
+fn screen_draw_line(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let [x1, y1, x2, y2] = [args[0], args[1], args[2], args[3]];
+    if x1 == x2 {
+        let top = emulator.call_function("Math.min", &[y1, y2])?;
+        let bottom = emulator.call_function("Math.max", &[y1, y2])?;
+        draw_vertical_line(emulator, x1, top, bottom)?;
+    } else if y1 == y2 {
+        let left = emulator.call_function("Math.min", &[x1, x2])?;
+        let right = emulator.call_function("Math.max", &[x1, x2])?;
+        draw_horizontal_line(emulator, y1, left, right)?;
+    } else if x1 < x2 && y1 < y2 {
+        draw_sloped_line(
+            emulator,
+            x1,
+            y1,
+            x2.wrapping_sub(x1),
+            y2.wrapping_sub(y1),
+            true,
+        )?;
+    } else if x2 < x1 && y2 < y1 {
+        draw_sloped_line(
+            emulator,
+            x2,
+            y2,
+            x1.wrapping_sub(x2),
+            y1.wrapping_sub(y2),
+            true,
+        )?;
+    } else if x1 < x2 && y1 > y2 {
+        draw_sloped_line(
+            emulator,
+            x1,
+            y1,
+            x2.wrapping_sub(x1),
+            y2.wrapping_sub(y1),
+            false,
+        )?;
+    } else if x2 < x1 && y1 < y2 {
+        draw_sloped_line(
+            emulator,
+            x2,
+            y2,
+            x1.wrapping_sub(x2),
+            y1.wrapping_sub(y2),
+            false,
+        )?;
+    }
+    Some(0)
+}
+
+fn screen_draw_rectangle(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let [mut x1, y1, x2, y2] = [args[0], args[1], args[2], args[3]];
+    draw_vertical_line(emulator, x1, y1, y2)?;
+    while x1 < x2 {
+        x1 += 1;
+        draw_vertical_line(emulator, x1, y1, y2)?;
+    }
+    Some(0)
+}
+
+fn screen_draw_circle(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let [x, y, r] = [args[0], args[1], args[2]];
+    let mut current_y = emulator.call_function("Math.max", &[0, y.wrapping_sub(r)])?;
+    while current_y < 256 && current_y < y.wrapping_add(r).wrapping_add(1) {
+        let h = emulator.call_function("Math.abs", &[y.wrapping_sub(current_y)])?;
+        let r2 = emulator.call_function("Math.multiply", &[r, r])?;
+        let h2 = emulator.call_function("Math.multiply", &[h, h])?;
+        let a = emulator.call_function("Math.sqrt", &[r2.wrapping_sub(h2)])?;
+        let left = emulator.call_function("Math.max", &[0, x.wrapping_sub(a)])?;
+        let right = emulator.call_function("Math.min", &[511, x.wrapping_add(a)])?;
+        draw_horizontal_line(emulator, current_y, left, right)?;
+        current_y += 1;
+    }
+    Some(0)
+}
+
+// Keyboard
+
+fn keyboard_init(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    emulator.os.keyboard = KEYBOARD;
+    Some(0)
+}
+
+fn keyboard_key_pressed(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    emulator.call_function("Memory.peek", &[emulator.os.keyboard])
+}
+
+fn read_char(emulator: &mut VmEmulator) -> Option<i16> {
+    // Waits for a key to be pressed and released, one poll per step, then
+    // echoes it
+    let key = keyboard_key_pressed(emulator, &[])?;
+    let Some(c) = emulator.os.pressed_key else {
+        emulator.os.pressed_key = Some(key).filter(|&key| key != 0);
+        return None;
+    };
+    if key != 0 {
+        return None;
+    }
+    emulator.os.pressed_key = None;
+    match c {
+        NEW_LINE => emulator.call_function("Output.println", &[])?,
+        BACKSPACE => emulator.call_function("Output.backSpace", &[])?,
+        _ => emulator.call_function("Output.printChar", &[c])?,
+    };
+    Some(c)
+}
+
+fn keyboard_read_char(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    read_char(emulator)
+}
+
+fn read_line(emulator: &mut VmEmulator, message: i16) -> Option<i16> {
+    if emulator.os.line.is_none() {
+        emulator.call_function("Output.printString", &[message])?;
+        emulator.os.line = Some(emulator.call_function("String.new", &[100])?);
+    }
+    loop {
+        let line = emulator.os.line.unwrap();
+        match read_char(emulator)? {
+            NEW_LINE => {
+                emulator.os.line = None;
+                return Some(line);
+            }
+            BACKSPACE => {
+                emulator.call_function("String.eraseLastChar", &[line])?;
+            }
+            c => {
+                emulator.os.line = Some(emulator.call_function("String.appendChar", &[line, c])?);
+            }
+        }
+    }
+}
+
+fn keyboard_read_line(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    read_line(emulator, args[0])
+}
+
+fn keyboard_read_int(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    let line = read_line(emulator, args[0])?;
+    let int_value = emulator.call_function("String.intValue", &[line])?;
+    emulator.call_function("String.dispose", &[line])?;
+    Some(int_value)
+}
+
+// Sys
+
+fn sys_halt(emulator: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    emulator.halt();
+    Some(0)
+}
+
+fn sys_error(emulator: &mut VmEmulator, args: &[i16]) -> Option<i16> {
+    emulator.os.error_code = Some(args[0]);
+    let message = new_string(emulator, "ERROR: ")?;
+    emulator.call_function("Output.printString", &[message])?;
+    emulator.call_function("Output.printInt", &[args[0]])?;
+    emulator.call_function("Sys.halt", &[])?;
+    Some(0)
+}
+
+fn sys_wait(_: &mut VmEmulator, _: &[i16]) -> Option<i16> {
+    // Sys.jack busy-waits; the emulator has no clock to wait on
+    Some(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KEYBOARD;
+    use crate::vm_emulator::{EmulatorOptions, VmEmulator};
+
+    fn init_os() -> VmEmulator {
+        init_os_with(&EmulatorOptions::default())
+    }
+
+    fn init_os_with(options: &EmulatorOptions) -> VmEmulator {
+        let mut emulator = VmEmulator::new(vec![], options).unwrap();
+        for class in ["Memory", "Math", "Screen", "Output", "Keyboard"] {
+            emulator
+                .call_function(&format!("{class}.init"), &[])
+                .unwrap();
+        }
+        emulator
+    }
+
+    fn string_value(emulator: &mut VmEmulator, string: i16) -> String {
+        let length = emulator.call_function("String.length", &[string]).unwrap();
+        (0..length)
+            .map(|i| {
+                emulator
+                    .call_function("String.charAt", &[string, i])
+                    .unwrap() as u8 as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_math() {
+        let mut emulator = init_os();
+        let test_cases = [
+            ("Math.multiply", vec![-180, 100], -18000),
+            ("Math.multiply", vec![300, 300], 24464),
+            ("Math.divide", vec![-18000, 6], -3000),
+            ("Math.divide", vec![7, -2], -3),
+            // abs(-32768) overflows, so Math.jack divides it to 0
+            ("Math.divide", vec![-32768, 10], 0),
+            ("Math.sqrt", vec![32767], 181),
+            ("Math.sqrt", vec![-4], 0),
+        ];
+        for (function, args, expected) in test_cases {
+            let result = emulator.call_function(function, &args);
+            assert_eq!(result, Some(expected), "{function}{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        // Like Math.jack, the builtin never returns
+        let mut emulator = init_os();
+        assert_eq!(emulator.call_function("Math.divide", &[1, 0]), None);
+        assert_eq!(emulator.os_error(), None);
+
+        let options = EmulatorOptions {
+            divide_by_zero_error: true,
+            ..Default::default()
+        };
+        let mut emulator = init_os_with(&options);
+        emulator.call_function("Math.divide", &[1, 0]).unwrap();
+        assert_eq!(emulator.os_error(), Some(3));
+    }
+
+    #[test]
+    fn test_heap_layout() {
+        let mut emulator = init_os();
+        // Blocks are carved off the end of the heap's single free block,
+        // after the allocations made by Screen.init and Output.init
+        let free_size = emulator.ram[2049];
+        // The heap ends one word into the screen, so the first block
+        // allocated (Screen.init's bit masks) ends at RAM[16384]
+        assert_eq!(emulator.ram[16384], -32768);
+        let a = emulator.call_function("Memory.alloc", &[3]).unwrap();
+        assert_eq!(a, 2048 + free_size - 5 + 4);
+        assert_eq!(emulator.ram[a as usize - 1], 3);
+        assert_eq!(emulator.ram[2049], free_size - 5);
+        emulator.call_function("Memory.deAlloc", &[a]).unwrap();
+        assert_eq!(emulator.ram[2048], a - 2);
+    }
+
+    #[test]
+    fn test_set_int() {
+        let mut emulator = init_os();
+        let string = emulator.call_function("String.new", &[8]).unwrap();
+        // String.jack only keeps the sign of single digit negative values
+        for (value, expected) in [(1234, "1234"), (-5, "-5"), (-15, "15"), (0, "0")] {
+            emulator
+                .call_function("String.setInt", &[string, value])
+                .unwrap();
+            assert_eq!(string_value(&mut emulator, string), expected);
+            let int_value = emulator.call_function("String.intValue", &[string]);
+            assert_eq!(int_value, expected.parse().ok());
+        }
+    }
+
+    #[test]
+    fn test_print_char() {
+        let mut emulator = init_os();
+        // The second text row starts at pixel row 11, clear of RAM[16384]
+        let text_row = 16384 + 11 * 32;
+        emulator
+            .call_function("Output.moveCursor", &[1, 0])
+            .unwrap();
+        emulator
+            .call_function("Output.printChar", &[b'A' as i16])
+            .unwrap();
+        let column: Vec<i16> = (0..11)
+            .map(|row| emulator.ram[text_row + row * 32] & 0xff)
+            .collect();
+        assert_eq!(column, vec![12, 30, 51, 51, 63, 51, 51, 51, 51, 0, 0]);
+        // The cursor moved one column right
+        emulator
+            .call_function("Output.printChar", &[b'A' as i16])
+            .unwrap();
+        assert_eq!(emulator.ram[text_row + 32] as u16 >> 8, 30);
+    }
+
+    #[test]
+    fn test_read_char_waits_for_release() {
+        let mut emulator = init_os();
+        assert_eq!(emulator.call_function("Keyboard.readChar", &[]), None);
+        emulator.ram[KEYBOARD as usize] = 65;
+        assert_eq!(emulator.call_function("Keyboard.readChar", &[]), None);
+        assert_eq!(emulator.call_function("Keyboard.readChar", &[]), None);
+        emulator.ram[KEYBOARD as usize] = 0;
+        assert_eq!(emulator.call_function("Keyboard.readChar", &[]), Some(65));
+        // The character was echoed at the cursor
+        assert_eq!(emulator.ram[16384 + 4 * 32] & 0xff, 63);
+    }
+}
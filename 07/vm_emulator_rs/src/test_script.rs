@@ -0,0 +1,174 @@
+// Runs the VM emulator test scripts (the *VME.tst files shipped with projects
+// 7 and 8, and the project 12 OS tests) and compares their output against the
+// matching .cmp file.
+use std::fs::read_to_string;
+use std::path::Path;
+
+use vm_translator_rs::vm_translator::vm_files;
+
+use crate::vm_emulator::{EmulatorOptions, VmEmulator, ARG, LCL, SP, THAT, THIS};
+
+fn load(dir: &Path, file: Option<&str>) -> Result<VmEmulator, String> {
+    // A bare `load` loads every .vm (or .vmb) file in the script's
+    // directory. As in the VM emulator, OS functions the files do not define
+    // are builtin.
+    let infiles = match file {
+        Some(file) => vec![dir.join(file)],
+        None => vm_files(dir),
+    };
+    VmEmulator::load(&infiles, &EmulatorOptions::default()).map_err(|errors| {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join("\n")
+    })
+}
+
+fn location(emulator: &VmEmulator, name: &str) -> Result<usize, String> {
+    let invalid = || format!("Unsupported test script location: {name}");
+    let pointer = |name: &str| match name {
+        "sp" => Some(SP),
+        "local" => Some(LCL),
+        "argument" => Some(ARG),
+        "this" => Some(THIS),
+        "that" => Some(THAT),
+        _ => None,
+    };
+    if let Some(pointer) = pointer(name) {
+        return Ok(pointer);
+    }
+    let (segment, idx) = name
+        .strip_suffix(']')
+        .and_then(|name| name.split_once('['))
+        .ok_or_else(invalid)?;
+    let idx: usize = idx.parse().map_err(|_| invalid())?;
+    match segment {
+        "RAM" => Ok(idx),
+        "temp" => Ok(5 + idx),
+        _ => {
+            let base = pointer(segment).ok_or_else(invalid)?;
+            Ok(emulator.ram[base] as u16 as usize + idx)
+        }
+    }
+}
+
+fn read_cmp_rows(cmp_file: &Path) -> Result<Vec<Vec<i16>>, String> {
+    // Header rows are skipped; only rows made of numbers are compared.
+    let cmp = read_to_string(cmp_file)
+        .map_err(|e| format!("Cannot read {}: {e}", cmp_file.to_str().unwrap()))?;
+    Ok(cmp
+        .lines()
+        .filter_map(|line| {
+            line.split('|')
+                .map(str::trim)
+                .filter(|cell| !cell.is_empty())
+                .map(|cell| cell.parse::<i16>().ok())
+                .collect::<Option<Vec<i16>>>()
+                .filter(|row| !row.is_empty())
+        })
+        .collect())
+}
+
+/// Runs a VM emulator test script, returning an error describing the first
+/// output that does not match the script's .cmp file.
+pub fn run_test_script(tst_file: &Path) -> Result<(), String> {
+    run_test_script_with_program(tst_file, tst_file.parent().unwrap())
+}
+
+/// Runs a VM emulator test script like `run_test_script`, but `load` reads
+/// the program from `program_dir` instead of the script's directory.
+pub fn run_test_script_with_program(tst_file: &Path, program_dir: &Path) -> Result<(), String> {
+    let dir = tst_file.parent().unwrap();
+    let script = read_to_string(tst_file)
+        .map_err(|e| format!("Cannot read {}: {e}", tst_file.to_str().unwrap()))?;
+    let script: String = script
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    let mut emulator = None;
+    let mut expected_rows = Vec::new();
+    let mut output_list: Vec<usize> = Vec::new();
+    let mut outputs = 0;
+    let mut tokens = script
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .filter(|token| !token.is_empty())
+        .peekable();
+    let no_program = || String::from("No program loaded");
+    while let Some(token) = tokens.next() {
+        match token {
+            "load" => {
+                let file = tokens.next_if(|file| file.ends_with(".vm") || file.ends_with(".vmb"));
+                emulator = Some(load(program_dir, file)?);
+            }
+            "output-file" => {
+                tokens.next();
+            }
+            "compare-to" => {
+                let cmp_file = tokens.next().ok_or("compare-to expects a file")?;
+                expected_rows = read_cmp_rows(&dir.join(cmp_file))?;
+            }
+            "output-list" => {
+                let emulator = emulator.as_ref().ok_or_else(no_program)?;
+                output_list.clear();
+                while let Some(entry) = tokens.next_if(|entry| entry.contains('%')) {
+                    output_list.push(location(emulator, entry.split('%').next().unwrap())?);
+                }
+            }
+            "set" => {
+                let emulator = emulator.as_mut().ok_or_else(no_program)?;
+                let name = tokens.next().ok_or("set expects a location")?;
+                let location = location(emulator, name)?;
+                let value = tokens.next().ok_or("set expects a value")?;
+                emulator.ram[location] = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value: {value}"))?;
+            }
+            "repeat" => {
+                let emulator = emulator.as_mut().ok_or_else(no_program)?;
+                let count = tokens.next().ok_or("repeat expects a count")?;
+                let steps: usize = count
+                    .parse()
+                    .map_err(|_| format!("Invalid count: {count}"))?;
+                let body: Vec<&str> = tokens.by_ref().take(3).collect();
+                if body != ["{", "vmstep", "}"] {
+                    return Err(format!("Unsupported repeat body: {}", body.join(" ")));
+                }
+                emulator.run(steps);
+                if let Some(error) = emulator.runtime_error() {
+                    return Err(error.to_owned());
+                }
+            }
+            "vmstep" => {
+                let emulator = emulator.as_mut().ok_or_else(no_program)?;
+                emulator.step();
+                if let Some(error) = emulator.runtime_error() {
+                    return Err(error.to_owned());
+                }
+            }
+            "output" => {
+                let emulator = emulator.as_ref().ok_or_else(no_program)?;
+                let actual: Vec<i16> = output_list.iter().map(|&addr| emulator.ram[addr]).collect();
+                match expected_rows.get(outputs) {
+                    Some(expected) if *expected == actual => (),
+                    Some(expected) => {
+                        return Err(format!(
+                            "Comparison failure at output {outputs}: expected {expected:?} but got {actual:?}"
+                        ))
+                    }
+                    None => return Err(format!("No expected row for output {outputs}")),
+                }
+                outputs += 1;
+            }
+            _ => return Err(format!("Unsupported test script command: {token}")),
+        }
+    }
+    if outputs != expected_rows.len() {
+        return Err(format!(
+            "Expected {} output(s) but the script produced {outputs}",
+            expected_rows.len()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,714 @@
+// Executes VM programs directly against a model of the Hack RAM, laid out
+// exactly as the translated assembly would lay it out: the segment pointers
+// in RAM[0..4], temp in RAM[5..12], statics from RAM[16] in order of first
+// appearance, and the stack and call frames from wherever SP points.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use vm_translator_rs::vm_translator::parser::ParsedVMInstruction;
+use vm_translator_rs::vm_translator::{parse_file, MemorySegment, VmError};
+
+use crate::os;
+
+pub const RAM_SIZE: usize = 32768;
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+const TEMP_OFFSET: usize = 5;
+const STATIC_OFFSET: usize = 16;
+const STACK_BASE: i16 = 256;
+// Return address used when a builtin calls a function loaded from a .vm file
+const NESTED_RETURN: usize = u16::MAX as usize;
+// Steps after which such a call is assumed to never return
+const NESTED_STEP_LIMIT: usize = 100_000_000;
+
+pub struct EmulatorOptions {
+    // Provide native implementations of the Jack OS classes for functions
+    // that no loaded .vm file defines
+    pub builtins: bool,
+    // Make the builtin Math.divide call Sys.error(3) when dividing by zero.
+    // Math.jack does not check, and recurses until the stack overflows, so
+    // by default the builtin never returns either.
+    pub divide_by_zero_error: bool,
+}
+
+impl Default for EmulatorOptions {
+    fn default() -> Self {
+        EmulatorOptions {
+            builtins: true,
+            divide_by_zero_error: false,
+        }
+    }
+}
+
+struct LoadedInstruction {
+    instruction: ParsedVMInstruction,
+    // RAM address of a static operand, resolved at load time
+    static_address: usize,
+    // Instruction that a goto, if-goto or call transfers control to, or the
+    // builtin that a call runs instead
+    target: usize,
+    builtin: Option<os::Builtin>,
+}
+
+pub struct VmEmulator {
+    pub ram: Vec<i16>,
+    program: Vec<LoadedInstruction>,
+    functions: HashMap<String, usize>,
+    pc: usize,
+    halted: bool,
+    // Why the program stopped, if it could not go on
+    error: Option<String>,
+    nested_step_limit: usize,
+    pub(crate) os: os::OsState,
+}
+
+impl VmEmulator {
+    /// Loads the given .vm or .vmb files, reporting every parse or link error.
+    pub fn load(infiles: &[PathBuf], options: &EmulatorOptions) -> Result<Self, Vec<VmError>> {
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        for infile in infiles {
+            let file_name = infile.file_name().unwrap().to_str().unwrap();
+            match parse_file(infile) {
+                Ok(instructions) => files.push((file_name.to_owned(), instructions)),
+                Err(file_errors) => errors.extend(file_errors),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Self::new(files, options)
+    }
+
+    /// Builds an emulator from already parsed files, given as file names and
+    /// their instructions with line numbers. Execution starts at Sys.init if
+    /// it exists and at the first instruction otherwise, with SP = 256.
+    pub fn new(
+        mut files: Vec<(String, Vec<(usize, ParsedVMInstruction)>)>,
+        options: &EmulatorOptions,
+    ) -> Result<Self, Vec<VmError>> {
+        let defines = |files: &[(String, Vec<(usize, ParsedVMInstruction)>)], function: &str| {
+            files.iter().flat_map(|(_, instructions)| instructions).any(|(_, instruction)| {
+                matches!(instruction, ParsedVMInstruction::Function { name, .. } if name == function)
+            })
+        };
+        // A compiled Jack program without its own OS starts at the builtin
+        // Sys.init, which runs Main.main
+        if options.builtins && !defines(&files, "Sys.init") && defines(&files, "Main.main") {
+            files.push((String::from("Sys.vm"), os::sys_init()));
+        }
+        let mut errors = Vec::new();
+        // Labels are scoped to their function, or to their file outside any
+        // function, just like the translator's label names.
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut idx = 0;
+        for (file_name, instructions) in &files {
+            let mut scope = static_base(file_name).to_owned();
+            for (line, instruction) in instructions {
+                let duplicate = match instruction {
+                    ParsedVMInstruction::Function { name, .. } => {
+                        scope = name.clone();
+                        functions.insert(name.clone(), idx).is_some()
+                    }
+                    ParsedVMInstruction::Label { label } => {
+                        labels.insert((scope.clone(), label.clone()), idx).is_some()
+                    }
+                    _ => false,
+                };
+                if duplicate {
+                    errors.push(VmError {
+                        file: file_name.clone(),
+                        line: *line,
+                        reason: format!("Duplicate definition: {}", scope_name(instruction)),
+                    });
+                }
+                idx += 1;
+            }
+        }
+
+        // The statics of the builtin classes that the program calls are given
+        // the slots that their compiled classes would take, in file name
+        // order, so the program's own statics are where they would be with
+        // the real OS. The builtins themselves keep their state natively.
+        let mut builtin_classes: Vec<String> = Vec::new();
+        for (_, instruction) in files.iter().flat_map(|(_, instructions)| instructions) {
+            if let ParsedVMInstruction::Call { function, .. } = instruction {
+                if options.builtins
+                    && !functions.contains_key(function)
+                    && os::builtin(function).is_some()
+                {
+                    builtin_classes.push(function.split('.').next().unwrap().to_owned());
+                }
+            }
+        }
+        let mut reserved = os::CLASS_STATICS
+            .iter()
+            .filter(|(class, _)| builtin_classes.iter().any(|used| used == class))
+            .peekable();
+        let mut statics: HashMap<(String, u16), usize> = HashMap::new();
+        let mut program = Vec::with_capacity(idx);
+        for (file_name, instructions) in files {
+            let base = static_base(&file_name).to_owned();
+            while let Some((class, n_statics)) = reserved.next_if(|(class, _)| **class < *base) {
+                for idx in 0..*n_statics {
+                    let next = STATIC_OFFSET + statics.len();
+                    statics.insert((class.to_string(), idx), next);
+                }
+            }
+            let mut scope = base.clone();
+            for (line, instruction) in instructions {
+                let static_address = match &instruction {
+                    ParsedVMInstruction::Push {
+                        segment: MemorySegment::Static,
+                        idx,
+                    }
+                    | ParsedVMInstruction::Pop {
+                        segment: MemorySegment::Static,
+                        idx,
+                    } => {
+                        let next = STATIC_OFFSET + statics.len();
+                        *statics.entry((base.clone(), *idx)).or_insert(next)
+                    }
+                    _ => 0,
+                };
+                let mut builtin = None;
+                let target = match &instruction {
+                    ParsedVMInstruction::Function { name, .. } => {
+                        scope = name.clone();
+                        None
+                    }
+                    ParsedVMInstruction::Call { function, n_args }
+                        if !functions.contains_key(function) =>
+                    {
+                        match os::builtin(function).filter(|_| options.builtins) {
+                            Some(found) if found.n_args == *n_args => {
+                                builtin = Some(found);
+                                None
+                            }
+                            Some(found) => Some(Err(format!(
+                                "{function} expects {} argument(s) but {n_args} were given",
+                                found.n_args
+                            ))),
+                            None => Some(Err(format!("Undefined function: {function}"))),
+                        }
+                    }
+                    ParsedVMInstruction::Goto { label } | ParsedVMInstruction::IfGoto { label } => {
+                        Some(
+                            labels
+                                .get(&(scope.clone(), label.clone()))
+                                .ok_or_else(|| format!("Undefined label: {label}")),
+                        )
+                    }
+                    ParsedVMInstruction::Call { function, .. } => Some(
+                        functions
+                            .get(function)
+                            .ok_or_else(|| format!("Undefined function: {function}")),
+                    ),
+                    _ => None,
+                };
+                let target = match target {
+                    Some(Ok(&target)) => target,
+                    Some(Err(reason)) => {
+                        errors.push(VmError {
+                            file: file_name.clone(),
+                            line,
+                            reason,
+                        });
+                        0
+                    }
+                    None => 0,
+                };
+                program.push(LoadedInstruction {
+                    instruction,
+                    static_address,
+                    target,
+                    builtin,
+                });
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let pc = functions.get("Sys.init").copied().unwrap_or(0);
+        let mut ram = vec![0; RAM_SIZE];
+        ram[SP] = STACK_BASE;
+        let mut os = os::OsState::default();
+        os.divide_by_zero_error = options.divide_by_zero_error;
+        Ok(VmEmulator {
+            ram,
+            program,
+            functions,
+            pc,
+            halted: false,
+            error: None,
+            nested_step_limit: NESTED_STEP_LIMIT,
+            os,
+        })
+    }
+
+    /// Sets SP = 256 and calls Sys.init, like the translator's bootstrap code.
+    /// Returning from Sys.init halts the program.
+    pub fn bootstrap(&mut self) -> Result<(), String> {
+        let Some(&sys_init) = self.functions.get("Sys.init") else {
+            return Err(String::from("No Sys.init function to bootstrap"));
+        };
+        self.ram[SP] = STACK_BASE;
+        call(&mut self.ram, self.program.len(), 0);
+        self.pc = sys_init;
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+            || self.program[self.pc.min(self.program.len())..]
+                .iter()
+                .all(|loaded| matches!(loaded.instruction, ParsedVMInstruction::Label { .. }))
+    }
+
+    /// The error code passed to the builtin Sys.error, if it was called.
+    pub fn os_error(&self) -> Option<i16> {
+        self.os.error_code
+    }
+
+    /// The error that stopped the program, if it could not go on.
+    pub fn runtime_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub(crate) fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    /// Calls a function with the given arguments and returns its result.
+    /// Builtins call other OS classes through this, so that functions loaded
+    /// from .vm files take precedence over builtins. Returns None if a builtin
+    /// is waiting for keyboard input, or never returns, like Math.divide by
+    /// zero.
+    pub(crate) fn call_function(&mut self, function: &str, args: &[i16]) -> Option<i16> {
+        if let Some(&target) = self.functions.get(function) {
+            return self.call_loaded(function, target, args);
+        }
+        let builtin =
+            os::builtin(function).unwrap_or_else(|| panic!("Undefined function: {function}"));
+        (builtin.run)(self, args)
+    }
+
+    fn call_loaded(&mut self, function: &str, target: usize, args: &[i16]) -> Option<i16> {
+        // Returns None, leaving the program halted, if the function does not
+        // return
+        for &arg in args {
+            push(&mut self.ram, arg);
+        }
+        let caller_pc = self.pc;
+        call(&mut self.ram, NESTED_RETURN, args.len() as u16);
+        self.pc = target;
+        let mut steps = 0;
+        while self.pc != NESTED_RETURN && self.step() {
+            steps += 1;
+            if steps == self.nested_step_limit {
+                self.error = Some(format!(
+                    "{function} did not return within {} steps",
+                    self.nested_step_limit
+                ));
+                self.halt();
+            }
+        }
+        if self.halted {
+            return None;
+        }
+        self.pc = caller_pc;
+        Some(pop(&mut self.ram))
+    }
+
+    fn call_builtin(&mut self, builtin: os::Builtin) -> bool {
+        let sp = self.ram[SP];
+        let args: Vec<i16> = (0..builtin.n_args)
+            .map(|idx| self.ram[address(sp.wrapping_sub((builtin.n_args - idx) as i16))])
+            .collect();
+        // A builtin waiting for keyboard input is retried on the next step,
+        // with its arguments still on the stack
+        if let Some(value) = (builtin.run)(self, &args) {
+            self.ram[SP] = sp.wrapping_sub(builtin.n_args as i16);
+            push(&mut self.ram, value);
+            self.pc += 1;
+        }
+        true
+    }
+
+    /// Runs up to the given number of steps, returning how many were executed
+    /// before the program halted.
+    pub fn run(&mut self, steps: usize) -> usize {
+        for step in 0..steps {
+            if !self.step() {
+                return step;
+            }
+        }
+        steps
+    }
+
+    /// Executes one VM instruction, returning false if the program has halted,
+    /// either through Sys.halt or by running past its last instruction.
+    pub fn step(&mut self) -> bool {
+        if self.halted {
+            return false;
+        }
+        // Labels only mark positions, so they are not steps of their own
+        while self
+            .program
+            .get(self.pc)
+            .is_some_and(|loaded| matches!(loaded.instruction, ParsedVMInstruction::Label { .. }))
+        {
+            self.pc += 1;
+        }
+        let Some(loaded) = self.program.get(self.pc) else {
+            return false;
+        };
+        if let Some(builtin) = loaded.builtin {
+            return self.call_builtin(builtin);
+        }
+        let ram = &mut self.ram;
+        let mut next = self.pc + 1;
+        match &loaded.instruction {
+            ParsedVMInstruction::Add => binary(ram, i16::wrapping_add),
+            ParsedVMInstruction::Sub => binary(ram, i16::wrapping_sub),
+            ParsedVMInstruction::Neg => unary(ram, i16::wrapping_neg),
+            ParsedVMInstruction::Eq => binary(ram, |x, y| truth(x == y)),
+            ParsedVMInstruction::Gt => binary(ram, |x, y| truth(x > y)),
+            ParsedVMInstruction::Lt => binary(ram, |x, y| truth(x < y)),
+            ParsedVMInstruction::And => binary(ram, |x, y| x & y),
+            ParsedVMInstruction::Or => binary(ram, |x, y| x | y),
+            ParsedVMInstruction::Not => unary(ram, |x| !x),
+            ParsedVMInstruction::Mul => binary(ram, i16::wrapping_mul),
+            ParsedVMInstruction::Div => binary(ram, divide),
+            ParsedVMInstruction::Mod => binary(ram, remainder),
+            ParsedVMInstruction::Shl => binary(ram, |x, y| shift(x, y, u16::checked_shl)),
+            ParsedVMInstruction::Shr => binary(ram, |x, y| shift(x, y, u16::checked_shr)),
+            ParsedVMInstruction::Push { segment, idx } => {
+                let value = read(ram, segment, *idx, loaded.static_address);
+                push(ram, value);
+            }
+            ParsedVMInstruction::Pop { segment, idx } => {
+                let address = segment_address(ram, segment, *idx, loaded.static_address);
+                ram[address] = pop(ram);
+            }
+            ParsedVMInstruction::Label { .. } => unreachable!(),
+            ParsedVMInstruction::Goto { .. } => next = loaded.target,
+            ParsedVMInstruction::IfGoto { .. } => {
+                if pop(ram) != 0 {
+                    next = loaded.target;
+                }
+            }
+            ParsedVMInstruction::Function { n_vars, .. } => {
+                for _ in 0..*n_vars {
+                    push(ram, 0);
+                }
+            }
+            ParsedVMInstruction::Call { n_args, .. } => {
+                call(ram, next, *n_args);
+                next = loaded.target;
+            }
+            ParsedVMInstruction::Return => {
+                let frame = ram[LCL];
+                let return_address = ram[address(frame.wrapping_sub(5))];
+                let arg = ram[ARG];
+                ram[address(arg)] = pop(ram);
+                ram[SP] = arg.wrapping_add(1);
+                for (offset, pointer) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+                    ram[pointer] = ram[address(frame.wrapping_sub(offset as i16 + 1))];
+                }
+                next = return_address as u16 as usize;
+            }
+        }
+        self.pc = next;
+        true
+    }
+}
+
+fn static_base(file_name: &str) -> &str {
+    // Each file's statics are named after the file, as in the translator
+    Path::new(file_name).file_stem().unwrap().to_str().unwrap()
+}
+
+fn scope_name(instruction: &ParsedVMInstruction) -> String {
+    match instruction {
+        ParsedVMInstruction::Function { name, .. } => format!("function {name}"),
+        ParsedVMInstruction::Label { label } => format!("label {label}"),
+        _ => unreachable!(),
+    }
+}
+
+pub(crate) fn address(value: i16) -> usize {
+    value as u16 as usize % RAM_SIZE
+}
+
+// The arithmetic extensions behave like the translator's runtime: division
+// truncates and wraps, and dividing by zero gives -1 and a remainder of x
+fn divide(x: i16, y: i16) -> i16 {
+    if y == 0 {
+        -1
+    } else {
+        x.wrapping_div(y)
+    }
+}
+
+fn remainder(x: i16, y: i16) -> i16 {
+    if y == 0 {
+        x
+    } else {
+        x.wrapping_rem(y)
+    }
+}
+
+fn shift(x: i16, y: i16, shift: fn(u16, u32) -> Option<u16>) -> i16 {
+    // Shifting by y outside 0..15 gives 0
+    shift(x as u16, y as u16 as u32).unwrap_or(0) as i16
+}
+
+fn truth(condition: bool) -> i16 {
+    if condition {
+        -1
+    } else {
+        0
+    }
+}
+
+fn push(ram: &mut [i16], value: i16) {
+    let sp = ram[SP];
+    ram[address(sp)] = value;
+    ram[SP] = sp.wrapping_add(1);
+}
+
+fn pop(ram: &mut [i16]) -> i16 {
+    let sp = ram[SP].wrapping_sub(1);
+    ram[SP] = sp;
+    ram[address(sp)]
+}
+
+fn unary(ram: &mut [i16], op: impl Fn(i16) -> i16) {
+    let x = pop(ram);
+    push(ram, op(x));
+}
+
+fn binary(ram: &mut [i16], op: impl Fn(i16, i16) -> i16) {
+    let y = pop(ram);
+    let x = pop(ram);
+    push(ram, op(x, y));
+}
+
+fn call(ram: &mut [i16], return_address: usize, n_args: u16) {
+    push(ram, return_address as i16);
+    for pointer in [LCL, ARG, THIS, THAT] {
+        push(ram, ram[pointer]);
+    }
+    let sp = ram[SP];
+    ram[ARG] = sp.wrapping_sub(n_args as i16 + 5);
+    ram[LCL] = sp;
+}
+
+fn segment_address(ram: &[i16], segment: &MemorySegment, idx: u16, static_address: usize) -> usize {
+    match segment {
+        MemorySegment::Local => address(ram[LCL].wrapping_add(idx as i16)),
+        MemorySegment::Argument => address(ram[ARG].wrapping_add(idx as i16)),
+        MemorySegment::This => address(ram[THIS].wrapping_add(idx as i16)),
+        MemorySegment::That => address(ram[THAT].wrapping_add(idx as i16)),
+        MemorySegment::Pointer => THIS + idx as usize,
+        MemorySegment::Temp => TEMP_OFFSET + idx as usize,
+        MemorySegment::Static => static_address,
+        MemorySegment::Constant => panic!("The constant segment has no address"),
+    }
+}
+
+fn read(ram: &[i16], segment: &MemorySegment, idx: u16, static_address: usize) -> i16 {
+    match segment {
+        MemorySegment::Constant => idx as i16,
+        _ => ram[segment_address(ram, segment, idx, static_address)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmulatorOptions, VmEmulator, ARG, LCL, SP};
+    use vm_translator_rs::vm_translator::parser::parse_instruction;
+
+    fn load(files: &[(&str, &[&str])]) -> VmEmulator {
+        let files = files
+            .iter()
+            .map(|(file_name, lines)| {
+                let instructions = lines
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, line)| (idx + 1, parse_instruction(line).unwrap()))
+                    .collect();
+                (file_name.to_string(), instructions)
+            })
+            .collect();
+        VmEmulator::new(files, &EmulatorOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_bootstrap_and_return() {
+        let mut emulator = load(&[(
+            "Sys.vm",
+            &[
+                "function Sys.init 1",
+                "push constant 3",
+                "call Sys.double 1",
+                "pop local 0",
+                "push local 0",
+                "return",
+                "function Sys.double 0",
+                "push argument 0",
+                "push argument 0",
+                "add",
+                "return",
+            ],
+        )]);
+        emulator.bootstrap().unwrap();
+        assert_eq!(emulator.run(100), 11);
+        assert!(emulator.is_halted());
+        // Sys.init's return value replaces its (empty) argument at RAM[256]
+        assert_eq!(emulator.ram[SP], 257);
+        assert_eq!(emulator.ram[256], 6);
+        assert_eq!((emulator.ram[LCL], emulator.ram[ARG]), (0, 0));
+    }
+
+    #[test]
+    fn test_arithmetic_extensions() {
+        let mut emulator = load(&[(
+            "Main.vm",
+            &[
+                "push constant 300",
+                "push constant 300",
+                "mul",
+                "push constant 7",
+                "neg",
+                "push constant 2",
+                "div",
+                "push constant 7",
+                "neg",
+                "push constant 2",
+                "mod",
+                "push constant 5",
+                "push constant 0",
+                "div",
+                "push constant 1",
+                "push constant 15",
+                "shl",
+                "push constant 1",
+                "neg",
+                "push constant 12",
+                "shr",
+                "push constant 1",
+                "push constant 16",
+                "shl",
+            ],
+        )]);
+        emulator.ram[SP] = 256;
+        emulator.run(100);
+        // Results wrap to 16 bits and shifts are logical
+        assert_eq!(emulator.ram[SP], 263);
+        assert_eq!(&emulator.ram[256..263], &[24464, -3, -1, -1, -32768, 15, 0]);
+    }
+
+    #[test]
+    fn test_statics_and_labels_are_scoped() {
+        let mut emulator = load(&[
+            (
+                "A.vm",
+                &[
+                    "push constant 1",
+                    "pop static 3",
+                    "goto END",
+                    "push constant 9",
+                    "pop static 3",
+                    "label END",
+                ],
+            ),
+            ("B.vm", &["push constant 2", "pop static 0", "label END"]),
+        ]);
+        emulator.ram[SP] = 256;
+        emulator.run(100);
+        // Statics are allocated per file in order of first appearance
+        assert_eq!(&emulator.ram[16..18], &[1, 2]);
+    }
+
+    #[test]
+    fn test_builtin_statics_are_reserved() {
+        let mut emulator = load(&[(
+            "Main.vm",
+            &[
+                "function Main.main 0",
+                "push constant 5",
+                "pop static 0",
+                "push constant 0",
+                "return",
+            ],
+        )]);
+        emulator.run(1_000_000);
+        assert!(emulator.is_halted());
+        // With the compiled OS, Keyboard.vm's static comes before Main.vm's
+        assert_eq!(emulator.ram[17], 5);
+
+        // Builtins that are never called take no slots
+        let mut emulator = load(&[("Main.vm", &["push constant 5", "pop static 0"])]);
+        emulator.ram[SP] = 256;
+        emulator.run(100);
+        assert_eq!(emulator.ram[16], 5);
+    }
+
+    #[test]
+    fn test_nested_call_step_limit() {
+        // Array.new calls Memory.alloc, which never returns here
+        let mut emulator = load(&[(
+            "Sys.vm",
+            &[
+                "function Sys.init 0",
+                "push constant 2",
+                "call Array.new 1",
+                "return",
+                "function Memory.alloc 0",
+                "label LOOP",
+                "goto LOOP",
+            ],
+        )]);
+        emulator.nested_step_limit = 1000;
+        emulator.bootstrap().unwrap();
+        assert_eq!(emulator.run(10), 3);
+        assert!(emulator.is_halted());
+        assert_eq!(
+            emulator.runtime_error(),
+            Some("Memory.alloc did not return within 1000 steps")
+        );
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let files = vec![(
+            String::from("Main.vm"),
+            vec![
+                (1, parse_instruction("function Main.main 0").unwrap()),
+                (2, parse_instruction("goto NOWHERE").unwrap()),
+                (3, parse_instruction("call Main.missing 0").unwrap()),
+                (4, parse_instruction("function Main.main 0").unwrap()),
+            ],
+        )];
+        let errors: Vec<String> = VmEmulator::new(files, &EmulatorOptions::default())
+            .err()
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Main.vm:4: Duplicate definition: function Main.main",
+                "Main.vm:2: Undefined label: NOWHERE",
+                "Main.vm:3: Undefined function: Main.missing",
+            ]
+        );
+    }
+}
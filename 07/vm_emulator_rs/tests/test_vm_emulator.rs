@@ -0,0 +1,243 @@
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use vm_emulator_rs::test_script::{run_test_script, run_test_script_with_program};
+use vm_emulator_rs::vm_emulator::{EmulatorOptions, VmEmulator};
+use vm_translator_rs::bytecode;
+use vm_translator_rs::test_util::{inline_slots, observable_ram, HackCpu, TempDir};
+use vm_translator_rs::vm_translator::{parse_file, translate_files, vm_files, TranslationOptions};
+
+fn project_dir(path: &str) -> PathBuf {
+    env::current_dir().unwrap().join("../..").join(path)
+}
+
+fn read_script_tokens(tst_file: &Path) -> Vec<String> {
+    read_to_string(tst_file)
+        .unwrap()
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',' || c == ';'))
+        .filter(|token| !token.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn cpu_test_setup(tst_file: &Path) -> (Vec<(usize, i16)>, usize) {
+    // The RAM initialization and cycle count of a CPU emulator test script
+    let tokens = read_script_tokens(tst_file);
+    let mut ram = Vec::new();
+    let mut cycles = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.as_str() {
+            "set" => {
+                let address = tokens[idx + 1]
+                    .strip_prefix("RAM[")
+                    .and_then(|address| address.strip_suffix(']'))
+                    .unwrap();
+                ram.push((address.parse().unwrap(), tokens[idx + 2].parse().unwrap()));
+            }
+            "repeat" => cycles += tokens[idx + 1].parse::<usize>().unwrap(),
+            _ => (),
+        }
+    }
+    (ram, cycles)
+}
+
+fn run_vme_test_script(dir: &Path, name: &str) {
+    if let Err(reason) = run_test_script(&dir.join(format!("{name}VME.tst"))) {
+        panic!("{name}: {reason}");
+    }
+}
+
+fn compare_with_translation(dir: &Path, name: &str, bootstrap: bool) {
+    // Runs the program on the emulator and as translated assembly on a Hack
+    // CPU, with and without each optimization, and compares the final RAM.
+    let infiles = vm_files(dir);
+    let (setup, cycles) = cpu_test_setup(&dir.join(format!("{name}.tst")));
+    let options = EmulatorOptions {
+        builtins: false,
+        ..Default::default()
+    };
+    let mut emulator = VmEmulator::load(&infiles, &options).unwrap();
+    for &(address, value) in &setup {
+        emulator.ram[address] = value;
+    }
+    if bootstrap {
+        emulator.bootstrap().unwrap();
+    }
+    // Every VM instruction takes at least one CPU cycle
+    emulator.run(cycles);
+    let expected = observable_ram(&emulator.ram, true);
+    for optimize_size in [false, true] {
+        for optimize in [false, true] {
+            let options = TranslationOptions {
+                bootstrap,
+                optimize_size,
+                optimize,
+                ..Default::default()
+            };
+            let asm = translate_files(&infiles, &options).unwrap().asm();
+            let mut cpu = HackCpu::new(&asm);
+            for &(address, value) in &setup {
+                cpu.ram[address] = value;
+            }
+            cpu.run(cycles);
+            let actual = observable_ram(&cpu.ram, true);
+            // Inline slots have no counterpart in the VM
+            let inline_slots = inline_slots(&asm);
+            let mismatches: Vec<_> = expected
+                .iter()
+                .zip(&actual)
+                .filter(|(expected, actual)| {
+                    expected != actual && !inline_slots.contains(&actual.0)
+                })
+                .collect();
+            assert!(
+                expected.len() == actual.len() && mismatches.is_empty(),
+                "{name} (optimize_size: {optimize_size}, optimize: {optimize}): {mismatches:?}"
+            );
+        }
+    }
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_SimpleAdd() {
+    let dir = project_dir("07/StackArithmetic/SimpleAdd");
+    run_vme_test_script(&dir, "SimpleAdd");
+    compare_with_translation(&dir, "SimpleAdd", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_StackTest() {
+    let dir = project_dir("07/StackArithmetic/StackTest");
+    run_vme_test_script(&dir, "StackTest");
+    compare_with_translation(&dir, "StackTest", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_BasicTest() {
+    let dir = project_dir("07/MemoryAccess/BasicTest");
+    run_vme_test_script(&dir, "BasicTest");
+    compare_with_translation(&dir, "BasicTest", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_PointerTest() {
+    let dir = project_dir("07/MemoryAccess/PointerTest");
+    run_vme_test_script(&dir, "PointerTest");
+    compare_with_translation(&dir, "PointerTest", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_StaticTest() {
+    let dir = project_dir("07/MemoryAccess/StaticTest");
+    run_vme_test_script(&dir, "StaticTest");
+    compare_with_translation(&dir, "StaticTest", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_BasicLoop() {
+    let dir = project_dir("08/ProgramFlow/BasicLoop");
+    run_vme_test_script(&dir, "BasicLoop");
+    compare_with_translation(&dir, "BasicLoop", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_FibonacciSeries() {
+    let dir = project_dir("08/ProgramFlow/FibonacciSeries");
+    run_vme_test_script(&dir, "FibonacciSeries");
+    compare_with_translation(&dir, "FibonacciSeries", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_SimpleFunction() {
+    let dir = project_dir("08/FunctionCalls/SimpleFunction");
+    run_vme_test_script(&dir, "SimpleFunction");
+    compare_with_translation(&dir, "SimpleFunction", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_NestedCall() {
+    let dir = project_dir("08/FunctionCalls/NestedCall");
+    run_vme_test_script(&dir, "NestedCall");
+    compare_with_translation(&dir, "NestedCall", false);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_FibonacciElement() {
+    let dir = project_dir("08/FunctionCalls/FibonacciElement");
+    run_vme_test_script(&dir, "FibonacciElement");
+    compare_with_translation(&dir, "FibonacciElement", true);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_StaticsTest() {
+    let dir = project_dir("08/FunctionCalls/StaticsTest");
+    run_vme_test_script(&dir, "StaticsTest");
+    compare_with_translation(&dir, "StaticsTest", true);
+}
+
+#[test]
+fn test_bytecode_input() {
+    // The same program loaded from .vmb files runs to the same state
+    let source_dir = project_dir("08/FunctionCalls/FibonacciElement");
+    let test_dir = TempDir::new("vm_emulator_rs_bytecode");
+    for infile in vm_files(&source_dir) {
+        let outfile = test_dir
+            .join(infile.file_name().unwrap())
+            .with_extension("vmb");
+        bytecode::write_file(&outfile, &parse_file(&infile).unwrap()).unwrap();
+    }
+    let options = EmulatorOptions::default();
+    let mut expected = VmEmulator::load(&vm_files(&source_dir), &options).unwrap();
+    let mut emulator = VmEmulator::load(&vm_files(&test_dir), &options).unwrap();
+    for emulator in [&mut expected, &mut emulator] {
+        emulator.bootstrap().unwrap();
+        emulator.run(10_000);
+    }
+    assert_eq!(emulator.is_halted(), expected.is_halted());
+    assert_eq!(emulator.ram[..2048], expected.ram[..2048]);
+}
+
+fn run_os_test_script(name: &str) {
+    // Project 12 OS tests, with Main.jack compiled by hand into Main.vm
+    let tst_file = project_dir("12").join(name).join(format!("{name}.tst"));
+    let program_dir = env::current_dir()
+        .unwrap()
+        .join("tests/test_data")
+        .join(name);
+    if let Err(reason) = run_test_script_with_program(&tst_file, &program_dir) {
+        panic!("{name}: {reason}");
+    }
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_MathTest() {
+    run_os_test_script("MathTest");
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_MemoryTest() {
+    run_os_test_script("MemoryTest");
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn test_ArrayTest() {
+    // Array.vm (compiled from 12/Array.jack) replaces the builtin Array class
+    run_os_test_script("ArrayTest");
+}
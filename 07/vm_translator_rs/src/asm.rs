@@ -0,0 +1,360 @@
+// Typed Hack assembly. The translator builds its output from these, so that
+// tools can use it without parsing text; each instruction is written as an
+// assembly line by Display, and parsed back by FromStr.
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AsmInstruction {
+    // @value, where the value fits in 15 bits
+    Address(u16),
+    // @symbol: a predefined symbol, label or variable
+    Symbol(String),
+    // dest=comp;jump, where dest and jump are optional
+    Compute {
+        dest: Option<Dest>,
+        comp: Comp,
+        jump: Option<Jump>,
+    },
+    // (label), which does not take a ROM word
+    Label(String),
+    // The text after //, such as an annotation of the VM source
+    Comment(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dest {
+    M,
+    D,
+    MD,
+    A,
+    AM,
+    AD,
+    AMD,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comp {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    DPlusOne,
+    APlusOne,
+    MPlusOne,
+    DMinusOne,
+    AMinusOne,
+    MMinusOne,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jump {
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+impl AsmInstruction {
+    pub fn is_rom_word(&self) -> bool {
+        !matches!(self, AsmInstruction::Label(_) | AsmInstruction::Comment(_))
+    }
+}
+
+impl Dest {
+    fn as_str(&self) -> &str {
+        match self {
+            Dest::M => "M",
+            Dest::D => "D",
+            Dest::MD => "MD",
+            Dest::A => "A",
+            Dest::AM => "AM",
+            Dest::AD => "AD",
+            Dest::AMD => "AMD",
+        }
+    }
+}
+
+impl Comp {
+    fn as_str(&self) -> &str {
+        match self {
+            Comp::Zero => "0",
+            Comp::One => "1",
+            Comp::MinusOne => "-1",
+            Comp::D => "D",
+            Comp::A => "A",
+            Comp::M => "M",
+            Comp::NotD => "!D",
+            Comp::NotA => "!A",
+            Comp::NotM => "!M",
+            Comp::NegD => "-D",
+            Comp::NegA => "-A",
+            Comp::NegM => "-M",
+            Comp::DPlusOne => "D+1",
+            Comp::APlusOne => "A+1",
+            Comp::MPlusOne => "M+1",
+            Comp::DMinusOne => "D-1",
+            Comp::AMinusOne => "A-1",
+            Comp::MMinusOne => "M-1",
+            Comp::DPlusA => "D+A",
+            Comp::DPlusM => "D+M",
+            Comp::DMinusA => "D-A",
+            Comp::DMinusM => "D-M",
+            Comp::AMinusD => "A-D",
+            Comp::MMinusD => "M-D",
+            Comp::DAndA => "D&A",
+            Comp::DAndM => "D&M",
+            Comp::DOrA => "D|A",
+            Comp::DOrM => "D|M",
+        }
+    }
+}
+
+impl Jump {
+    fn as_str(&self) -> &str {
+        match self {
+            Jump::JGT => "JGT",
+            Jump::JEQ => "JEQ",
+            Jump::JGE => "JGE",
+            Jump::JLT => "JLT",
+            Jump::JNE => "JNE",
+            Jump::JLE => "JLE",
+            Jump::JMP => "JMP",
+        }
+    }
+}
+
+impl fmt::Display for Dest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Display for Comp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Dest {
+    type Err = String;
+
+    fn from_str(dest: &str) -> Result<Self, Self::Err> {
+        match dest {
+            "M" => Ok(Dest::M),
+            "D" => Ok(Dest::D),
+            "MD" => Ok(Dest::MD),
+            "A" => Ok(Dest::A),
+            "AM" => Ok(Dest::AM),
+            "AD" => Ok(Dest::AD),
+            "AMD" => Ok(Dest::AMD),
+            _ => Err(format!("Invalid dest: {dest}")),
+        }
+    }
+}
+
+impl FromStr for Comp {
+    type Err = String;
+
+    fn from_str(comp: &str) -> Result<Self, Self::Err> {
+        // Like the assembler, accepts commutative operations with their
+        // operands in either order. They are written in the order above.
+        match comp {
+            "0" => Ok(Comp::Zero),
+            "1" => Ok(Comp::One),
+            "-1" => Ok(Comp::MinusOne),
+            "D" => Ok(Comp::D),
+            "A" => Ok(Comp::A),
+            "M" => Ok(Comp::M),
+            "!D" => Ok(Comp::NotD),
+            "!A" => Ok(Comp::NotA),
+            "!M" => Ok(Comp::NotM),
+            "-D" => Ok(Comp::NegD),
+            "-A" => Ok(Comp::NegA),
+            "-M" => Ok(Comp::NegM),
+            "D+1" => Ok(Comp::DPlusOne),
+            "A+1" => Ok(Comp::APlusOne),
+            "M+1" => Ok(Comp::MPlusOne),
+            "D-1" => Ok(Comp::DMinusOne),
+            "A-1" => Ok(Comp::AMinusOne),
+            "M-1" => Ok(Comp::MMinusOne),
+            "D+A" | "A+D" => Ok(Comp::DPlusA),
+            "D+M" | "M+D" => Ok(Comp::DPlusM),
+            "D-A" => Ok(Comp::DMinusA),
+            "D-M" => Ok(Comp::DMinusM),
+            "A-D" => Ok(Comp::AMinusD),
+            "M-D" => Ok(Comp::MMinusD),
+            "D&A" | "A&D" => Ok(Comp::DAndA),
+            "D&M" | "M&D" => Ok(Comp::DAndM),
+            "D|A" | "A|D" => Ok(Comp::DOrA),
+            "D|M" | "M|D" => Ok(Comp::DOrM),
+            _ => Err(format!("Invalid comp: {comp}")),
+        }
+    }
+}
+
+impl FromStr for Jump {
+    type Err = String;
+
+    fn from_str(jump: &str) -> Result<Self, Self::Err> {
+        match jump {
+            "JGT" => Ok(Jump::JGT),
+            "JEQ" => Ok(Jump::JEQ),
+            "JGE" => Ok(Jump::JGE),
+            "JLT" => Ok(Jump::JLT),
+            "JNE" => Ok(Jump::JNE),
+            "JLE" => Ok(Jump::JLE),
+            "JMP" => Ok(Jump::JMP),
+            _ => Err(format!("Invalid jump: {jump}")),
+        }
+    }
+}
+
+impl fmt::Display for AsmInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmInstruction::Address(value) => write!(f, "@{value}"),
+            AsmInstruction::Symbol(symbol) => write!(f, "@{symbol}"),
+            AsmInstruction::Compute { dest, comp, jump } => {
+                if let Some(dest) = dest {
+                    write!(f, "{dest}=")?;
+                }
+                write!(f, "{comp}")?;
+                if let Some(jump) = jump {
+                    write!(f, ";{jump}")?;
+                }
+                Ok(())
+            }
+            AsmInstruction::Label(label) => write!(f, "({label})"),
+            AsmInstruction::Comment(comment) => write!(f, "//{comment}"),
+        }
+    }
+}
+
+impl FromStr for AsmInstruction {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix("//") {
+            return Ok(AsmInstruction::Comment(comment.to_owned()));
+        }
+        if let Some(label) = line.strip_prefix('(') {
+            return match label.strip_suffix(')') {
+                Some(label) if !label.is_empty() => Ok(AsmInstruction::Label(label.to_owned())),
+                _ => Err(format!("Invalid label: {line}")),
+            };
+        }
+        if let Some(value) = line.strip_prefix('@') {
+            if value.starts_with(|c: char| c.is_ascii_digit()) {
+                return match value.parse::<u16>() {
+                    Ok(value) if value < 32768 => Ok(AsmInstruction::Address(value)),
+                    _ => Err(format!("Invalid address: {line}")),
+                };
+            }
+            if value.is_empty() || value.contains(char::is_whitespace) {
+                return Err(format!("Invalid symbol: {line}"));
+            }
+            return Ok(AsmInstruction::Symbol(value.to_owned()));
+        }
+        let invalid = |_| format!("Invalid instruction: {line}");
+        let (dest, rest) = match line.split_once('=') {
+            Some((dest, rest)) => (Some(dest.parse().map_err(invalid)?), rest),
+            None => (None, line),
+        };
+        let (comp, jump) = match rest.split_once(';') {
+            Some((comp, jump)) => (comp, Some(jump.parse().map_err(invalid)?)),
+            None => (rest, None),
+        };
+        Ok(AsmInstruction::Compute {
+            dest,
+            comp: comp.parse().map_err(invalid)?,
+            jump,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsmInstruction, Comp, Dest, Jump};
+
+    #[test]
+    fn test_parse_asm_instruction() {
+        let test_cases = [
+            ("@256", AsmInstruction::Address(256)),
+            ("@SP", AsmInstruction::Symbol(String::from("SP"))),
+            (
+                "@Main.main$ret$0",
+                AsmInstruction::Symbol(String::from("Main.main$ret$0")),
+            ),
+            (
+                "AM=M-1",
+                AsmInstruction::Compute {
+                    dest: Some(Dest::AM),
+                    comp: Comp::MMinusOne,
+                    jump: None,
+                },
+            ),
+            (
+                "0;JMP",
+                AsmInstruction::Compute {
+                    dest: None,
+                    comp: Comp::Zero,
+                    jump: Some(Jump::JMP),
+                },
+            ),
+            ("(LOOP)", AsmInstruction::Label(String::from("LOOP"))),
+            (
+                "// Main.vm:3: add",
+                AsmInstruction::Comment(String::from(" Main.vm:3: add")),
+            ),
+        ];
+        for (line, instruction) in test_cases {
+            assert_eq!(line.parse::<AsmInstruction>(), Ok(instruction.clone()));
+            assert_eq!(instruction.to_string(), line);
+        }
+        // Commuted operands are read, and written in the canonical order
+        let commuted = "M=M+D".parse::<AsmInstruction>().unwrap();
+        assert_eq!(
+            commuted,
+            AsmInstruction::Compute {
+                dest: Some(Dest::M),
+                comp: Comp::DPlusM,
+                jump: None,
+            }
+        );
+        assert_eq!(commuted.to_string(), "M=D+M");
+        for line in [
+            "@32768", "@", "()", "", "M =D", "0; JMP", "X=D", "D+2", "D;JMQ",
+        ] {
+            assert!(line.parse::<AsmInstruction>().is_err(), "{line}");
+        }
+    }
+}
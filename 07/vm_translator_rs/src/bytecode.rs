@@ -0,0 +1,357 @@
+// Binary encoding of VM programs (.vmb files), which is smaller than the
+// text form and needs no parsing. All numbers are little-endian:
+//
+//   magic      "VMB" followed by the format version (1)
+//   strings    u16 count, then each as a u16 byte length and UTF-8 bytes
+//   commands   u32 count, then each as its u32 source line number, a u8
+//              opcode and its operands:
+//                push, pop        u8 segment, u16 index
+//                label, goto,     u16 string index
+//                if-goto
+//                function, call   u16 string index, u16 count
+//
+// Function and label names are stored once in the string table.
+use std::collections::HashMap;
+use std::fs::{read, write};
+use std::path::Path;
+
+use crate::vm_translator::parser::{check_count, check_index, parse_symbol, ParsedVMInstruction};
+use crate::vm_translator::MemorySegment;
+
+const MAGIC: &[u8] = b"VMB\x01";
+
+const ADD: u8 = 0;
+const SUB: u8 = 1;
+const NEG: u8 = 2;
+const EQ: u8 = 3;
+const GT: u8 = 4;
+const LT: u8 = 5;
+const AND: u8 = 6;
+const OR: u8 = 7;
+const NOT: u8 = 8;
+const PUSH: u8 = 9;
+const POP: u8 = 10;
+const LABEL: u8 = 11;
+const GOTO: u8 = 12;
+const IF_GOTO: u8 = 13;
+const FUNCTION: u8 = 14;
+const CALL: u8 = 15;
+const RETURN: u8 = 16;
+const MUL: u8 = 17;
+const DIV: u8 = 18;
+const MOD: u8 = 19;
+const SHL: u8 = 20;
+const SHR: u8 = 21;
+
+// Segments in the order of their codes
+const SEGMENTS: [MemorySegment; 8] = [
+    MemorySegment::Local,
+    MemorySegment::Argument,
+    MemorySegment::This,
+    MemorySegment::That,
+    MemorySegment::Constant,
+    MemorySegment::Static,
+    MemorySegment::Pointer,
+    MemorySegment::Temp,
+];
+
+pub fn is_bytecode(infile: &Path) -> bool {
+    infile.extension().is_some_and(|ext| ext == "vmb")
+}
+
+#[derive(Default)]
+struct Strings<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, u16>,
+}
+
+impl<'a> Strings<'a> {
+    fn index(&mut self, string: &'a str) -> Result<u16, String> {
+        if let Some(&idx) = self.indices.get(string) {
+            return Ok(idx);
+        }
+        // The count of strings must fit in a u16 too
+        let idx = u16::try_from(self.strings.len())
+            .ok()
+            .filter(|&idx| idx < u16::MAX)
+            .ok_or("Too many names")?;
+        self.strings.push(string);
+        self.indices.insert(string, idx);
+        Ok(idx)
+    }
+}
+
+/// Encodes instructions, paired with their source line numbers.
+pub fn encode(instructions: &[(usize, ParsedVMInstruction)]) -> Result<Vec<u8>, String> {
+    let mut strings = Strings::default();
+    let mut commands = Vec::new();
+    let count = u32::try_from(instructions.len()).map_err(|_| "Too many instructions")?;
+    commands.extend(count.to_le_bytes());
+    for (line, instruction) in instructions {
+        let line = u32::try_from(*line).map_err(|_| format!("Line {line} out of range"))?;
+        commands.extend(line.to_le_bytes());
+        let (opcode, operands): (u8, Vec<u8>) = match instruction {
+            ParsedVMInstruction::Add => (ADD, vec![]),
+            ParsedVMInstruction::Sub => (SUB, vec![]),
+            ParsedVMInstruction::Neg => (NEG, vec![]),
+            ParsedVMInstruction::Eq => (EQ, vec![]),
+            ParsedVMInstruction::Gt => (GT, vec![]),
+            ParsedVMInstruction::Lt => (LT, vec![]),
+            ParsedVMInstruction::And => (AND, vec![]),
+            ParsedVMInstruction::Or => (OR, vec![]),
+            ParsedVMInstruction::Not => (NOT, vec![]),
+            ParsedVMInstruction::Mul => (MUL, vec![]),
+            ParsedVMInstruction::Div => (DIV, vec![]),
+            ParsedVMInstruction::Mod => (MOD, vec![]),
+            ParsedVMInstruction::Shl => (SHL, vec![]),
+            ParsedVMInstruction::Shr => (SHR, vec![]),
+            ParsedVMInstruction::Push { segment, idx } => (PUSH, encode_segment(segment, *idx)?),
+            ParsedVMInstruction::Pop { segment, idx } => (POP, encode_segment(segment, *idx)?),
+            ParsedVMInstruction::Label { label } => {
+                (LABEL, strings.index(label)?.to_le_bytes().to_vec())
+            }
+            ParsedVMInstruction::Goto { label } => {
+                (GOTO, strings.index(label)?.to_le_bytes().to_vec())
+            }
+            ParsedVMInstruction::IfGoto { label } => {
+                (IF_GOTO, strings.index(label)?.to_le_bytes().to_vec())
+            }
+            ParsedVMInstruction::Function { name, n_vars } => {
+                (FUNCTION, encode_symbol_count(strings.index(name)?, *n_vars))
+            }
+            ParsedVMInstruction::Call { function, n_args } => {
+                (CALL, encode_symbol_count(strings.index(function)?, *n_args))
+            }
+            ParsedVMInstruction::Return => (RETURN, vec![]),
+        };
+        commands.push(opcode);
+        commands.extend(operands);
+    }
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend((strings.strings.len() as u16).to_le_bytes());
+    for string in strings.strings {
+        let len = u16::try_from(string.len()).map_err(|_| format!("Name too long: {string}"))?;
+        bytes.extend(len.to_le_bytes());
+        bytes.extend(string.as_bytes());
+    }
+    bytes.extend(commands);
+    Ok(bytes)
+}
+
+fn encode_segment(segment: &MemorySegment, idx: u16) -> Result<Vec<u8>, String> {
+    let code = SEGMENTS
+        .iter()
+        .position(|known| known == segment)
+        .ok_or_else(|| format!("Cannot encode the {segment} segment"))?;
+    let mut operands = vec![code as u8];
+    operands.extend(idx.to_le_bytes());
+    Ok(operands)
+}
+
+fn encode_symbol_count(symbol: u16, count: u16) -> Vec<u8> {
+    let mut operands = symbol.to_le_bytes().to_vec();
+    operands.extend(count.to_le_bytes());
+    operands
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + len)
+            .ok_or_else(|| format!("Unexpected end of bytecode at byte {}", self.position))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Decodes instructions with their source line numbers, checking them just
+/// like the text parser does.
+pub fn decode(bytes: &[u8]) -> Result<Vec<(usize, ParsedVMInstruction)>, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+        return Err(String::from("Not a VM bytecode file"));
+    }
+    let mut strings = Vec::new();
+    for _ in 0..reader.u16()? {
+        let len = reader.u16()? as usize;
+        let string = std::str::from_utf8(reader.take(len)?).map_err(|_| "Invalid name")?;
+        strings.push(parse_symbol(string)?);
+    }
+    let count = reader.u32()?;
+    let mut instructions = Vec::new();
+    for _ in 0..count {
+        let line = reader.u32()? as usize;
+        let opcode = reader.u8()?;
+        let string = |reader: &mut Reader| -> Result<String, String> {
+            let idx = reader.u16()? as usize;
+            strings
+                .get(idx)
+                .cloned()
+                .ok_or_else(|| format!("Invalid name index {idx}"))
+        };
+        let instruction = match opcode {
+            ADD => ParsedVMInstruction::Add,
+            SUB => ParsedVMInstruction::Sub,
+            NEG => ParsedVMInstruction::Neg,
+            EQ => ParsedVMInstruction::Eq,
+            GT => ParsedVMInstruction::Gt,
+            LT => ParsedVMInstruction::Lt,
+            AND => ParsedVMInstruction::And,
+            OR => ParsedVMInstruction::Or,
+            NOT => ParsedVMInstruction::Not,
+            MUL => ParsedVMInstruction::Mul,
+            DIV => ParsedVMInstruction::Div,
+            MOD => ParsedVMInstruction::Mod,
+            SHL => ParsedVMInstruction::Shl,
+            SHR => ParsedVMInstruction::Shr,
+            PUSH | POP => {
+                let code = reader.u8()?;
+                let segment = SEGMENTS
+                    .get(code as usize)
+                    .cloned()
+                    .ok_or_else(|| format!("Invalid segment code {code}"))?;
+                let idx = check_index(&segment, reader.u16()?)?;
+                if opcode == PUSH {
+                    ParsedVMInstruction::Push { segment, idx }
+                } else if segment == MemorySegment::Constant {
+                    return Err(String::from("Cannot pop to the constant segment"));
+                } else {
+                    ParsedVMInstruction::Pop { segment, idx }
+                }
+            }
+            LABEL => ParsedVMInstruction::Label {
+                label: string(&mut reader)?,
+            },
+            GOTO => ParsedVMInstruction::Goto {
+                label: string(&mut reader)?,
+            },
+            IF_GOTO => ParsedVMInstruction::IfGoto {
+                label: string(&mut reader)?,
+            },
+            FUNCTION => ParsedVMInstruction::Function {
+                name: string(&mut reader)?,
+                n_vars: check_count(reader.u16()?)?,
+            },
+            CALL => ParsedVMInstruction::Call {
+                function: string(&mut reader)?,
+                n_args: check_count(reader.u16()?)?,
+            },
+            RETURN => ParsedVMInstruction::Return,
+            _ => return Err(format!("Invalid opcode {opcode}")),
+        };
+        instructions.push((line, instruction));
+    }
+    if reader.position != bytes.len() {
+        return Err(format!(
+            "Unexpected data after the last command at byte {}",
+            reader.position
+        ));
+    }
+    Ok(instructions)
+}
+
+pub fn read_file(infile: &Path) -> Result<Vec<(usize, ParsedVMInstruction)>, String> {
+    decode(&read(infile).map_err(|error| error.to_string())?)
+}
+
+pub fn write_file(
+    outfile: &Path,
+    instructions: &[(usize, ParsedVMInstruction)],
+) -> Result<(), String> {
+    write(outfile, encode(instructions)?).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+    use crate::vm_translator::parser::{parse_instruction, ParsedVMInstruction};
+    use crate::vm_translator::MemorySegment;
+
+    #[test]
+    fn test_round_trip() {
+        let instructions: Vec<(usize, ParsedVMInstruction)> = [
+            "function Main.main 2",
+            "push constant 7",
+            "pop local 1",
+            "label LOOP",
+            "push static 3",
+            "push temp 7",
+            "add",
+            "if-goto LOOP",
+            "call Math.multiply 2",
+            "goto LOOP",
+            "neg",
+            "push constant 3",
+            "shl",
+            "push local 1",
+            "mod",
+            "return",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(idx, line)| (idx * 2 + 1, parse_instruction(line).unwrap()))
+        .collect();
+        let bytes = encode(&instructions).unwrap();
+        assert_eq!(decode(&bytes).unwrap(), instructions);
+        // LOOP is stored once
+        let text_len: usize = instructions
+            .iter()
+            .map(|(_, i)| i.to_string().len() + 1)
+            .sum();
+        assert!(bytes.len() < text_len, "{} >= {text_len}", bytes.len());
+    }
+
+    #[test]
+    fn test_invalid_bytecode() {
+        let push = |segment: u8, idx: u16| {
+            let mut bytes = b"VMB\x01\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00\x09".to_vec();
+            bytes.push(segment);
+            bytes.extend(idx.to_le_bytes());
+            bytes
+        };
+        assert_eq!(
+            decode(&push(4, 17)).unwrap(),
+            [(
+                1,
+                ParsedVMInstruction::Push {
+                    segment: MemorySegment::Constant,
+                    idx: 17
+                }
+            )]
+        );
+        assert_eq!(decode(b"VM"), Err(String::from("Not a VM bytecode file")));
+        assert_eq!(
+            decode(&push(7, 8)),
+            Err(String::from(
+                "Invalid temp index 8: must be between 0 and 7"
+            ))
+        );
+        assert_eq!(
+            decode(&push(8, 0)),
+            Err(String::from("Invalid segment code 8"))
+        );
+        let truncated = push(0, 0);
+        assert_eq!(
+            decode(&truncated[..truncated.len() - 1]),
+            Err(String::from("Unexpected end of bytecode at byte 16"))
+        );
+    }
+}
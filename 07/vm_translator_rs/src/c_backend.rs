@@ -0,0 +1,629 @@
+// Translates a VM program into a single self-contained C program, as an
+// alternative to the Hack assembly translator for running programs natively.
+// RAM is a `short` array laid out exactly as on the Hack platform, and each VM
+// function and label becomes a C label in one big function, so calls and
+// returns work on the VM stack just like in the assembly translation. Return
+// addresses are numbered return sites rather than ROM addresses.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{parse_file, MemorySegment, VmError};
+
+// Statics are allocated from RAM[16] in order of first use, like the
+// assembler allocates variables
+const STATIC_BASE: u16 = 16;
+const TEMP_BASE: u16 = 5;
+const POINTER_BASE: u16 = 3;
+const ENTRY_POINT: &str = "Sys.init";
+
+const PRELUDE: &str = r#"#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define SCREEN 16384
+#define KBD 24576
+
+static short RAM[32768];
+#define SP RAM[0]
+#define LCL RAM[1]
+#define ARG RAM[2]
+#define THIS RAM[3]
+#define THAT RAM[4]
+/* Addresses wrap around like the 15-bit Hack address bus */
+#define M(address) RAM[(unsigned short)(address) & 0x7fff]
+#define TOP M(SP - 1)
+
+static void push(short value) { M(SP) = value; SP++; }
+static short pop(void) { SP--; return M(SP); }
+
+/* Division truncates and wraps like the assembly runtime; dividing by zero
+   gives -1 and a remainder of x */
+static short quotient(short x, short y) {
+    if (y == 0) return -1;
+    if (y == -1) return (short)-x;
+    return x / y;
+}
+
+static short modulo(short x, short y) {
+    if (y == 0) return x;
+    if (y == -1) return 0;
+    return x % y;
+}
+
+/* The keyboard script has a line "<step> <key code>" for every key press
+   or release (key code 0), in order of steps */
+static FILE *keys;
+static long key_step = -1;
+static short key_code;
+
+static void next_key(void) {
+    if (!keys || fscanf(keys, "%ld %hd", &key_step, &key_code) != 2) key_step = -1;
+}
+
+/* Steps are counted at jumps and function entries, which every endless
+   loop passes through */
+static long steps, max_steps = 100000000;
+
+static int tick(void) {
+    steps++;
+    while (key_step >= 0 && steps >= key_step) {
+        RAM[KBD] = key_code;
+        next_key();
+    }
+    return steps <= max_steps;
+}
+
+static void write_screen(const char *path) {
+    FILE *file = fopen(path, "wb");
+    if (!file) {
+        perror(path);
+        exit(1);
+    }
+    fprintf(file, "P6\n512 256\n255\n");
+    for (int row = 0; row < 256; row++) {
+        for (int col = 0; col < 512; col++) {
+            int black = RAM[SCREEN + row * 32 + col / 16] >> (col % 16) & 1;
+            for (int channel = 0; channel < 3; channel++) fputc(black ? 0 : 255, file);
+        }
+    }
+    fclose(file);
+}
+"#;
+
+const MAIN: &str = r#"int main(int argc, char **argv) {
+    const char *screen = NULL;
+    int dump = 0;
+    for (int i = 1; i < argc; i++) {
+        if (!strcmp(argv[i], "--steps") && i + 1 < argc) {
+            max_steps = atol(argv[++i]);
+        } else if (!strcmp(argv[i], "--keys") && i + 1 < argc) {
+            if (!(keys = fopen(argv[++i], "r"))) {
+                perror(argv[i]);
+                return 1;
+            }
+        } else if (!strcmp(argv[i], "--screen") && i + 1 < argc) {
+            screen = argv[++i];
+        } else if (!strcmp(argv[i], "--set") && i + 2 < argc) {
+            int address = atoi(argv[++i]);
+            M(address) = (short)atoi(argv[++i]);
+        } else if (!strcmp(argv[i], "--dump") && i + 1 < argc) {
+            dump = atoi(argv[++i]);
+        } else {
+            fprintf(stderr, "Usage: %s [--steps N] [--keys FILE] [--screen FILE.ppm] "
+                            "[--set ADDRESS VALUE]... [--dump N]\n", argv[0]);
+            return 1;
+        }
+    }
+    next_key();
+    run();
+    if (screen) write_screen(screen);
+    for (int address = 0; address < dump && address < 32768; address++) {
+        printf("%d\n", RAM[address]);
+    }
+    return 0;
+}"#;
+
+// File name, static namespace and instructions
+type ParsedFile = (String, String, Vec<(usize, ParsedVMInstruction)>);
+
+struct Program {
+    files: Vec<ParsedFile>,
+    functions: HashMap<String, usize>,
+    // Labels are scoped to their function, or to their file outside any
+    // function, just like the assembly translator's label names
+    labels: HashMap<(String, String), usize>,
+    statics: HashMap<(String, u16), u16>,
+    return_sites: usize,
+    errors: Vec<VmError>,
+}
+
+impl Program {
+    fn error(&mut self, file: &str, line: usize, reason: String) {
+        self.errors.push(VmError {
+            file: file.to_owned(),
+            line,
+            reason,
+        });
+    }
+
+    fn define_names(&mut self) {
+        let mut duplicates = Vec::new();
+        for (file_name, static_base, instructions) in &self.files {
+            let mut scope = static_base.clone();
+            for (line, instruction) in instructions {
+                match instruction {
+                    ParsedVMInstruction::Function { name, .. } => {
+                        let id = self.functions.len();
+                        if self.functions.insert(name.clone(), id).is_some() {
+                            duplicates.push((file_name.clone(), *line, instruction.to_string()));
+                        }
+                        scope = name.clone();
+                    }
+                    ParsedVMInstruction::Label { label } => {
+                        let id = self.labels.len();
+                        if self
+                            .labels
+                            .insert((scope.clone(), label.clone()), id)
+                            .is_some()
+                        {
+                            duplicates.push((file_name.clone(), *line, instruction.to_string()));
+                        }
+                    }
+                    ParsedVMInstruction::Push {
+                        segment: MemorySegment::Static,
+                        idx,
+                    }
+                    | ParsedVMInstruction::Pop {
+                        segment: MemorySegment::Static,
+                        idx,
+                    } => {
+                        let address = STATIC_BASE + self.statics.len() as u16;
+                        self.statics
+                            .entry((static_base.clone(), *idx))
+                            .or_insert(address);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for (file_name, line, instruction) in duplicates {
+            self.error(
+                &file_name,
+                line,
+                format!("Duplicate definition: {instruction}"),
+            );
+        }
+    }
+
+    fn location(&self, static_base: &str, segment: &MemorySegment, idx: u16) -> String {
+        match segment {
+            MemorySegment::Local => format!("M(LCL + {idx})"),
+            MemorySegment::Argument => format!("M(ARG + {idx})"),
+            MemorySegment::This => format!("M(THIS + {idx})"),
+            MemorySegment::That => format!("M(THAT + {idx})"),
+            MemorySegment::Constant => idx.to_string(),
+            MemorySegment::Static => {
+                format!("RAM[{}]", self.statics[&(static_base.to_owned(), idx)])
+            }
+            MemorySegment::Pointer => format!("RAM[{}]", POINTER_BASE + idx),
+            MemorySegment::Temp => format!("RAM[{}]", TEMP_BASE + idx),
+        }
+    }
+
+    fn call(&mut self, function: &str, n_args: u16) -> Option<String> {
+        let function_id = *self.functions.get(function)?;
+        let return_site = self.return_sites;
+        self.return_sites += 1;
+        Some(format!(
+            "push({return_site}); push(LCL); push(ARG); push(THIS); push(THAT); \
+             ARG = SP - {}; LCL = SP; goto F{function_id}; R{return_site}:;",
+            n_args + 5
+        ))
+    }
+
+    fn statement(
+        &mut self,
+        instruction: &ParsedVMInstruction,
+        static_base: &str,
+        scope: &str,
+    ) -> Result<String, String> {
+        let binary = |operation: &str| format!("t = pop(); TOP = (short)({operation});");
+        let label = |label: &str| {
+            self.labels
+                .get(&(scope.to_owned(), label.to_owned()))
+                .map(|id| format!("if (tick()) goto L{id}; else goto halt;"))
+                .ok_or_else(|| format!("Undefined label: {label}"))
+        };
+        let statement = match instruction {
+            ParsedVMInstruction::Add => binary("TOP + t"),
+            ParsedVMInstruction::Sub => binary("TOP - t"),
+            ParsedVMInstruction::Neg => String::from("TOP = (short)-TOP;"),
+            ParsedVMInstruction::Eq => binary("-(TOP == t)"),
+            ParsedVMInstruction::Gt => binary("-(TOP > t)"),
+            ParsedVMInstruction::Lt => binary("-(TOP < t)"),
+            ParsedVMInstruction::And => binary("TOP & t"),
+            ParsedVMInstruction::Or => binary("TOP | t"),
+            ParsedVMInstruction::Not => String::from("TOP = ~TOP;"),
+            ParsedVMInstruction::Mul => binary("TOP * t"),
+            ParsedVMInstruction::Div => String::from("t = pop(); TOP = quotient(TOP, t);"),
+            ParsedVMInstruction::Mod => String::from("t = pop(); TOP = modulo(TOP, t);"),
+            ParsedVMInstruction::Shl => {
+                binary("(unsigned short)t < 16 ? (unsigned short)TOP << t : 0")
+            }
+            ParsedVMInstruction::Shr => {
+                binary("(unsigned short)t < 16 ? (unsigned short)TOP >> t : 0")
+            }
+            ParsedVMInstruction::Push { segment, idx } => {
+                format!("push({});", self.location(static_base, segment, *idx))
+            }
+            ParsedVMInstruction::Pop { segment, idx } => {
+                format!(
+                    "t = pop(); {} = t;",
+                    self.location(static_base, segment, *idx)
+                )
+            }
+            ParsedVMInstruction::Label { label } => {
+                format!("L{}:;", self.labels[&(scope.to_owned(), label.clone())])
+            }
+            ParsedVMInstruction::Goto { label: target } => label(target)?,
+            ParsedVMInstruction::IfGoto { label: target } => {
+                format!("if (pop()) {{ {} }}", label(target)?)
+            }
+            ParsedVMInstruction::Function { name, n_vars } => {
+                let mut statement = format!("F{}:; if (!tick()) goto halt;", self.functions[name]);
+                if *n_vars > 0 {
+                    statement.push_str(&format!(" for (t = 0; t < {n_vars}; t++) push(0);"));
+                }
+                statement
+            }
+            ParsedVMInstruction::Call { function, n_args } => self
+                .call(function, *n_args)
+                .ok_or_else(|| format!("Undefined function: {function}"))?,
+            ParsedVMInstruction::Return => String::from(
+                "frame = LCL; ret = M(frame - 5); M(ARG) = pop(); SP = ARG + 1; \
+                 THAT = M(frame - 1); THIS = M(frame - 2); ARG = M(frame - 3); \
+                 LCL = M(frame - 4); goto dispatch;",
+            ),
+        };
+        Ok(statement)
+    }
+
+    fn run_function(&mut self, bootstrap: bool) -> Vec<String> {
+        let mut lines = vec![
+            String::from("static void run(void) {"),
+            String::from("    short t = 0, frame = 0;"),
+            String::from("    unsigned short ret = 0;"),
+        ];
+        if bootstrap && self.functions.contains_key(ENTRY_POINT) {
+            lines.push(String::from("    SP = 256;"));
+            let call = self.call(ENTRY_POINT, 0).unwrap();
+            lines.push(format!("    {call} /* bootstrap */"));
+        }
+        let files = std::mem::take(&mut self.files);
+        for (file_name, static_base, instructions) in &files {
+            let mut scope = static_base.clone();
+            for (line, instruction) in instructions {
+                if let ParsedVMInstruction::Function { name, .. } = instruction {
+                    scope = name.clone();
+                }
+                match self.statement(instruction, static_base, &scope) {
+                    Ok(statement) => lines.push(format!(
+                        "    {statement} /* {file_name}:{line}: {instruction} */"
+                    )),
+                    Err(reason) => self.error(file_name, *line, reason),
+                }
+            }
+        }
+        self.files = files;
+        lines.push(String::from("    goto halt;"));
+        // Returns jump back to their return site
+        lines.push(String::from("dispatch:"));
+        lines.push(String::from("    switch (ret) {"));
+        for return_site in 0..self.return_sites {
+            lines.push(format!("    case {return_site}: goto R{return_site};"));
+        }
+        lines.push(String::from("    }"));
+        lines.push(String::from("halt:;"));
+        lines.push(String::from("}"));
+        lines
+    }
+}
+
+/// Translates .vm (or .vmb) files into the lines of a C program, which starts
+/// by calling Sys.init if bootstrap is set and Sys.init exists. The program
+/// runs until it falls off its last command or its step limit, and can set
+/// RAM beforehand, feed the keyboard from a script, write the screen to a PPM
+/// image and dump RAM afterwards (run it without arguments for its usage).
+pub fn translate_to_c(infiles: &[PathBuf], bootstrap: bool) -> Result<Vec<String>, Vec<VmError>> {
+    let mut program = Program {
+        files: Vec::new(),
+        functions: HashMap::new(),
+        labels: HashMap::new(),
+        statics: HashMap::new(),
+        return_sites: 0,
+        errors: Vec::new(),
+    };
+    for infile in infiles {
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        let static_base = infile.file_stem().unwrap().to_str().unwrap();
+        match parse_file(infile) {
+            Ok(instructions) => {
+                program
+                    .files
+                    .push((file_name.to_owned(), static_base.to_owned(), instructions))
+            }
+            Err(errors) => program.errors.extend(errors),
+        }
+    }
+    if !program.errors.is_empty() {
+        return Err(program.errors);
+    }
+    program.define_names();
+    let run = program.run_function(bootstrap);
+    if !program.errors.is_empty() {
+        return Err(program.errors);
+    }
+    let sources: Vec<&str> = infiles
+        .iter()
+        .map(|infile| infile.file_name().unwrap().to_str().unwrap())
+        .collect();
+    let mut lines = vec![format!(
+        "/* Translated from {} by vm_translator_rs */",
+        sources.join(", ")
+    )];
+    lines.extend(PRELUDE.lines().map(String::from));
+    lines.extend(run);
+    lines.push(String::new());
+    lines.extend(MAIN.lines().map(String::from));
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::translate_to_c;
+    use crate::test_util::{final_output, HackCpu, TempDir};
+    use crate::vm_translator::{translate_files, vm_files, write_lines, TranslationOptions};
+
+    use std::env;
+    use std::fs::read;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    fn compile(build_dir: &Path, name: &str, c_source: &[String]) -> Option<PathBuf> {
+        // Builds the program in build_dir with the system C compiler, or
+        // returns None if there is none
+        let c_file = build_dir.join(format!("{name}.c"));
+        let binary = build_dir.join(name);
+        write_lines(&c_file, c_source);
+        let Ok(status) = Command::new("cc")
+            .arg("-O1")
+            .arg("-o")
+            .arg(&binary)
+            .arg(&c_file)
+            .status()
+        else {
+            eprintln!("No C compiler found; skipping {name}");
+            return None;
+        };
+        assert!(status.success(), "{name}: compilation failed");
+        Some(binary)
+    }
+
+    fn run(binary: &Path, args: &[String]) -> Vec<i16> {
+        let output = Command::new(binary).args(args).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect()
+    }
+
+    fn compile_and_run_test_script(test_dir: &str, name: &str, bootstrap: bool) {
+        // The C program must produce the script's expected output, just like
+        // the assembly translation does
+        let test_dir = env::current_dir().unwrap().join(test_dir);
+        let infiles = vm_files(&test_dir);
+        let build_dir = TempDir::new(&format!("vm_translator_rs_c_{name}"));
+        let c_source = translate_to_c(&infiles, bootstrap).unwrap();
+        let Some(binary) = compile(&build_dir, name, &c_source) else {
+            return;
+        };
+        let (setup, output_list, expected) = final_output(&test_dir, name);
+        let mut args: Vec<String> = setup
+            .iter()
+            .flat_map(|(address, value)| {
+                [
+                    String::from("--set"),
+                    address.to_string(),
+                    value.to_string(),
+                ]
+            })
+            .collect();
+        args.extend([
+            String::from("--steps"),
+            String::from("100000"),
+            String::from("--dump"),
+            String::from("24577"),
+        ]);
+        let ram = run(&binary, &args);
+        let actual: Vec<i16> = output_list.iter().map(|&address| ram[address]).collect();
+        assert_eq!(actual, expected, "{name}");
+
+        // Statics are where the assembler puts them
+        let options = TranslationOptions {
+            bootstrap,
+            ..Default::default()
+        };
+        let mut cpu = HackCpu::new(&translate_files(&infiles, &options).unwrap().asm());
+        for &(address, value) in &setup {
+            cpu.ram[address] = value;
+        }
+        cpu.run(100_000);
+        assert_eq!(ram[16..256], cpu.ram[16..256], "{name}");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_BasicTest() {
+        compile_and_run_test_script("../../07/MemoryAccess/BasicTest", "BasicTest", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_StackTest() {
+        compile_and_run_test_script("../../07/StackArithmetic/StackTest", "StackTest", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_FibonacciSeries() {
+        compile_and_run_test_script(
+            "../../08/ProgramFlow/FibonacciSeries",
+            "FibonacciSeries",
+            false,
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_FibonacciElement() {
+        compile_and_run_test_script(
+            "../../08/FunctionCalls/FibonacciElement",
+            "FibonacciElement",
+            true,
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_StaticsTest() {
+        compile_and_run_test_script("../../08/FunctionCalls/StaticsTest", "StaticsTest", true);
+    }
+
+    #[test]
+    fn test_keyboard_and_screen() {
+        // Copies the keyboard to the first screen word until the step limit
+        let test_dir = TempDir::new("vm_translator_rs_c_screen");
+        let program = [
+            "function Sys.init 0",
+            "label LOOP",
+            "push constant 24576",
+            "pop pointer 1",
+            "push that 0",
+            "push constant 16384",
+            "pop pointer 1",
+            "pop that 0",
+            "goto LOOP",
+        ];
+        write_lines(&test_dir.join("Sys.vm"), &program.map(String::from));
+        let Some(binary) = compile(
+            &test_dir,
+            "Screen",
+            &translate_to_c(&vm_files(&test_dir), true).unwrap(),
+        ) else {
+            return;
+        };
+        // 'A' is pressed at step 10 and released at step 20, and 'C' pressed
+        // at step 30
+        let keys = test_dir.join("keys.txt");
+        write_lines(
+            &keys,
+            &[
+                String::from("10 65"),
+                String::from("20 0"),
+                String::from("30 67"),
+            ],
+        );
+        let screen = test_dir.join("screen.ppm");
+        let args = [
+            "--steps",
+            "40",
+            "--keys",
+            keys.to_str().unwrap(),
+            "--screen",
+            screen.to_str().unwrap(),
+        ];
+        run(&binary, &args.map(String::from));
+        let image = read(&screen).unwrap();
+        let header = b"P6\n512 256\n255\n";
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(image.len(), header.len() + 512 * 256 * 3);
+        // 67 = 0b1000011 sets pixels 0, 1 and 6 of the first row
+        let black: Vec<usize> = (0..16)
+            .filter(|pixel| image[header.len() + pixel * 3] == 0)
+            .collect();
+        assert_eq!(black, [0, 1, 6]);
+    }
+
+    #[test]
+    fn test_arithmetic_extensions() {
+        // The C helpers must agree with the assembly runtime on the edge cases
+        let test_dir = TempDir::new("vm_translator_rs_c_arithmetic");
+        let mut program = vec![String::from("push constant 3000\npop pointer 1")];
+        let cases: [(i16, i16); 6] = [(7, 3), (-7, 2), (-32767, -1), (100, 0), (255, 4), (-1, 15)];
+        for (x, y) in cases {
+            for operation in ["mul", "div", "mod", "shl", "shr"] {
+                for value in [x, y] {
+                    program.push(format!("push constant {}", value.abs()));
+                    if value < 0 {
+                        program.push(String::from("neg"));
+                    }
+                }
+                program.push(String::from(operation));
+                program.push(String::from(
+                    "pop that 0\npush pointer 1\npush constant 1\nadd\npop pointer 1",
+                ));
+            }
+        }
+        write_lines(&test_dir.join("Arithmetic.vm"), &program);
+        let infiles = vm_files(&test_dir);
+        let Some(binary) = compile(
+            &test_dir,
+            "Arithmetic",
+            &translate_to_c(&infiles, false).unwrap(),
+        ) else {
+            return;
+        };
+        let args = ["--set", "0", "256", "--steps", "100000", "--dump", "3030"];
+        let ram = run(&binary, &args.map(String::from));
+        let options = TranslationOptions {
+            bootstrap: false,
+            ..Default::default()
+        };
+        let mut cpu = HackCpu::new(&translate_files(&infiles, &options).unwrap().asm());
+        cpu.ram[0] = 256;
+        cpu.run(100_000);
+        assert_eq!(ram[3000..3030], cpu.ram[3000..3030]);
+        assert_eq!(ram[3000..3005], [21, 2, 1, 56, 0]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let test_dir = TempDir::new("vm_translator_rs_c_errors");
+        let program = [
+            "function Main.f 0",
+            "goto NOWHERE",
+            "call Main.g 0",
+            "label A",
+            "label A",
+            "function Main.f 0",
+        ];
+        write_lines(&test_dir.join("Main.vm"), &program.map(String::from));
+        let errors: Vec<String> = translate_to_c(&vm_files(&test_dir), false)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Main.vm:5: Duplicate definition: label A",
+                "Main.vm:6: Duplicate definition: function Main.f 0",
+                "Main.vm:2: Undefined label: NOWHERE",
+                "Main.vm:3: Undefined function: Main.g",
+            ]
+        );
+    }
+}
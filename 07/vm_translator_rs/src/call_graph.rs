@@ -0,0 +1,187 @@
+// Call graph of a VM program, built from the function and call commands of
+// all of its files. Used to find the functions reachable from Sys.init so
+// that unused ones (typically most of the OS) can be left out.
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::vm_translator::parser::ParsedVMInstruction;
+
+pub const ENTRY_POINT: &str = "Sys.init";
+
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    // Functions called by each function. Code outside of any function is a
+    // caller named after its file.
+    calls: BTreeMap<String, BTreeSet<String>>,
+    functions: BTreeSet<String>,
+    top_level: BTreeSet<String>,
+}
+
+impl CallGraph {
+    pub fn add_file<'a>(
+        &mut self,
+        file_name: &str,
+        instructions: impl IntoIterator<Item = &'a ParsedVMInstruction>,
+    ) {
+        let mut caller = None;
+        for instruction in instructions {
+            match instruction {
+                ParsedVMInstruction::Function { name, .. } => {
+                    self.functions.insert(name.clone());
+                    self.calls.entry(name.clone()).or_default();
+                    caller = Some(name.clone());
+                    continue;
+                }
+                _ if caller.is_none() => {
+                    self.top_level.insert(file_name.to_owned());
+                    caller = Some(file_name.to_owned());
+                }
+                _ => (),
+            }
+            if let (ParsedVMInstruction::Call { function, .. }, Some(caller)) =
+                (instruction, &caller)
+            {
+                self.calls
+                    .entry(caller.clone())
+                    .or_default()
+                    .insert(function.clone());
+            }
+        }
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &str> {
+        self.functions.iter().map(String::as_str)
+    }
+
+    pub fn reachable(&self) -> Option<BTreeSet<String>> {
+        // Functions reachable from Sys.init or from code outside of any
+        // function, or None if the program has no Sys.init to start from.
+        if !self.functions.contains(ENTRY_POINT) {
+            return None;
+        }
+        let mut reachable = BTreeSet::new();
+        let mut pending: Vec<&String> = self.top_level.iter().collect();
+        pending.push(self.functions.get(ENTRY_POINT).unwrap());
+        while let Some(caller) = pending.pop() {
+            if !reachable.insert(caller.clone()) {
+                continue;
+            }
+            if let Some(callees) = self.calls.get(caller) {
+                pending.extend(callees);
+            }
+        }
+        reachable.retain(|name| self.functions.contains(name));
+        Some(reachable)
+    }
+
+    pub fn unreachable(&self) -> Vec<&str> {
+        match self.reachable() {
+            Some(reachable) => self
+                .functions()
+                .filter(|name| !reachable.contains(*name))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn dot_lines(&self) -> Vec<String> {
+        // Top-level code is drawn as boxes, unreachable functions dashed and
+        // functions that are called but not defined (e.g. OS functions that
+        // were not translated) without an outline.
+        let unreachable = self.unreachable();
+        let mut lines = vec![String::from("digraph calls {")];
+        for file_name in &self.top_level {
+            lines.push(format!("    \"{file_name}\" [shape=box];"));
+        }
+        for name in self.functions() {
+            if unreachable.contains(&name) {
+                lines.push(format!("    \"{name}\" [style=dashed];"));
+            } else {
+                lines.push(format!("    \"{name}\";"));
+            }
+        }
+        let undefined: BTreeSet<&String> = self
+            .calls
+            .values()
+            .flatten()
+            .filter(|callee| !self.functions.contains(*callee))
+            .collect();
+        for name in undefined {
+            lines.push(format!("    \"{name}\" [shape=plaintext];"));
+        }
+        for (caller, callees) in &self.calls {
+            for callee in callees {
+                lines.push(format!("    \"{caller}\" -> \"{callee}\";"));
+            }
+        }
+        lines.push(String::from("}"));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CallGraph;
+    use crate::vm_translator::parser::parse_instruction;
+    use crate::vm_translator::parser::ParsedVMInstruction;
+
+    fn parse(lines: &[&str]) -> Vec<ParsedVMInstruction> {
+        lines
+            .iter()
+            .map(|line| parse_instruction(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_reachable() {
+        let mut graph = CallGraph::default();
+        graph.add_file(
+            "Sys.vm",
+            &parse(&[
+                "function Sys.init 0",
+                "call Main.main 0",
+                "label LOOP",
+                "goto LOOP",
+            ]),
+        );
+        graph.add_file(
+            "Main.vm",
+            &parse(&[
+                "function Main.main 0",
+                "call Main.helper 0",
+                "call Math.multiply 2",
+                "return",
+                "function Main.helper 0",
+                "call Main.helper 0",
+                "return",
+                "function Main.unused 0",
+                "call Main.main 0",
+                "return",
+            ]),
+        );
+        let reachable: Vec<String> = graph.reachable().unwrap().into_iter().collect();
+        assert_eq!(reachable, ["Main.helper", "Main.main", "Sys.init"]);
+        assert_eq!(graph.unreachable(), ["Main.unused"]);
+        let dot = graph.dot_lines();
+        assert!(dot.contains(&String::from("    \"Main.unused\" [style=dashed];")));
+        assert!(dot.contains(&String::from("    \"Math.multiply\" [shape=plaintext];")));
+        assert!(dot.contains(&String::from("    \"Main.main\" -> \"Main.helper\";")));
+    }
+
+    #[test]
+    fn test_top_level_code_and_no_entry_point() {
+        let mut graph = CallGraph::default();
+        graph.add_file(
+            "Test.vm",
+            &parse(&["call Test.f 0", "function Test.f 0", "return"]),
+        );
+        assert_eq!(graph.reachable(), None);
+        assert!(graph.unreachable().is_empty());
+        graph.add_file(
+            "Sys.vm",
+            &parse(&["function Sys.init 0", "function Sys.unused 0"]),
+        );
+        let reachable: Vec<String> = graph.reachable().unwrap().into_iter().collect();
+        assert_eq!(reachable, ["Sys.init", "Test.f"]);
+        assert!(graph.dot_lines()[1].contains("\"Test.vm\" [shape=box]"));
+    }
+}
@@ -0,0 +1,172 @@
+// Formatter for .vm files: every command is written in its canonical form,
+// function bodies are indented, and comments and single blank lines are kept
+// in place.
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::vm_translator::parser::{parse_instruction, ParsedVMInstruction};
+use crate::vm_translator::{split_comment, VmError};
+
+const INDENT: &str = "    ";
+
+pub fn format_source(file_name: &str, source: &str) -> Result<String, Vec<VmError>> {
+    let mut formatted: Vec<String> = Vec::new();
+    let mut errors = Vec::new();
+    // Comment and blank lines wait for the next command, so that they are
+    // indented like it: a comment before a function is not part of the
+    // previous function's body
+    let mut pending: Vec<Option<&str>> = Vec::new();
+    let mut in_function = false;
+    for (idx, line) in source.lines().enumerate() {
+        let (code, comment) = split_comment(line);
+        if code.is_empty() {
+            // Runs of blank lines are collapsed into one
+            if comment.is_some() || pending.last().is_some_and(Option::is_some) {
+                pending.push(comment);
+            } else if pending.is_empty() && formatted.last().is_some_and(|line| !line.is_empty()) {
+                pending.push(None);
+            }
+            continue;
+        }
+        let instruction = match parse_instruction(code) {
+            Ok(instruction) => instruction,
+            Err(reason) => {
+                errors.push(VmError {
+                    file: file_name.to_owned(),
+                    line: idx + 1,
+                    reason,
+                });
+                continue;
+            }
+        };
+        let is_function = matches!(instruction, ParsedVMInstruction::Function { .. });
+        in_function |= is_function;
+        let indent = if in_function && !is_function {
+            INDENT
+        } else {
+            ""
+        };
+        flush(&mut formatted, &mut pending, indent);
+        let mut line = format!("{indent}{instruction}");
+        if let Some(comment) = comment {
+            line.push_str(&format!(" //{}", comment.trim_end()));
+        }
+        formatted.push(line);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let indent = if in_function { INDENT } else { "" };
+    flush(&mut formatted, &mut pending, indent);
+    // No blank lines at either end, and a final newline
+    while formatted.last().is_some_and(String::is_empty) {
+        formatted.pop();
+    }
+    let start = formatted.iter().take_while(|line| line.is_empty()).count();
+    let mut text = formatted[start..].join("\n");
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+fn flush(formatted: &mut Vec<String>, pending: &mut Vec<Option<&str>>, indent: &str) {
+    for comment in pending.drain(..) {
+        formatted.push(match comment {
+            Some(comment) => format!("{indent}//{}", comment.trim_end()),
+            None => String::new(),
+        });
+    }
+}
+
+pub fn format_file(infile: &Path) -> Result<String, Vec<VmError>> {
+    let file_name = infile.file_name().unwrap().to_str().unwrap();
+    format_source(file_name, &read_to_string(infile).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+    use crate::vm_translator::vm_files;
+
+    use std::env;
+    use std::fs::read_to_string;
+
+    #[test]
+    fn test_format_source() {
+        let source = [
+            "",
+            "// Adds two numbers",
+            "   push   constant 7  ",
+            "push constant\t8 // the second",
+            "",
+            "",
+            "function  Main.add 0 ",
+            "push argument 0",
+            "//   both arguments",
+            "push argument 1",
+            "add",
+            "",
+            "// The next function",
+            "function Main.one 0",
+            "  label    LOOP",
+            "push constant 1",
+            "return",
+            "",
+            "// end of file  ",
+            "",
+        ];
+        let expected = [
+            "// Adds two numbers",
+            "push constant 7",
+            "push constant 8 // the second",
+            "",
+            "function Main.add 0",
+            "    push argument 0",
+            "    //   both arguments",
+            "    push argument 1",
+            "    add",
+            "",
+            "// The next function",
+            "function Main.one 0",
+            "    label LOOP",
+            "    push constant 1",
+            "    return",
+            "",
+            "    // end of file",
+            "",
+        ];
+        let formatted = format_source("Main.vm", &source.join("\n")).unwrap();
+        assert_eq!(formatted, expected.join("\n"));
+        // Formatting is idempotent
+        assert_eq!(format_source("Main.vm", &formatted).unwrap(), formatted);
+
+        let errors = format_source("Main.vm", "push constant 1\npush nowhere 2").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+    }
+
+    #[test]
+    fn test_format_test_programs() {
+        // The test programs format without errors, and formatting does not
+        // change their commands
+        let root = env::current_dir().unwrap().join("../..");
+        for test_dir in [
+            "07/StackArithmetic/StackTest",
+            "08/ProgramFlow/FibonacciSeries",
+            "08/FunctionCalls/StaticsTest",
+        ] {
+            for infile in vm_files(&root.join(test_dir)) {
+                let source = read_to_string(&infile).unwrap();
+                let formatted = format_source("Test.vm", &source).unwrap();
+                let commands = |text: &str| -> Vec<String> {
+                    text.lines()
+                        .map(|line| line.split("//").next().unwrap())
+                        .flat_map(|code| code.split_whitespace().map(String::from))
+                        .collect()
+                };
+                assert_eq!(commands(&formatted), commands(&source), "{infile:?}");
+            }
+        }
+    }
+}
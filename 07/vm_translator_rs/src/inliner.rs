@@ -0,0 +1,355 @@
+// Inlining of small leaf functions, applied to the whole program before the
+// peephole optimizer. A call to a function whose body is straight-line code
+// without calls is replaced with that body. The callee's arguments and locals
+// live in inline slots: scratch slots shared by every inlined body, which is
+// safe because an inlined body never calls anything.
+use std::collections::HashMap;
+
+use crate::ir::{Instruction, Location};
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::MemorySegment;
+
+struct Candidate {
+    // Index of the file defining the function
+    file: usize,
+    n_vars: u16,
+    // The commands between `function` and the final `return`
+    body: Vec<Instruction>,
+    // Number of arguments the body reads or writes
+    n_args_used: u16,
+    uses_statics: bool,
+    // Whether the body sets THIS and THAT, which the caller expects back
+    sets_pointer: [bool; 2],
+}
+
+fn segments(instruction: &Instruction) -> Vec<(&MemorySegment, u16)> {
+    instruction
+        .locations()
+        .into_iter()
+        .filter_map(|location| match location {
+            Location::Segment(segment, idx) => Some((segment, *idx)),
+            Location::Inline(_) => None,
+        })
+        .collect()
+}
+
+fn sets_pointer(instruction: &Instruction, pointer: u16) -> bool {
+    match instruction {
+        Instruction::Pop(Location::Segment(MemorySegment::Pointer, idx))
+        | Instruction::Move {
+            to: Location::Segment(MemorySegment::Pointer, idx),
+            ..
+        } => *idx == pointer,
+        _ => false,
+    }
+}
+
+fn leaves_return_value(body: &[Instruction]) -> bool {
+    // The body must only use values it pushed itself and leave exactly one,
+    // since return would otherwise discard the rest of the working stack.
+    let mut depth: usize = 0;
+    for instruction in body {
+        let (pops, pushes) = match instruction {
+            Instruction::Command(
+                ParsedVMInstruction::Add
+                | ParsedVMInstruction::Sub
+                | ParsedVMInstruction::Eq
+                | ParsedVMInstruction::Gt
+                | ParsedVMInstruction::Lt
+                | ParsedVMInstruction::And
+                | ParsedVMInstruction::Or
+                | ParsedVMInstruction::Mul
+                | ParsedVMInstruction::Div
+                | ParsedVMInstruction::Mod
+                | ParsedVMInstruction::Shl
+                | ParsedVMInstruction::Shr,
+            ) => (2, 1),
+            Instruction::Command(ParsedVMInstruction::Neg | ParsedVMInstruction::Not) => (1, 1),
+            Instruction::Push(_) => (0, 1),
+            Instruction::Pop(_) => (1, 0),
+            Instruction::Move { .. } => (0, 0),
+            // Anything that jumps or calls is not straight-line code
+            _ => return false,
+        };
+        let Some(remaining) = depth.checked_sub(pops) else {
+            return false;
+        };
+        depth = remaining + pushes;
+    }
+    depth == 1
+}
+
+fn candidate(file: usize, n_vars: u16, commands: &[Instruction]) -> Option<Candidate> {
+    let (Instruction::Command(ParsedVMInstruction::Return), body) = commands.split_last()? else {
+        return None;
+    };
+    if !leaves_return_value(body) {
+        return None;
+    }
+    let accesses: Vec<(&MemorySegment, u16)> = body.iter().flat_map(segments).collect();
+    // Locals past the declared count would alias the working stack
+    if accesses
+        .iter()
+        .any(|(segment, idx)| **segment == MemorySegment::Local && *idx >= n_vars)
+    {
+        return None;
+    }
+    Some(Candidate {
+        file,
+        n_vars,
+        body: body.to_vec(),
+        n_args_used: accesses
+            .iter()
+            .filter(|(segment, _)| **segment == MemorySegment::Argument)
+            .map(|(_, idx)| idx + 1)
+            .max()
+            .unwrap_or(0),
+        uses_statics: accesses
+            .iter()
+            .any(|(segment, _)| **segment == MemorySegment::Static),
+        sets_pointer: [0, 1].map(|pointer| body.iter().any(|i| sets_pointer(i, pointer))),
+    })
+}
+
+fn is_function(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Command(ParsedVMInstruction::Function { .. })
+    )
+}
+
+fn candidates(files: &[Vec<(Instruction, usize)>], threshold: usize) -> HashMap<String, Candidate> {
+    let mut candidates = HashMap::new();
+    for (file, instructions) in files.iter().enumerate() {
+        let instructions: Vec<&Instruction> = instructions
+            .iter()
+            .map(|(instruction, _)| instruction)
+            .collect();
+        for (start, instruction) in instructions.iter().enumerate() {
+            let Some(ParsedVMInstruction::Function { name, n_vars }) = instruction.command() else {
+                continue;
+            };
+            let end = instructions[start + 1..]
+                .iter()
+                .position(|i| is_function(i))
+                .map_or(instructions.len(), |len| start + 1 + len);
+            // The threshold does not count the return command
+            if end - start - 1 > threshold + 1 {
+                continue;
+            }
+            let commands: Vec<Instruction> = instructions[start + 1..end]
+                .iter()
+                .map(|&i| i.clone())
+                .collect();
+            if let Some(candidate) = candidate(file, *n_vars, &commands) {
+                candidates.entry(name.clone()).or_insert(candidate);
+            }
+        }
+    }
+    candidates
+}
+
+fn remap(instruction: &Instruction, n_args: u16) -> Instruction {
+    // Arguments become inline slots 0..n_args and locals the slots after them
+    let slot = |location: &Location| match location {
+        Location::Segment(MemorySegment::Argument, idx) => Location::Inline(*idx),
+        Location::Segment(MemorySegment::Local, idx) => Location::Inline(n_args + idx),
+        _ => location.clone(),
+    };
+    match instruction {
+        Instruction::Push(location) => Instruction::Push(slot(location)),
+        Instruction::Pop(location) => Instruction::Pop(slot(location)),
+        Instruction::Move { from, to } => Instruction::Move {
+            from: slot(from),
+            to: slot(to),
+        },
+        _ => instruction.clone(),
+    }
+}
+
+fn expand(candidate: &Candidate, n_args: u16) -> Vec<Instruction> {
+    // Pop the arguments and clear the locals
+    let mut expanded: Vec<Instruction> = (0..n_args)
+        .rev()
+        .map(|idx| Instruction::Pop(Location::Inline(idx)))
+        .collect();
+    for local in 0..candidate.n_vars {
+        expanded.push(Instruction::Move {
+            from: Location::Segment(MemorySegment::Constant, 0),
+            to: Location::Inline(n_args + local),
+        });
+    }
+    // THIS and THAT are saved in the slots after the locals and restored
+    // once the return value is on the stack
+    let mut saved = Vec::new();
+    let mut slot = n_args + candidate.n_vars;
+    for pointer in [0, 1] {
+        if candidate.sets_pointer[pointer as usize] {
+            expanded.push(Instruction::Move {
+                from: Location::Segment(MemorySegment::Pointer, pointer),
+                to: Location::Inline(slot),
+            });
+            saved.push((pointer, slot));
+            slot += 1;
+        }
+    }
+    expanded.extend(candidate.body.iter().map(|i| remap(i, n_args)));
+    for (pointer, slot) in saved {
+        expanded.push(Instruction::Move {
+            from: Location::Inline(slot),
+            to: Location::Segment(MemorySegment::Pointer, pointer),
+        });
+    }
+    expanded
+}
+
+pub fn inline_calls(
+    files: Vec<Vec<(Instruction, usize)>>,
+    threshold: usize,
+) -> Vec<Vec<(Instruction, usize)>> {
+    // Replaces every call to a small leaf function with the function's body.
+    // Inlined commands are attributed to the source line of the call.
+    let candidates = candidates(&files, threshold);
+    files
+        .into_iter()
+        .enumerate()
+        .map(|(file, instructions)| {
+            instructions
+                .into_iter()
+                .flat_map(|(instruction, line)| {
+                    let inlined = match instruction.command() {
+                        Some(ParsedVMInstruction::Call { function, n_args }) => candidates
+                            .get(function)
+                            .filter(|candidate| {
+                                // Statics belong to the callee's file
+                                candidate.n_args_used <= *n_args
+                                    && (!candidate.uses_statics || candidate.file == file)
+                            })
+                            .map(|candidate| expand(candidate, *n_args)),
+                        _ => None,
+                    };
+                    inlined
+                        .unwrap_or_else(|| vec![instruction])
+                        .into_iter()
+                        .map(move |instruction| (instruction, line))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::inline_calls;
+    use crate::ir::{Instruction, Location};
+    use crate::vm_translator::parser::{parse_instruction, ParsedVMInstruction};
+    use crate::vm_translator::MemorySegment;
+
+    fn parse(lines: &[&str]) -> Vec<(Instruction, usize)> {
+        lines
+            .iter()
+            .enumerate()
+            .map(|(idx, line)| (parse_instruction(line).unwrap().into(), idx))
+            .collect()
+    }
+
+    #[test]
+    fn test_inline_getter() {
+        let square = parse(&[
+            "function Square.getSize 0",
+            "push argument 0",
+            "pop pointer 0",
+            "push this 2",
+            "return",
+        ]);
+        let game = parse(&[
+            "function Game.run 0",
+            "push local 0",
+            "call Square.getSize 1",
+        ]);
+        let inlined = inline_calls(vec![square, game], 8);
+        let expected = vec![
+            Instruction::Command(ParsedVMInstruction::Function {
+                name: String::from("Game.run"),
+                n_vars: 0,
+            }),
+            Instruction::Push(Location::Segment(MemorySegment::Local, 0)),
+            Instruction::Pop(Location::Inline(0)),
+            Instruction::Move {
+                from: Location::Segment(MemorySegment::Pointer, 0),
+                to: Location::Inline(1),
+            },
+            Instruction::Push(Location::Inline(0)),
+            Instruction::Pop(Location::Segment(MemorySegment::Pointer, 0)),
+            Instruction::Push(Location::Segment(MemorySegment::This, 2)),
+            Instruction::Move {
+                from: Location::Inline(1),
+                to: Location::Segment(MemorySegment::Pointer, 0),
+            },
+        ];
+        let (instructions, lines): (Vec<_>, Vec<usize>) = inlined[1].iter().cloned().unzip();
+        assert_eq!(instructions, expected);
+        // Inlined commands map to the call
+        assert_eq!(lines, [0, 1, 2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_not_inlined() {
+        let lib = parse(&[
+            // Not a leaf
+            "function Lib.caller 0",
+            "call Lib.leaf 0",
+            "return",
+            "function Lib.leaf 1",
+            "push local 0",
+            "return",
+            // Not straight-line code
+            "function Lib.loop 0",
+            "label LOOP",
+            "goto LOOP",
+            "push constant 0",
+            "return",
+            // Leaves two values on the stack
+            "function Lib.unbalanced 0",
+            "push constant 1",
+            "push constant 2",
+            "return",
+            // Uses statics of its own file
+            "function Lib.getStatic 0",
+            "push static 0",
+            "return",
+            // Longer than the threshold
+            "function Lib.long 0",
+            "push constant 1",
+            "push constant 1",
+            "add",
+            "push constant 1",
+            "add",
+            "return",
+        ]);
+        let main = parse(&[
+            "call Lib.caller 0",
+            "call Lib.loop 0",
+            "call Lib.unbalanced 0",
+            "call Lib.getStatic 0",
+            "call Lib.long 0",
+            // Reads an argument that was not passed
+            "call Lib.arg 0",
+            "function Lib.arg 0",
+            "push argument 0",
+            "return",
+        ]);
+        let inlined = inline_calls(vec![lib.clone(), main.clone()], 4);
+        assert_eq!(inlined[1], main);
+        // Lib.leaf is inlined into Lib.caller, and statics are only inlined
+        // within the same file
+        let lib_inlined = inline_calls(vec![lib], 4);
+        assert_eq!(
+            lib_inlined[0][1].0,
+            Instruction::Move {
+                from: Location::Segment(MemorySegment::Constant, 0),
+                to: Location::Inline(0),
+            }
+        );
+    }
+}
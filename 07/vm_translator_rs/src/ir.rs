@@ -0,0 +1,104 @@
+// The instructions the translator works on once a program is parsed: the VM
+// commands, plus the forms that only the inliner and the optimizer produce.
+// Those have no VM syntax, so they are kept out of ParsedVMInstruction.
+use crate::asm::Jump;
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::MemorySegment;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    Segment(MemorySegment, u16),
+    // Scratch slots for the arguments and locals of inlined functions
+    Inline(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    // Any VM command other than push and pop
+    Command(ParsedVMInstruction),
+    Push(Location),
+    Pop(Location),
+    // A push immediately popped into another location
+    Move {
+        from: Location,
+        to: Location,
+    },
+    // A comparison immediately used by if-goto
+    CompareGoto {
+        comparison: Comparison,
+        label: String,
+    },
+    // A call immediately followed by return, which reuses the caller's frame
+    TailCall {
+        function: String,
+        n_args: u16,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    // Condition on x - y, where x and y are the top two stack values
+    Eq,
+    Ne,
+    Gt,
+    Le,
+    Lt,
+    Ge,
+}
+
+impl Comparison {
+    pub fn negate(self) -> Self {
+        match self {
+            Comparison::Eq => Comparison::Ne,
+            Comparison::Ne => Comparison::Eq,
+            Comparison::Gt => Comparison::Le,
+            Comparison::Le => Comparison::Gt,
+            Comparison::Lt => Comparison::Ge,
+            Comparison::Ge => Comparison::Lt,
+        }
+    }
+
+    pub fn jump(&self) -> Jump {
+        match self {
+            Comparison::Eq => Jump::JEQ,
+            Comparison::Ne => Jump::JNE,
+            Comparison::Gt => Jump::JGT,
+            Comparison::Le => Jump::JLE,
+            Comparison::Lt => Jump::JLT,
+            Comparison::Ge => Jump::JGE,
+        }
+    }
+}
+
+impl From<ParsedVMInstruction> for Instruction {
+    fn from(instruction: ParsedVMInstruction) -> Self {
+        match instruction {
+            ParsedVMInstruction::Push { segment, idx } => {
+                Instruction::Push(Location::Segment(segment, idx))
+            }
+            ParsedVMInstruction::Pop { segment, idx } => {
+                Instruction::Pop(Location::Segment(segment, idx))
+            }
+            _ => Instruction::Command(instruction),
+        }
+    }
+}
+
+impl Instruction {
+    pub fn command(&self) -> Option<&ParsedVMInstruction> {
+        match self {
+            Instruction::Command(command) => Some(command),
+            _ => None,
+        }
+    }
+
+    pub fn locations(&self) -> Vec<&Location> {
+        // The locations the instruction reads or writes, in the order its
+        // assembly accesses them
+        match self {
+            Instruction::Push(location) | Instruction::Pop(location) => vec![location],
+            Instruction::Move { from, to } => vec![from, to],
+            _ => Vec::new(),
+        }
+    }
+}
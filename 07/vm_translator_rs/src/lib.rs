@@ -0,0 +1,21 @@
+pub mod asm;
+pub mod bytecode;
+pub mod c_backend;
+pub mod call_graph;
+pub mod formatter;
+mod inliner;
+mod ir;
+pub mod lint;
+mod optimizer;
+pub mod profile;
+pub mod stack_analysis;
+pub mod statics;
+// Shared with the emulator's tests through the test-util feature
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod vm_translator;
+
+// The API for driving translation in memory
+pub use asm::AsmInstruction;
+pub use vm_translator::parser::ParsedVMInstruction;
+pub use vm_translator::{parse_str, translate_program, MemorySegment, TranslationOptions, VmError};
@@ -0,0 +1,258 @@
+// Lint checks for VM code: mistakes that still parse, and often still
+// translate, but are unlikely to be intended. Each file is checked on its
+// own, since labels and statics are scoped to a file.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{parse_file, MemorySegment, VmError};
+
+struct Unit<'a> {
+    // A function with the line it is declared on, or the code outside any
+    // function in a file
+    name: &'a str,
+    declaration: Option<(usize, u16)>,
+    instructions: &'a [(usize, ParsedVMInstruction)],
+}
+
+fn units<'a>(
+    file_name: &'a str,
+    instructions: &'a [(usize, ParsedVMInstruction)],
+) -> Vec<Unit<'a>> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    let mut unit = (file_name, None);
+    for (idx, (line, instruction)) in instructions.iter().enumerate() {
+        if let ParsedVMInstruction::Function { name, n_vars } = instruction {
+            if idx > start || unit.1.is_some() {
+                units.push(Unit {
+                    name: unit.0,
+                    declaration: unit.1,
+                    instructions: &instructions[start..idx],
+                });
+            }
+            unit = (name.as_str(), Some((*line, *n_vars)));
+            start = idx + 1;
+        }
+    }
+    if start < instructions.len() || unit.1.is_some() {
+        units.push(Unit {
+            name: unit.0,
+            declaration: unit.1,
+            instructions: &instructions[start..],
+        });
+    }
+    units
+}
+
+fn lint_unit(
+    unit: &Unit,
+    label_owners: &HashMap<&str, &str>,
+    warn: &mut impl FnMut(usize, String),
+) {
+    let mut labels: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut targets = HashSet::new();
+    let mut locals_used = 0;
+    // The command that ends the current run of reachable code
+    let mut jumped_by: Option<&str> = None;
+    for (line, instruction) in unit.instructions {
+        match instruction {
+            ParsedVMInstruction::Label { label } => {
+                labels.entry(label).or_insert(*line);
+                jumped_by = None;
+            }
+            // Only the first unreachable command of a run is reported
+            _ if jumped_by.is_some() => {
+                warn(
+                    *line,
+                    format!("Unreachable code after {}", jumped_by.take().unwrap()),
+                );
+            }
+            _ => (),
+        }
+        match instruction {
+            ParsedVMInstruction::Goto { label } | ParsedVMInstruction::IfGoto { label } => {
+                targets.insert(label.as_str());
+            }
+            ParsedVMInstruction::Push {
+                segment: MemorySegment::Local,
+                idx,
+            }
+            | ParsedVMInstruction::Pop {
+                segment: MemorySegment::Local,
+                idx,
+            } => locals_used = locals_used.max(idx + 1),
+            _ => (),
+        }
+        jumped_by = match instruction {
+            ParsedVMInstruction::Goto { .. } => Some("goto"),
+            ParsedVMInstruction::Return => Some("return"),
+            _ => jumped_by,
+        };
+    }
+
+    for (line, instruction) in unit.instructions {
+        if let ParsedVMInstruction::Goto { label } | ParsedVMInstruction::IfGoto { label } =
+            instruction
+        {
+            // Labels are scoped to their function, so the jump would fail to
+            // translate
+            if !labels.contains_key(label.as_str()) {
+                let reason = match label_owners.get(label.as_str()) {
+                    Some(owner) => {
+                        format!("Label {label} is declared in {owner}, not in {}", unit.name)
+                    }
+                    None => format!("Label {label} is not declared"),
+                };
+                warn(*line, reason);
+            }
+        }
+    }
+    for (label, line) in labels {
+        if !targets.contains(label) {
+            warn(line, format!("Label {label} is never jumped to"));
+        }
+    }
+    if let Some((line, n_vars)) = unit.declaration {
+        if n_vars > locals_used {
+            warn(
+                line,
+                format!(
+                    "{} declares {n_vars} local(s) but only uses {locals_used}",
+                    unit.name
+                ),
+            );
+        }
+    }
+}
+
+pub fn lint(file_name: &str, instructions: &[(usize, ParsedVMInstruction)]) -> Vec<VmError> {
+    let mut warnings = Vec::new();
+    let mut warn = |line: usize, reason: String| {
+        warnings.push(VmError {
+            file: file_name.to_owned(),
+            line,
+            reason,
+        })
+    };
+    let units = units(file_name, instructions);
+    let mut label_owners = HashMap::new();
+    for unit in &units {
+        for (_, instruction) in unit.instructions {
+            if let ParsedVMInstruction::Label { label } = instruction {
+                label_owners.entry(label.as_str()).or_insert(unit.name);
+            }
+        }
+    }
+    for unit in &units {
+        lint_unit(unit, &label_owners, &mut warn);
+    }
+
+    // A static that is used once is either never read or never written
+    let mut static_uses: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+    for (line, instruction) in instructions {
+        if let ParsedVMInstruction::Push {
+            segment: MemorySegment::Static,
+            idx,
+        }
+        | ParsedVMInstruction::Pop {
+            segment: MemorySegment::Static,
+            idx,
+        } = instruction
+        {
+            static_uses.entry(*idx).or_default().push(*line);
+        }
+    }
+    for (idx, lines) in static_uses {
+        if let [line] = lines[..] {
+            warn(line, format!("static {idx} is only used once"));
+        }
+    }
+    warnings.sort_by_key(|warning| warning.line);
+    warnings
+}
+
+pub fn lint_files(infiles: &[PathBuf]) -> Result<Vec<VmError>, Vec<VmError>> {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    for infile in infiles {
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        match parse_file(infile) {
+            Ok(instructions) => warnings.extend(lint(file_name, &instructions)),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if errors.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, lint_files};
+    use crate::vm_translator::parser::parse_instruction;
+    use crate::vm_translator::vm_files;
+
+    use std::env;
+
+    #[test]
+    fn test_lint() {
+        let program = [
+            "function Main.main 3",
+            "push constant 1",
+            "pop local 1",
+            "push static 0",
+            "pop static 1",
+            "goto END",
+            "push constant 2",
+            "pop static 0",
+            "label UNUSED",
+            "label END",
+            "push local 1",
+            "if-goto LOOP",
+            "return",
+            "push constant 0",
+            "function Main.loop 0",
+            "label LOOP",
+            "goto LOOP",
+            "goto MISSING",
+        ];
+        let instructions: Vec<_> = program
+            .iter()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, parse_instruction(line).unwrap()))
+            .collect();
+        let warnings: Vec<String> = lint("Main.vm", &instructions)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "Main.vm:1: Main.main declares 3 local(s) but only uses 2",
+                "Main.vm:5: static 1 is only used once",
+                "Main.vm:7: Unreachable code after goto",
+                "Main.vm:9: Label UNUSED is never jumped to",
+                "Main.vm:12: Label LOOP is declared in Main.loop, not in Main.main",
+                "Main.vm:14: Unreachable code after return",
+                "Main.vm:18: Unreachable code after goto",
+                "Main.vm:18: Label MISSING is not declared",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_test_programs() {
+        // Programs written by the course's compiler are clean
+        let root = env::current_dir().unwrap().join("../..");
+        for test_dir in [
+            "08/FunctionCalls/FibonacciElement",
+            "08/FunctionCalls/StaticsTest",
+        ] {
+            let warnings = lint_files(&vm_files(&root.join(test_dir))).unwrap();
+            assert!(warnings.is_empty(), "{warnings:?}");
+        }
+    }
+}
@@ -1,24 +1,290 @@
-mod vm_translator;
-
 use std::env;
-use std::path::Path;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use vm_translator_rs::profile::{self, Counters};
+use vm_translator_rs::vm_translator::{self, SizeReport, TranslationOptions, VmError};
+use vm_translator_rs::{bytecode, c_backend, formatter, lint, stack_analysis};
+
+const USAGE: &str = "Usage: vm_translator_rs [--no-bootstrap] [--optimize] [--no-inline] [--inline-threshold N] [--optimize-size] [--annotate] [--checked] [--remove-unused] [--instrument] [--pack-statics] [--call-graph] [--stack-report] [--static-report] [--write-vmb] [--hack | --c] <infile or directory>
+       vm_translator_rs --counter-report <counters file> <RAM dump>
+       vm_translator_rs (--fmt | --lint) <infile or directory>";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: vm_translator_rs <infile>");
+    let mut options = TranslationOptions::default();
+    let mut hack = false;
+    let mut c = false;
+    let mut write_call_graph = false;
+    let mut stack_report = false;
+    let mut static_report = false;
+    let mut write_vmb = false;
+    let mut fmt = false;
+    let mut lint = false;
+    let mut infile_or_directory = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-bootstrap" => options.bootstrap = false,
+            "--optimize" => options.optimize = true,
+            "--no-inline" => options.inline_threshold = 0,
+            "--inline-threshold" => {
+                options.inline_threshold = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage_error())
+            }
+            "--optimize-size" => options.optimize_size = true,
+            "--annotate" => options.annotate = true,
+            "--checked" => options.checked = true,
+            "--remove-unused" => options.remove_unused = true,
+            "--instrument" => options.instrument = true,
+            "--pack-statics" => options.pack_statics = true,
+            "--counter-report" => {
+                let counters_file = args.next().unwrap_or_else(|| usage_error());
+                let ram_dump = args.next().unwrap_or_else(|| usage_error());
+                counter_report(Path::new(&counters_file), Path::new(&ram_dump));
+                return;
+            }
+            "--call-graph" => write_call_graph = true,
+            "--stack-report" => stack_report = true,
+            "--static-report" => static_report = true,
+            "--write-vmb" => write_vmb = true,
+            "--fmt" => fmt = true,
+            "--lint" => lint = true,
+            "--hack" => hack = true,
+            "--c" => c = true,
+            _ if infile_or_directory.is_none() && !arg.starts_with("--") => {
+                infile_or_directory = Some(arg)
+            }
+            _ => usage_error(),
+        }
+    }
+    let infile_or_directory = Path::new(
+        infile_or_directory
+            .as_deref()
+            .unwrap_or_else(|| usage_error()),
+    );
+    // With --hack the assembly is assembled in memory and only the binary is
+    // written
+    let extension = if hack { "hack" } else { "asm" };
+    let outfile = if infile_or_directory.is_dir() {
+        // Dir/ is translated into Dir/Dir.asm
+        infile_or_directory
+            .join(infile_or_directory.file_name().unwrap())
+            .with_extension(extension)
+    } else {
+        infile_or_directory.with_extension(extension)
+    };
+    let infiles = vm_translator::vm_files(infile_or_directory);
+    if fmt {
+        format_files(&infiles);
+        return;
+    }
+    if lint {
+        lint_files(&infiles);
+        return;
+    }
+    if c {
+        translate_to_c(&infiles, &outfile.with_extension("c"), options.bootstrap);
+        return;
     }
-    let infile = Path::new(&args[1]);
-    let outfile = infile.with_extension("asm");
     println!(
-        "Translating {} and writing hack assembly output to {}...",
-        infile.to_str().unwrap(),
+        "Translating {} and writing hack {} output to {}...",
+        infile_or_directory.to_str().unwrap(),
+        if hack { "binary" } else { "assembly" },
         outfile.to_str().unwrap()
     );
-    let asm_output = vm_translator::translate(infile);
-    vm_translator::write_lines(&outfile, &asm_output);
+    let output = vm_translator::translate_files(&infiles, &options)
+        .unwrap_or_else(|errors| exit_with_errors("Translation", &errors));
+    if options.remove_unused {
+        if output.call_graph.reachable().is_none() {
+            println!("No Sys.init found; keeping all functions");
+        }
+        println!(
+            "Removed {} unused function(s)",
+            output.removed_functions.len()
+        );
+        for name in &output.removed_functions {
+            println!("  {name}");
+        }
+    }
+    if options.instrument {
+        // Read back by --counter-report after the program has run
+        let counters_file = outfile.with_extension("counters");
+        vm_translator::write_lines(&counters_file, &output.counters.lines());
+        println!(
+            "{} counters written to {}",
+            output.counters.len(),
+            counters_file.to_str().unwrap()
+        );
+    }
+    if write_call_graph {
+        let dot_file = outfile.with_extension("dot");
+        vm_translator::write_lines(&dot_file, &output.call_graph.dot_lines());
+        println!("Call graph written to {}", dot_file.to_str().unwrap());
+    }
+    if write_vmb {
+        // Each .vm file is encoded next to itself, e.g. for caching the OS
+        for infile in infiles
+            .iter()
+            .filter(|infile| !bytecode::is_bytecode(infile))
+        {
+            let vmb_file = infile.with_extension("vmb");
+            let instructions = vm_translator::parse_file(infile)
+                .unwrap_or_else(|errors| exit_with_errors("Encoding", &errors));
+            if let Err(reason) = bytecode::write_file(&vmb_file, &instructions) {
+                eprintln!("{}: {reason}", vmb_file.to_str().unwrap());
+                process::exit(1);
+            }
+            println!("Bytecode written to {}", vmb_file.to_str().unwrap());
+        }
+    }
+    if stack_report {
+        // Analyzes the VM code as written, before any optimization
+        let report = stack_analysis::analyze_files(&infiles)
+            .unwrap_or_else(|errors| exit_with_errors("Stack analysis", &errors));
+        for line in report.report_lines() {
+            println!("{line}");
+        }
+    }
+    if static_report {
+        // The addresses the assembler gives the statics and any other
+        // variables, or those the translator gave them with --pack-statics
+        for line in output.statics.report_lines() {
+            println!("{line}");
+        }
+    }
+    if options.optimize_size {
+        print_size_report(&output.size_report);
+    }
+    if hack {
+        let assembly = output.assemble();
+        vm_translator::write_lines(&outfile, &assembly.hack);
+        // The debug map merges the source map with the assembler's symbols
+        let map_file = outfile.with_extension("map");
+        vm_translator::write_lines(&map_file, &output.debug_map_lines(&assembly));
+        println!("Debug map written to {}", map_file.to_str().unwrap());
+    } else {
+        vm_translator::write_lines(&outfile, &output.asm());
+    }
+    if options.annotate && !hack {
+        // The source map is written next to the assembly output
+        let map_file = outfile.with_extension("map");
+        vm_translator::write_lines(&map_file, &output.source_map_lines());
+        println!("Source map written to {}", map_file.to_str().unwrap());
+    }
     println!(
         "Translation successful; output written to {}",
         outfile.to_str().unwrap()
     );
 }
+
+fn translate_to_c(infiles: &[PathBuf], outfile: &PathBuf, bootstrap: bool) {
+    // The C backend is a separate code generator, so none of the assembly
+    // options apply
+    println!(
+        "Translating to C and writing output to {}...",
+        outfile.to_str().unwrap()
+    );
+    let c_source = c_backend::translate_to_c(infiles, bootstrap)
+        .unwrap_or_else(|errors| exit_with_errors("Translation", &errors));
+    vm_translator::write_lines(outfile, &c_source);
+    println!(
+        "Translation successful; compile it with e.g. cc -O2 -o program {}",
+        outfile.to_str().unwrap()
+    );
+}
+
+fn format_files(infiles: &[PathBuf]) {
+    // Rewrites each .vm file in place; a file with errors is left as it is
+    let mut errors = Vec::new();
+    for infile in infiles
+        .iter()
+        .filter(|infile| !bytecode::is_bytecode(infile))
+    {
+        match formatter::format_file(infile) {
+            Ok(formatted) if formatted == read_to_string(infile).unwrap() => (),
+            Ok(formatted) => {
+                write(infile, formatted).unwrap();
+                println!("Formatted {}", infile.to_str().unwrap());
+            }
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        exit_with_errors("Formatting", &errors);
+    }
+}
+
+fn lint_files(infiles: &[PathBuf]) {
+    // Exits with an error status if there are warnings, for use in scripts
+    let warnings =
+        lint::lint_files(infiles).unwrap_or_else(|errors| exit_with_errors("Linting", &errors));
+    for warning in &warnings {
+        println!("Warning: {warning}");
+    }
+    println!("{} warning(s)", warnings.len());
+    if !warnings.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn counter_report(counters_file: &Path, ram_dump: &Path) {
+    let read = |path: &Path| -> Vec<String> {
+        match read_to_string(path) {
+            Ok(text) => text.lines().map(String::from).collect(),
+            Err(error) => {
+                eprintln!("{}: {error}", path.to_str().unwrap());
+                process::exit(1);
+            }
+        }
+    };
+    let counters = Counters::from_lines(&read(counters_file));
+    let ram = profile::parse_ram_dump(&read(ram_dump));
+    match (counters, ram) {
+        (Ok(counters), Ok(ram)) => {
+            for line in counters.report_lines(&ram) {
+                println!("{line}");
+            }
+        }
+        (Err(reason), _) => {
+            eprintln!("{}: {reason}", counters_file.to_str().unwrap());
+            process::exit(1);
+        }
+        (_, Err(reason)) => {
+            eprintln!("{}: {reason}", ram_dump.to_str().unwrap());
+            process::exit(1);
+        }
+    }
+}
+
+fn exit_with_errors(action: &str, errors: &[VmError]) -> ! {
+    for error in errors {
+        eprintln!("{error}");
+    }
+    eprintln!("{action} failed with {} error(s)", errors.len());
+    process::exit(1);
+}
+
+fn usage_error() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2);
+}
+
+fn print_size_report(size_report: &SizeReport) {
+    for (operation, size) in size_report.operations() {
+        println!(
+            "{operation}: {} uses, {} words inline, {} words shared, {} words saved",
+            size.count,
+            size.inline_words,
+            size.shared_words,
+            size.inline_words as isize - size.shared_words as isize
+        );
+    }
+    println!(
+        "Total words saved: {} (including {} words to skip the shared routines)",
+        size_report.words_saved(),
+        size_report.overhead_words
+    );
+}
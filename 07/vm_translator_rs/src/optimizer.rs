@@ -0,0 +1,281 @@
+// Peephole optimizations over a file's parsed VM instructions, applied before
+// translation. Every rewrite only looks at consecutive instructions, and
+// labels are instructions themselves, so no rewrite spans a jump target.
+use crate::ir::{Comparison, Instruction, Location};
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::MemorySegment;
+
+pub fn optimize_with_origins(instructions: Vec<Instruction>) -> Vec<(Instruction, Vec<usize>)> {
+    // Pairs each optimized instruction with the indices of the input
+    // instructions it was derived from.
+    let mut origins: Vec<Vec<usize>> = (0..instructions.len()).map(|i| vec![i]).collect();
+    let mut instructions = instructions;
+    // Rewrites can expose new opportunities (e.g. a folded constant that is
+    // then popped), so passes are repeated until nothing changes.
+    loop {
+        let (optimized, optimized_origins) = optimize_pass(&instructions, &origins);
+        if optimized == instructions {
+            return optimized.into_iter().zip(optimized_origins).collect();
+        }
+        instructions = optimized;
+        origins = optimized_origins;
+    }
+}
+
+fn optimize_pass(
+    instructions: &[Instruction],
+    origins: &[Vec<usize>],
+) -> (Vec<Instruction>, Vec<Vec<usize>>) {
+    let mut optimized = Vec::with_capacity(instructions.len());
+    let mut optimized_origins = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        let window = &instructions[i..];
+        let (rewritten, consumed) = fold_constants(window)
+            .or_else(|| eliminate_redundant_ops(window))
+            .or_else(|| fuse_comparison_goto(window))
+            .or_else(|| direct_move(window))
+            .or_else(|| tail_call(window))
+            .unwrap_or_else(|| (vec![instructions[i].clone()], 1));
+        let origin = origins[i..i + consumed].concat();
+        optimized_origins.extend(rewritten.iter().map(|_| origin.clone()));
+        optimized.extend(rewritten);
+        i += consumed;
+    }
+    (optimized, optimized_origins)
+}
+
+fn push_constant(value: i16) -> Option<Vec<Instruction>> {
+    // Only non-negative values can be pushed with a single instruction.
+    u16::try_from(value).ok().map(|idx| {
+        vec![Instruction::Push(Location::Segment(
+            MemorySegment::Constant,
+            idx,
+        ))]
+    })
+}
+
+type Rewrite = Option<(Vec<Instruction>, usize)>;
+
+fn fold_constants(window: &[Instruction]) -> Rewrite {
+    // push constant a, push constant b, <binary op> => push constant (a op b)
+    let [Instruction::Push(Location::Segment(MemorySegment::Constant, a)), Instruction::Push(Location::Segment(MemorySegment::Constant, b)), Instruction::Command(op), ..] =
+        window
+    else {
+        return None;
+    };
+    let (a, b) = (*a as i16, *b as i16);
+    let result = match op {
+        ParsedVMInstruction::Add => a.wrapping_add(b),
+        ParsedVMInstruction::Sub => a.wrapping_sub(b),
+        ParsedVMInstruction::And => a & b,
+        ParsedVMInstruction::Or => a | b,
+        ParsedVMInstruction::Mul => a.wrapping_mul(b),
+        // Division by zero is left to the runtime
+        ParsedVMInstruction::Div if b != 0 => a.wrapping_div(b),
+        ParsedVMInstruction::Mod if b != 0 => a.wrapping_rem(b),
+        ParsedVMInstruction::Shl => (a as u16).checked_shl(b as u16 as u32).unwrap_or(0) as i16,
+        ParsedVMInstruction::Shr => (a as u16).checked_shr(b as u16 as u32).unwrap_or(0) as i16,
+        // A false comparison is 0; true (-1) cannot be pushed directly
+        ParsedVMInstruction::Eq if a != b => 0,
+        ParsedVMInstruction::Gt if a <= b => 0,
+        ParsedVMInstruction::Lt if a >= b => 0,
+        _ => return None,
+    };
+    push_constant(result).map(|rewritten| (rewritten, 3))
+}
+
+fn eliminate_redundant_ops(window: &[Instruction]) -> Rewrite {
+    match window {
+        // push x, pop x => nothing
+        [Instruction::Push(from), Instruction::Pop(to), ..] if from == to => Some((vec![], 2)),
+        // -0 = 0
+        [push @ Instruction::Push(Location::Segment(MemorySegment::Constant, 0)), Instruction::Command(ParsedVMInstruction::Neg), ..] => {
+            Some((vec![push.clone()], 2))
+        }
+        // Double negation
+        [Instruction::Command(ParsedVMInstruction::Neg), Instruction::Command(ParsedVMInstruction::Neg), ..]
+        | [Instruction::Command(ParsedVMInstruction::Not), Instruction::Command(ParsedVMInstruction::Not), ..] => {
+            Some((vec![], 2))
+        }
+        _ => None,
+    }
+}
+
+fn fuse_comparison_goto(window: &[Instruction]) -> Rewrite {
+    // <comparison> [not] if-goto L => a single conditional jump on x - y.
+    // This is only valid for comparisons, whose result is always 0 or -1.
+    let commands: Vec<&ParsedVMInstruction> = window
+        .iter()
+        .take(3)
+        .map_while(Instruction::command)
+        .collect();
+    let comparison = match commands.first()? {
+        ParsedVMInstruction::Eq => Comparison::Eq,
+        ParsedVMInstruction::Gt => Comparison::Gt,
+        ParsedVMInstruction::Lt => Comparison::Lt,
+        _ => return None,
+    };
+    let (comparison, consumed) = match &commands[1..] {
+        [ParsedVMInstruction::Not, ParsedVMInstruction::IfGoto { .. }, ..] => {
+            (comparison.negate(), 3)
+        }
+        [ParsedVMInstruction::IfGoto { .. }, ..] => (comparison, 2),
+        _ => return None,
+    };
+    let ParsedVMInstruction::IfGoto { label } = commands[consumed - 1] else {
+        unreachable!()
+    };
+    Some((
+        vec![Instruction::CompareGoto {
+            comparison,
+            label: label.clone(),
+        }],
+        consumed,
+    ))
+}
+
+fn direct_move(window: &[Instruction]) -> Rewrite {
+    // push S i, pop T j => T[j] = S[i] without going through the stack
+    let [Instruction::Push(from), Instruction::Pop(to), ..] = window else {
+        return None;
+    };
+    Some((
+        vec![Instruction::Move {
+            from: from.clone(),
+            to: to.clone(),
+        }],
+        2,
+    ))
+}
+
+fn tail_call(window: &[Instruction]) -> Rewrite {
+    // call f n, return => a call that reuses the current frame
+    let [Instruction::Command(ParsedVMInstruction::Call { function, n_args }), Instruction::Command(ParsedVMInstruction::Return), ..] =
+        window
+    else {
+        return None;
+    };
+    Some((
+        vec![Instruction::TailCall {
+            function: function.clone(),
+            n_args: *n_args,
+        }],
+        2,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize_with_origins;
+    use crate::ir::{Comparison, Instruction, Location};
+    use crate::vm_translator::parser::parse_instruction;
+    use crate::vm_translator::MemorySegment;
+
+    fn optimize(instructions: Vec<Instruction>) -> Vec<Instruction> {
+        optimize_with_origins(instructions)
+            .into_iter()
+            .map(|(instruction, _)| instruction)
+            .collect()
+    }
+
+    fn parse(lines: &[&str]) -> Vec<Instruction> {
+        lines
+            .iter()
+            .map(|line| parse_instruction(line).unwrap().into())
+            .collect()
+    }
+
+    #[test]
+    fn test_optimize() {
+        let test_cases = vec![
+            (
+                vec!["push constant 2", "push constant 3", "add"],
+                parse(&["push constant 5"]),
+            ),
+            (
+                vec!["push constant 7", "push constant 8", "gt"],
+                parse(&["push constant 0"]),
+            ),
+            (
+                // 3 - 8 is negative and cannot be folded into a push
+                vec!["push constant 3", "push constant 8", "sub"],
+                parse(&["push constant 3", "push constant 8", "sub"]),
+            ),
+            (
+                vec!["push constant 300", "push constant 7", "mul"],
+                parse(&["push constant 2100"]),
+            ),
+            (
+                vec!["push constant 1", "push constant 14", "shl"],
+                parse(&["push constant 16384"]),
+            ),
+            (
+                vec!["push constant 9", "push constant 0", "mod"],
+                parse(&["push constant 9", "push constant 0", "mod"]),
+            ),
+            (vec!["push local 1", "pop local 1"], vec![]),
+            (
+                vec!["push constant 0", "neg", "push argument 1", "add"],
+                parse(&["push constant 0", "push argument 1", "add"]),
+            ),
+            (
+                vec!["lt", "not", "if-goto WHILE_END0"],
+                vec![Instruction::CompareGoto {
+                    comparison: Comparison::Ge,
+                    label: String::from("WHILE_END0"),
+                }],
+            ),
+            (
+                vec!["push local 0", "pop that 1"],
+                vec![Instruction::Move {
+                    from: Location::Segment(MemorySegment::Local, 0),
+                    to: Location::Segment(MemorySegment::That, 1),
+                }],
+            ),
+            (
+                // Folding first exposes a constant move
+                vec!["push constant 1", "push constant 1", "add", "pop temp 0"],
+                vec![Instruction::Move {
+                    from: Location::Segment(MemorySegment::Constant, 2),
+                    to: Location::Segment(MemorySegment::Temp, 0),
+                }],
+            ),
+            (
+                vec!["call Main.loop 2", "return"],
+                vec![Instruction::TailCall {
+                    function: String::from("Main.loop"),
+                    n_args: 2,
+                }],
+            ),
+            (
+                // A label between the two instructions blocks the rewrite
+                vec!["push local 0", "label LOOP", "pop local 0"],
+                parse(&["push local 0", "label LOOP", "pop local 0"]),
+            ),
+        ];
+
+        for test in test_cases {
+            assert_eq!(optimize(parse(&test.0)), test.1, "{:?}", test.0);
+        }
+    }
+
+    #[test]
+    fn test_optimize_with_origins() {
+        let instructions = parse(&[
+            "push local 0",
+            "push constant 1",
+            "add",
+            "push static 2",
+            "pop static 2",
+            "push constant 2",
+            "push constant 3",
+            "add",
+        ]);
+        let optimized: Vec<Vec<usize>> = optimize_with_origins(instructions)
+            .into_iter()
+            .map(|(_, origin)| origin)
+            .collect();
+        assert_eq!(optimized, vec![vec![0], vec![1], vec![2], vec![5, 6, 7]]);
+    }
+}
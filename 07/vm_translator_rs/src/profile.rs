@@ -0,0 +1,392 @@
+// Execution counters for instrumented translations: one RAM word per VM
+// function, incremented on entry, and one per branch target label,
+// incremented whenever a goto or if-goto to it is taken. The counters file
+// written next to the assembly maps the counters back to their names after a
+// run, given a dump of RAM.
+use std::collections::{BTreeMap, HashMap};
+
+// The counters occupy the RAM above the keyboard, which programs do not use
+pub const COUNTER_BASE: u16 = 24577;
+pub const COUNTER_CAPACITY: usize = 32768 - COUNTER_BASE as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterKind {
+    Function,
+    Branch,
+}
+
+impl CounterKind {
+    fn name(&self) -> &str {
+        match self {
+            CounterKind::Function => "function",
+            CounterKind::Branch => "branch",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    // In order of their addresses
+    counters: Vec<(CounterKind, String)>,
+    addresses: HashMap<String, u16>,
+}
+
+impl Counters {
+    pub fn add(&mut self, kind: CounterKind, name: &str) -> Result<(), String> {
+        // Functions are named as in the VM code and branch targets like
+        // their assembly labels, Function$label. Adding a name again is a
+        // no-op.
+        if self.addresses.contains_key(name) {
+            return Ok(());
+        }
+        if self.counters.len() == COUNTER_CAPACITY {
+            return Err(format!(
+                "Too many counters: at most {COUNTER_CAPACITY} functions and branch targets can be instrumented"
+            ));
+        }
+        let address = COUNTER_BASE + self.counters.len() as u16;
+        self.addresses.insert(name.to_owned(), address);
+        self.counters.push((kind, name.to_owned()));
+        Ok(())
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u16, CounterKind, &str)> {
+        (COUNTER_BASE..)
+            .zip(&self.counters)
+            .map(|(address, (kind, name))| (address, *kind, name.as_str()))
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        // The counters file: one line per counter with its RAM address
+        let mut lines = vec![String::from("// ram_address kind name")];
+        lines.extend(
+            self.iter()
+                .map(|(address, kind, name)| format!("{address} {} {name}", kind.name())),
+        );
+        lines
+    }
+
+    pub fn from_lines(lines: &[String]) -> Result<Self, String> {
+        // Reads a counters file written by lines()
+        let mut counters = Counters::default();
+        for (idx, line) in lines.iter().enumerate() {
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let error = || format!("Line {}: invalid counter: {line}", idx + 1);
+            let [address, kind, name] = line.split(' ').collect::<Vec<_>>()[..] else {
+                return Err(error());
+            };
+            let kind = match kind {
+                "function" => CounterKind::Function,
+                "branch" => CounterKind::Branch,
+                _ => return Err(error()),
+            };
+            counters.add(kind, name)?;
+            // Addresses follow from the order, so they must be consecutive
+            if address.parse() != Ok(counters.address(name).unwrap()) {
+                return Err(error());
+            }
+        }
+        Ok(counters)
+    }
+
+    pub fn counts(&self, ram: &BTreeMap<usize, i16>) -> Vec<(CounterKind, &str, Option<u16>)> {
+        // The count of each counter, or None if the dump does not include it.
+        // Counts are unsigned and wrap around after 65535.
+        self.iter()
+            .map(|(address, kind, name)| {
+                let count = ram.get(&(address as usize)).map(|&value| value as u16);
+                (kind, name, count)
+            })
+            .collect()
+    }
+
+    pub fn report_lines(&self, ram: &BTreeMap<usize, i16>) -> Vec<String> {
+        // The counters from the most often executed down, followed by how many
+        // functions and branch targets were reached at all
+        let mut counts = self.counts(ram);
+        counts.sort_by_key(|&(_, _, count)| std::cmp::Reverse(count));
+        let mut lines: Vec<String> = counts
+            .iter()
+            .map(|(kind, name, count)| match count {
+                Some(count) => format!("{count:>8} {} {name}", kind.name()),
+                None => format!("{:>8} {} {name}", "-", kind.name()),
+            })
+            .collect();
+        for kind in [CounterKind::Function, CounterKind::Branch] {
+            let of_kind: Vec<_> = counts.iter().filter(|count| count.0 == kind).collect();
+            let reached = of_kind
+                .iter()
+                .filter(|(_, _, count)| count.is_some_and(|count| count > 0))
+                .count();
+            lines.push(format!(
+                "{} of {} {} counter(s) reached",
+                reached,
+                of_kind.len(),
+                kind.name()
+            ));
+        }
+        let missing = counts.iter().filter(|count| count.2.is_none()).count();
+        if missing > 0 {
+            lines.push(format!("{missing} counter(s) missing from the RAM dump"));
+        }
+        lines
+    }
+}
+
+fn parse_address(address: &str) -> Result<usize, String> {
+    // A RAM address, optionally written as RAM[address]
+    let number = address
+        .strip_prefix("RAM[")
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+    match number.parse::<usize>() {
+        Ok(address) if address < 32768 => Ok(address),
+        _ => Err(format!("Invalid RAM address: {address}")),
+    }
+}
+
+fn parse_value(value: &str) -> Result<i16, String> {
+    // Signed or unsigned 16-bit values
+    match value.parse::<i32>() {
+        Ok(value) if (-32768..=65535).contains(&value) => Ok(value as u16 as i16),
+        _ => Err(format!("Invalid RAM value: {value}")),
+    }
+}
+
+pub fn parse_ram_dump(lines: &[String]) -> Result<BTreeMap<usize, i16>, String> {
+    // Accepts the dump formats of the tools that run Hack programs:
+    //   - one value per line, starting at address 0, as written by the C
+    //     backend's --dump
+    //   - "address: value" lines, where the address may be written RAM[n]
+    //     and the separator may also be "=" or a space
+    //   - output tables of test scripts, as written by the CPU emulator, with
+    //     a |RAM[n]|...| header line followed by rows of values
+    let mut ram = BTreeMap::new();
+    let mut next_address = 0;
+    let mut columns: Vec<usize> = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        let with_line = |reason: String| format!("Line {}: {reason}", idx + 1);
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if let Some(row) = line.strip_prefix('|') {
+            let cells: Vec<&str> = row
+                .strip_suffix('|')
+                .unwrap_or(row)
+                .split('|')
+                .map(str::trim)
+                .collect();
+            if cells.iter().all(|cell| cell.starts_with("RAM[")) {
+                columns = cells
+                    .iter()
+                    .map(|cell| parse_address(cell))
+                    .collect::<Result<_, _>>()
+                    .map_err(with_line)?;
+            } else if cells.len() == columns.len() {
+                for (&address, cell) in columns.iter().zip(cells) {
+                    ram.insert(address, parse_value(cell).map_err(with_line)?);
+                }
+            } else {
+                return Err(with_line(String::from("Row does not match the header")));
+            }
+            continue;
+        }
+        let separator = |c: char| c == ':' || c == '=' || c.is_whitespace();
+        let (address, value) = match line.split_once(separator) {
+            Some((address, value)) => (
+                parse_address(address).map_err(with_line)?,
+                value.trim_start_matches(separator),
+            ),
+            None => (next_address, line),
+        };
+        ram.insert(address, parse_value(value).map_err(with_line)?);
+        next_address = address + 1;
+    }
+    Ok(ram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ram_dump, CounterKind, Counters, COUNTER_BASE, COUNTER_CAPACITY};
+    use crate::test_util::run_vm_files;
+    use crate::vm_translator::TranslationOptions;
+
+    use std::collections::BTreeMap;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_ram_dump() {
+        let expected = BTreeMap::from([(0, 256), (1, -1), (24577, 7), (24578, -2)]);
+        for dump in [
+            "256\n-1\n",
+            "0: 256\n1: 65535\n24577: 7\n24578: -2",
+            "RAM[0] = 256\nRAM[1] = -1\nRAM[24577] = 7\nRAM[24578] = 65534",
+            "|RAM[0]|RAM[1]|\n|   256 |    -1 |\n|RAM[24577]|RAM[24578]|\n|      7 |     -2 |",
+        ] {
+            let ram = parse_ram_dump(&lines(dump)).unwrap();
+            let expected: BTreeMap<usize, i16> = expected
+                .iter()
+                .filter(|(address, _)| ram.contains_key(address))
+                .map(|(&address, &value)| (address, value))
+                .collect();
+            assert!(ram.len() >= 2);
+            assert_eq!(ram, expected, "{dump}");
+        }
+        for (dump, error) in [
+            ("1\nx", "Line 2: Invalid RAM value: x"),
+            ("32768: 0", "Line 1: Invalid RAM address: 32768"),
+            (
+                "|RAM[0]|\n| 1 | 2 |",
+                "Line 2: Row does not match the header",
+            ),
+        ] {
+            assert_eq!(parse_ram_dump(&lines(dump)), Err(String::from(error)));
+        }
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut counters = Counters::default();
+        counters.add(CounterKind::Function, "Main.main").unwrap();
+        counters.add(CounterKind::Branch, "Main.main$LOOP").unwrap();
+        counters.add(CounterKind::Function, "Main.main").unwrap();
+        counters.add(CounterKind::Function, "Main.unused").unwrap();
+        assert_eq!(counters.address("Main.main$LOOP"), Some(COUNTER_BASE + 1));
+
+        // The counters file reads back the same counters
+        let read = Counters::from_lines(&counters.lines()).unwrap();
+        assert_eq!(read.lines(), counters.lines());
+        assert!(Counters::from_lines(&lines("24578 function Main.main")).is_err());
+
+        let ram = parse_ram_dump(&lines("24577: 1\n24578: -1")).unwrap();
+        assert_eq!(
+            counters.report_lines(&ram),
+            [
+                "   65535 branch Main.main$LOOP",
+                "       1 function Main.main",
+                "       - function Main.unused",
+                "1 of 2 function counter(s) reached",
+                "1 of 1 branch counter(s) reached",
+                "1 counter(s) missing from the RAM dump",
+            ]
+        );
+
+        let mut full = Counters::default();
+        for idx in 0..COUNTER_CAPACITY {
+            full.add(CounterKind::Function, &format!("F.f{idx}"))
+                .unwrap();
+        }
+        assert!(full.add(CounterKind::Branch, "F.f0$LOOP").is_err());
+    }
+
+    #[test]
+    fn test_instrument() {
+        let program = [
+            "function Sys.init 0",
+            "push constant 5",
+            "call Main.count 1",
+            "pop temp 0",
+            // Small enough to be inlined, if not instrumenting
+            "push constant 3",
+            "call Main.double 1",
+            "pop temp 1",
+            "push constant 4",
+            "call Main.double 1",
+            "pop temp 2",
+            "label HALT",
+            "goto HALT",
+            "function Main.count 1",
+            "label LOOP",
+            "push argument 0",
+            "push constant 0",
+            "eq",
+            "if-goto END",
+            "push argument 0",
+            "push constant 1",
+            "sub",
+            "pop argument 0",
+            "push local 0",
+            "push constant 1",
+            "add",
+            "pop local 0",
+            "goto LOOP",
+            "label END",
+            "push local 0",
+            "return",
+            "function Main.unused 0",
+            "push constant 0",
+            "return",
+            "function Main.double 0",
+            "push argument 0",
+            "push argument 0",
+            "add",
+            "return",
+        ]
+        .join("\n");
+        // With optimize, the eq and if-goto are fused into one jump
+        let options = [
+            (false, false, false),
+            (true, false, false),
+            (false, true, true),
+            (true, true, true),
+        ]
+        .map(|(optimize, optimize_size, checked)| TranslationOptions {
+            optimize,
+            optimize_size,
+            checked,
+            instrument: true,
+            ..Default::default()
+        });
+        run_vm_files(
+            "vm_translator_rs_instrument",
+            &[("Main.vm", &program)],
+            &options,
+            10_000,
+            |options, output, cpu| {
+                assert_eq!(
+                    output.counters.lines(),
+                    [
+                        "// ram_address kind name",
+                        "24577 function Sys.init",
+                        "24578 branch Sys.init$HALT",
+                        "24579 function Main.count",
+                        "24580 branch Main.count$END",
+                        "24581 branch Main.count$LOOP",
+                        "24582 function Main.unused",
+                        "24583 function Main.double",
+                    ]
+                );
+                assert_eq!(cpu.ram[5..8], [5, 6, 8]);
+                let counts = &cpu.ram[24577..24584];
+                assert_eq!(counts[0], 1);
+                assert!(counts[1] > 0);
+                assert_eq!(counts[2..], [1, 1, 5, 0, 2], "{options:?}");
+            },
+        );
+        run_vm_files(
+            "vm_translator_rs_no_instrument",
+            &[("Main.vm", &program)],
+            &[TranslationOptions::default()],
+            0,
+            |_, output, _| assert!(output.counters.is_empty()),
+        );
+    }
+}
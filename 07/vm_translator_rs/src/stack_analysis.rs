@@ -0,0 +1,446 @@
+// Static analysis of stack usage: the maximum working stack depth of each
+// function, places where paths disagree on the depth, and the worst-case
+// stack usage of the whole program along its call graph.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::call_graph::ENTRY_POINT;
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{parse_file, VmError};
+
+// Words pushed by a call besides its arguments: the return address and the
+// caller's LCL, ARG, THIS and THAT
+const FRAME_SIZE: usize = 5;
+// The stack occupies RAM[256..2047]
+pub const STACK_CAPACITY: usize = 2048 - 256;
+
+#[derive(Debug, PartialEq)]
+pub struct FunctionStack {
+    pub name: String,
+    pub n_vars: u16,
+    // Largest number of values on the function's working stack
+    pub max_depth: usize,
+    // Worst-case words used by the function's locals and working stack and
+    // by everything it calls, or None if it can recurse
+    pub total: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct StackReport {
+    pub functions: Vec<FunctionStack>,
+    pub warnings: Vec<VmError>,
+    // Functions on a cycle of the call graph
+    pub recursive: Vec<String>,
+    // Called functions that were not analyzed, such as OS functions
+    pub undefined: Vec<String>,
+}
+
+struct Unit<'a> {
+    // A function, or the code outside any function in a file
+    name: String,
+    file: &'a str,
+    n_vars: u16,
+    instructions: &'a [(usize, ParsedVMInstruction)],
+}
+
+struct UnitResult {
+    max_depth: usize,
+    // Callee and stack depth (including its arguments) of each call
+    calls: Vec<(String, usize)>,
+}
+
+fn units<'a>(files: &'a [(String, Vec<(usize, ParsedVMInstruction)>)]) -> Vec<Unit<'a>> {
+    let mut units = Vec::new();
+    for (file, instructions) in files {
+        let mut start = 0;
+        let mut unit = (file.clone(), 0);
+        for (idx, (_, instruction)) in instructions.iter().enumerate() {
+            if let ParsedVMInstruction::Function { name, n_vars } = instruction {
+                if idx > start {
+                    units.push(Unit {
+                        name: unit.0,
+                        file,
+                        n_vars: unit.1,
+                        instructions: &instructions[start..idx],
+                    });
+                }
+                unit = (name.clone(), *n_vars);
+                start = idx + 1;
+            }
+        }
+        if start < instructions.len() || unit.0 != *file {
+            units.push(Unit {
+                name: unit.0,
+                file,
+                n_vars: unit.1,
+                instructions: &instructions[start..],
+            });
+        }
+    }
+    units
+}
+
+fn stack_effect(instruction: &ParsedVMInstruction) -> (usize, usize) {
+    // Values popped and pushed
+    match instruction {
+        ParsedVMInstruction::Add
+        | ParsedVMInstruction::Sub
+        | ParsedVMInstruction::Eq
+        | ParsedVMInstruction::Gt
+        | ParsedVMInstruction::Lt
+        | ParsedVMInstruction::And
+        | ParsedVMInstruction::Or
+        | ParsedVMInstruction::Mul
+        | ParsedVMInstruction::Div
+        | ParsedVMInstruction::Mod
+        | ParsedVMInstruction::Shl
+        | ParsedVMInstruction::Shr => (2, 1),
+        ParsedVMInstruction::Neg | ParsedVMInstruction::Not => (1, 1),
+        ParsedVMInstruction::Push { .. } => (0, 1),
+        ParsedVMInstruction::Pop { .. } | ParsedVMInstruction::IfGoto { .. } => (1, 0),
+        ParsedVMInstruction::Call { n_args, .. } => (*n_args as usize, 1),
+        ParsedVMInstruction::Return => (1, 0),
+        ParsedVMInstruction::Label { .. }
+        | ParsedVMInstruction::Goto { .. }
+        | ParsedVMInstruction::Function { .. } => (0, 0),
+    }
+}
+
+fn analyze_unit(unit: &Unit, all_warnings: &mut Vec<VmError>) -> UnitResult {
+    let labels: HashMap<&str, usize> = unit
+        .instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, (_, instruction))| match instruction {
+            ParsedVMInstruction::Label { label } => Some((label.as_str(), idx)),
+            _ => None,
+        })
+        .collect();
+    let mut warnings = Vec::new();
+    let mut warn = |line: usize, reason: String| {
+        warnings.push(VmError {
+            file: unit.file.to_owned(),
+            line,
+            reason,
+        })
+    };
+    let mut depths: Vec<Option<usize>> = vec![None; unit.instructions.len()];
+    let mut result = UnitResult {
+        max_depth: 0,
+        calls: Vec::new(),
+    };
+    let mut pending = vec![(0, 0)];
+    while let Some((pc, depth)) = pending.pop() {
+        let Some((line, instruction)) = unit.instructions.get(pc) else {
+            continue;
+        };
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                if let ParsedVMInstruction::Label { label } = instruction {
+                    let (low, high) = (known.min(depth), known.max(depth));
+                    warn(
+                        *line,
+                        format!("Unbalanced stack at label {label}: paths arrive with {low} and {high} values"),
+                    );
+                }
+                continue;
+            }
+            None => depths[pc] = Some(depth),
+        }
+        let (pops, pushes) = stack_effect(instruction);
+        if depth < pops {
+            warn(
+                *line,
+                format!(
+                    "{} pops {pops} value(s) but the working stack holds {depth}",
+                    unit.name
+                ),
+            );
+            continue;
+        }
+        let next_depth = depth - pops + pushes;
+        result.max_depth = result.max_depth.max(next_depth);
+        if let ParsedVMInstruction::Call { function, .. } = instruction {
+            result.calls.push((function.clone(), depth));
+        }
+        let target = |label: &String| labels.get(label.as_str()).copied();
+        match instruction {
+            ParsedVMInstruction::Return => {
+                if next_depth + pops != 1 {
+                    warn(
+                        *line,
+                        format!(
+                            "Unbalanced stack at return: {} value(s) instead of 1",
+                            next_depth + pops
+                        ),
+                    );
+                }
+            }
+            ParsedVMInstruction::Goto { label } => {
+                pending.extend(target(label).map(|pc| (pc, next_depth)))
+            }
+            ParsedVMInstruction::IfGoto { label } => {
+                pending.extend(target(label).map(|pc| (pc, next_depth)));
+                pending.push((pc + 1, next_depth));
+            }
+            _ => pending.push((pc + 1, next_depth)),
+        }
+    }
+    // Paths are explored depth first; report in source order
+    warnings.sort_by_key(|warning| warning.line);
+    all_warnings.extend(warnings);
+    result
+}
+
+struct TotalSearch<'a> {
+    units: HashMap<&'a str, (u16, &'a UnitResult)>,
+    totals: HashMap<String, Option<usize>>,
+    // Functions whose total is being computed, in call order
+    active: Vec<&'a str>,
+    recursive: Vec<String>,
+    undefined: Vec<String>,
+}
+
+impl<'a> TotalSearch<'a> {
+    fn total(&mut self, name: &'a str) -> Option<usize> {
+        if let Some(total) = self.totals.get(name) {
+            return *total;
+        }
+        if let Some(position) = self.active.iter().position(|active| *active == name) {
+            for function in &self.active[position..] {
+                if !self.recursive.iter().any(|recursive| recursive == function) {
+                    self.recursive.push(function.to_string());
+                }
+            }
+            return None;
+        }
+        let Some(&(n_vars, result)) = self.units.get(name) else {
+            if !self.undefined.iter().any(|undefined| undefined == name) {
+                self.undefined.push(name.to_owned());
+            }
+            return Some(0);
+        };
+        self.active.push(name);
+        let mut total = Some(result.max_depth);
+        for (callee, depth) in &result.calls {
+            let callee_total = self.total(callee).map(|callee| depth + FRAME_SIZE + callee);
+            total = match (total, callee_total) {
+                (Some(total), Some(callee_total)) => Some(total.max(callee_total)),
+                _ => None,
+            };
+        }
+        self.active.pop();
+        let total = total.map(|total| total + n_vars as usize);
+        self.totals.insert(name.to_owned(), total);
+        total
+    }
+}
+
+pub fn analyze(files: &[(String, Vec<(usize, ParsedVMInstruction)>)]) -> StackReport {
+    let units = units(files);
+    let mut warnings = Vec::new();
+    let results: Vec<UnitResult> = units
+        .iter()
+        .map(|unit| analyze_unit(unit, &mut warnings))
+        .collect();
+    let mut search = TotalSearch {
+        units: units
+            .iter()
+            .zip(&results)
+            .map(|(unit, result)| (unit.name.as_str(), (unit.n_vars, result)))
+            .collect(),
+        totals: HashMap::new(),
+        active: Vec::new(),
+        recursive: Vec::new(),
+        undefined: Vec::new(),
+    };
+    let functions = units
+        .iter()
+        .zip(&results)
+        .map(|(unit, result)| FunctionStack {
+            name: unit.name.clone(),
+            n_vars: unit.n_vars,
+            max_depth: result.max_depth,
+            total: search.total(&unit.name),
+        })
+        .collect();
+    StackReport {
+        functions,
+        warnings,
+        recursive: search.recursive,
+        undefined: search.undefined,
+    }
+}
+
+pub fn analyze_files(infiles: &[PathBuf]) -> Result<StackReport, Vec<VmError>> {
+    let mut errors = Vec::new();
+    let mut files = Vec::new();
+    for infile in infiles {
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        match parse_file(infile) {
+            Ok(instructions) => files.push((file_name.to_owned(), instructions)),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if errors.is_empty() {
+        Ok(analyze(&files))
+    } else {
+        Err(errors)
+    }
+}
+
+impl StackReport {
+    pub fn program_total(&self) -> Option<usize> {
+        // Starting from Sys.init, called by the bootstrap code, or else from
+        // the code outside any function
+        match self.functions.iter().find(|f| f.name == ENTRY_POINT) {
+            Some(sys_init) => sys_init.total.map(|total| FRAME_SIZE + total),
+            None => self
+                .functions
+                .iter()
+                .map(|function| function.total)
+                .try_fold(0, |max, total| total.map(|total| max.max(total))),
+        }
+    }
+
+    pub fn report_lines(&self) -> Vec<String> {
+        let mut lines = vec![String::from("// function locals max_depth total")];
+        for function in &self.functions {
+            let total = match function.total {
+                Some(total) => total.to_string(),
+                None => String::from("unbounded"),
+            };
+            lines.push(format!(
+                "{} {} {} {total}",
+                function.name, function.n_vars, function.max_depth
+            ));
+        }
+        lines.extend(
+            self.warnings
+                .iter()
+                .map(|warning| format!("Warning: {warning}")),
+        );
+        if !self.recursive.is_empty() {
+            lines.push(format!(
+                "Recursive functions: {}",
+                self.recursive.join(", ")
+            ));
+        }
+        if !self.undefined.is_empty() {
+            lines.push(format!(
+                "Not included (undefined): {}",
+                self.undefined.join(", ")
+            ));
+        }
+        match self.program_total() {
+            Some(total) if total <= STACK_CAPACITY => lines.push(format!(
+                "Worst-case stack usage: {total} of {STACK_CAPACITY} words"
+            )),
+            Some(total) => lines.push(format!(
+                "Worst-case stack usage: {total} words exceeds the {STACK_CAPACITY} available"
+            )),
+            None => lines.push(String::from(
+                "Worst-case stack usage is unbounded because of recursion",
+            )),
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, FunctionStack};
+    use crate::vm_translator::parser::{parse_instruction, ParsedVMInstruction};
+
+    fn parse(lines: &[&str]) -> Vec<(usize, ParsedVMInstruction)> {
+        lines
+            .iter()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, parse_instruction(line).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_totals() {
+        let files = vec![(
+            String::from("Main.vm"),
+            parse(&[
+                "function Sys.init 1",
+                "push constant 1",
+                "push constant 2",
+                "call Main.add 2",
+                "pop local 0",
+                "label LOOP",
+                "goto LOOP",
+                "function Main.add 2",
+                "push argument 0",
+                "push argument 1",
+                "add",
+                "push constant 0",
+                "call Math.abs 1",
+                "add",
+                "return",
+            ]),
+        )];
+        let report = analyze(&files);
+        assert_eq!(
+            report.functions,
+            [
+                FunctionStack {
+                    name: String::from("Sys.init"),
+                    n_vars: 1,
+                    max_depth: 2,
+                    // 1 local, 2 arguments, a frame and Main.add's total
+                    total: Some(1 + 2 + 5 + 9),
+                },
+                FunctionStack {
+                    name: String::from("Main.add"),
+                    n_vars: 2,
+                    max_depth: 2,
+                    // 2 locals, 2 values including the argument to Math.abs
+                    // and its frame
+                    total: Some(2 + 2 + 5),
+                },
+            ]
+        );
+        assert!(report.warnings.is_empty());
+        assert_eq!(report.undefined, ["Math.abs"]);
+        assert_eq!(report.program_total(), Some(5 + 17));
+    }
+
+    #[test]
+    fn test_recursion_and_unbalanced_stack() {
+        let files = vec![(
+            String::from("Main.vm"),
+            parse(&[
+                "function Main.f 0",
+                "push argument 0",
+                "if-goto ELSE",
+                "push constant 1",
+                "label ELSE",
+                "push constant 0",
+                "call Main.g 1",
+                "return",
+                "function Main.g 0",
+                "push constant 1",
+                "push constant 2",
+                "call Main.f 1",
+                "return",
+            ]),
+        )];
+        let report = analyze(&files);
+        assert_eq!(report.recursive, ["Main.f", "Main.g"]);
+        assert_eq!(report.functions[0].total, None);
+        assert_eq!(report.program_total(), None);
+        let warnings: Vec<String> = report.warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            warnings,
+            [
+                "Main.vm:5: Unbalanced stack at label ELSE: paths arrive with 0 and 1 values",
+                "Main.vm:8: Unbalanced stack at return: 2 value(s) instead of 1",
+                "Main.vm:13: Unbalanced stack at return: 2 value(s) instead of 1",
+            ]
+        );
+    }
+}
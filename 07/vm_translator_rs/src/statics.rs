@@ -0,0 +1,322 @@
+// Layout of the static segment, RAM[16..255], which holds every variable the
+// assembler allocates: the statics of each file, the slots of inlined
+// functions and any other symbol that is never defined as a label. By default
+// the assembler gives each variable the next free address when it first
+// appears; with pack_statics the translator assigns the statics' addresses
+// itself, giving each file one block in index order.
+use std::collections::HashMap;
+
+pub const STATIC_START: u16 = 16;
+pub const STATIC_END: u16 = 256;
+
+fn file_static(symbol: &str) -> Option<(&str, u16)> {
+    // The file stem (or $inline for the slots of inlined functions) and index
+    // of a File.i static
+    let (base, idx) = symbol.rsplit_once('.')?;
+    Some((base, idx.parse().ok()?))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticVariable {
+    pub symbol: String,
+    pub address: u16,
+}
+
+impl StaticVariable {
+    pub fn file_static(&self) -> Option<(&str, u16)> {
+        // None for variables other than statics
+        file_static(&self.symbol)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StaticLayout {
+    // In order of address. Addresses past the segment mean it overflowed.
+    variables: Vec<StaticVariable>,
+    addresses: HashMap<String, u16>,
+    packed: bool,
+}
+
+impl StaticLayout {
+    pub fn new<'a>(symbols: impl IntoIterator<Item = &'a str>, packed: bool) -> Self {
+        // The symbols are in the order they first appear in the assembly,
+        // which is the order the assembler allocates them in
+        let mut unique: Vec<&str> = Vec::new();
+        for symbol in symbols {
+            if !unique.contains(&symbol) {
+                unique.push(symbol);
+            }
+        }
+        let mut symbols = unique;
+        if packed {
+            // The assembler still allocates the other variables, so they
+            // come first
+            let mut bases: Vec<&str> = Vec::new();
+            for (base, _) in symbols.iter().filter_map(|symbol| file_static(symbol)) {
+                if !bases.contains(&base) {
+                    bases.push(base);
+                }
+            }
+            symbols.sort_by_key(|symbol| {
+                file_static(symbol).map(|(base, idx)| (bases.iter().position(|&b| b == base), idx))
+            });
+        }
+        let mut layout = StaticLayout {
+            packed,
+            ..Default::default()
+        };
+        for (symbol, address) in symbols.into_iter().zip(STATIC_START..) {
+            layout.addresses.insert(symbol.to_owned(), address);
+            layout.variables.push(StaticVariable {
+                symbol: symbol.to_owned(),
+                address,
+            });
+        }
+        layout
+    }
+
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    pub fn address(&self, symbol: &str) -> Option<u16> {
+        self.addresses.get(symbol).copied()
+    }
+
+    pub fn variable_at(&self, address: u16) -> Option<&StaticVariable> {
+        let position = address.checked_sub(STATIC_START)?;
+        self.variables.get(position as usize)
+    }
+
+    pub fn variables(&self) -> &[StaticVariable] {
+        &self.variables
+    }
+
+    pub fn overflow(&self) -> &[StaticVariable] {
+        // The variables that do not fit in the segment
+        let fitting = (STATIC_END - STATIC_START) as usize;
+        &self.variables[fitting.min(self.variables.len())..]
+    }
+
+    pub fn report_lines(&self) -> Vec<String> {
+        // The statics of each file in index order, with their addresses,
+        // followed by any other variables
+        let mut bases: Vec<&str> = Vec::new();
+        for (base, _) in self
+            .variables
+            .iter()
+            .filter_map(StaticVariable::file_static)
+        {
+            if !bases.contains(&base) {
+                bases.push(base);
+            }
+        }
+        let variable_line =
+            |variable: &StaticVariable| format!("  {} RAM[{}]", variable.symbol, variable.address);
+        let mut lines = Vec::new();
+        for base in bases {
+            let mut variables: Vec<(u16, &StaticVariable)> = self
+                .variables
+                .iter()
+                .filter_map(|variable| match variable.file_static() {
+                    Some((variable_base, idx)) if variable_base == base => Some((idx, variable)),
+                    _ => None,
+                })
+                .collect();
+            variables.sort_by_key(|&(idx, _)| idx);
+            lines.push(format!("{base}: {} static(s)", variables.len()));
+            lines.extend(
+                variables
+                    .into_iter()
+                    .map(|(_, variable)| variable_line(variable)),
+            );
+        }
+        let others: Vec<&StaticVariable> = self
+            .variables
+            .iter()
+            .filter(|variable| variable.file_static().is_none())
+            .collect();
+        if !others.is_empty() {
+            lines.push(format!("Other: {} variable(s)", others.len()));
+            lines.extend(others.into_iter().map(variable_line));
+        }
+        lines.push(format!(
+            "{} of {} static segment words used",
+            self.variables.len(),
+            STATIC_END - STATIC_START
+        ));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StaticLayout, STATIC_END};
+    use crate::test_util::{run_test_script, TempDir};
+    use crate::vm_translator::{translate_files, vm_files, TranslationOptions};
+
+    use std::env;
+    use std::fs::write;
+
+    fn symbols(layout: &StaticLayout) -> Vec<&str> {
+        layout
+            .variables()
+            .iter()
+            .map(|variable| variable.symbol.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let uses = ["B.3", "A.1", "Sys.init", "B.0", "A.1", "A.0"];
+        let layout = StaticLayout::new(uses, false);
+        assert_eq!(symbols(&layout), ["B.3", "A.1", "Sys.init", "B.0", "A.0"]);
+
+        // The other variables keep the addresses the assembler gives them
+        let packed = StaticLayout::new(uses, true);
+        assert_eq!(symbols(&packed), ["Sys.init", "B.0", "B.3", "A.0", "A.1"]);
+        assert_eq!(packed.address("A.1"), Some(20));
+        assert_eq!(
+            packed.variable_at(20).unwrap().file_static(),
+            Some(("A", 1))
+        );
+        assert_eq!(packed.variable_at(21), None);
+        assert_eq!(
+            packed.report_lines(),
+            [
+                "B: 2 static(s)",
+                "  B.0 RAM[17]",
+                "  B.3 RAM[18]",
+                "A: 2 static(s)",
+                "  A.0 RAM[19]",
+                "  A.1 RAM[20]",
+                "Other: 1 variable(s)",
+                "  Sys.init RAM[16]",
+                "5 of 240 static segment words used",
+            ]
+        );
+        assert!(packed.overflow().is_empty());
+
+        let symbols: Vec<String> = (0..241).map(|idx| format!("A.{idx}")).collect();
+        let full = StaticLayout::new(symbols.iter().map(String::as_str), true);
+        let overflow = &full.overflow()[0];
+        assert_eq!(
+            (overflow.symbol.as_str(), overflow.address),
+            ("A.240", STATIC_END)
+        );
+    }
+
+    #[test]
+    fn test_translated_layout() {
+        let test_dir = env::current_dir()
+            .unwrap()
+            .join("../../08/FunctionCalls/StaticsTest");
+        // The layout is where the assembler puts the statics, including the
+        // slots of inlined functions
+        for optimize in [false, true] {
+            let options = TranslationOptions {
+                optimize,
+                ..Default::default()
+            };
+            let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+            let layout: Vec<(String, u16)> = output
+                .statics
+                .variables()
+                .iter()
+                .map(|variable| (variable.symbol.clone(), variable.address))
+                .collect();
+            assert_eq!(layout, output.assemble().variables);
+        }
+
+        // Packed statics are addressed directly, one block per file
+        let options = TranslationOptions {
+            pack_statics: true,
+            ..Default::default()
+        };
+        let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+        assert!(output.assemble().variables.is_empty());
+        assert_eq!(
+            output.statics.report_lines(),
+            [
+                "Class1: 2 static(s)",
+                "  Class1.0 RAM[16]",
+                "  Class1.1 RAM[17]",
+                "Class2: 2 static(s)",
+                "  Class2.0 RAM[18]",
+                "  Class2.1 RAM[19]",
+                "4 of 240 static segment words used",
+            ]
+        );
+        run_test_script(&test_dir, "StaticsTest", &output.asm());
+    }
+
+    #[test]
+    fn test_other_variables() {
+        // Without a Sys.init, the bootstrap's call allocates it as a
+        // variable, which packed statics must not overlap
+        let test_dir = TempDir::new("vm_translator_rs_other_variables");
+        write(
+            test_dir.join("Main.vm"),
+            "push constant 7\npop static 1\npush static 1\npop static 0",
+        )
+        .unwrap();
+        for pack_statics in [false, true] {
+            let options = TranslationOptions {
+                pack_statics,
+                ..Default::default()
+            };
+            let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+            let layout: Vec<(&str, u16)> = output
+                .statics
+                .variables()
+                .iter()
+                .map(|variable| (variable.symbol.as_str(), variable.address))
+                .collect();
+            let expected = match pack_statics {
+                false => [("Sys.init", 16), ("Main.1", 17), ("Main.0", 18)],
+                true => [("Sys.init", 16), ("Main.0", 17), ("Main.1", 18)],
+            };
+            assert_eq!(layout, expected);
+            let assembled = output.assemble().variables;
+            assert_eq!(assembled[0], (String::from("Sys.init"), 16));
+            assert_eq!(assembled.len(), if pack_statics { 1 } else { 3 });
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        let test_dir = TempDir::new("vm_translator_rs_static_overflow");
+        let mut program = Vec::new();
+        for idx in 0..240 {
+            program.push(format!("push static {idx}"));
+            program.push(format!("pop static {idx}"));
+        }
+        write(test_dir.join("Main.vm"), program.join("\n")).unwrap();
+        write(test_dir.join("Other.vm"), "push static 0\npop static 1").unwrap();
+        for pack_statics in [false, true] {
+            let options = TranslationOptions {
+                bootstrap: false,
+                pack_statics,
+                ..Default::default()
+            };
+            let errors = translate_files(&vm_files(&test_dir), &options).unwrap_err();
+            assert_eq!(
+                errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                ["Other.vm:1: Too many statics: Other.0 needs RAM[256], past the static segment RAM[16..255] (242 variables in total)"]
+            );
+        }
+        // 240 statics just fit, but not with the bootstrap's Sys.init
+        let options = |bootstrap| TranslationOptions {
+            bootstrap,
+            ..Default::default()
+        };
+        let main = [test_dir.join("Main.vm")];
+        let output = translate_files(&main, &options(false)).unwrap();
+        assert_eq!(output.statics.variables().last().unwrap().address, 255);
+        let errors = translate_files(&main, &options(true)).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Main.vm:479: Too many statics: Main.239 needs RAM[256], past the static segment RAM[16..255] (241 variables in total)"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+// Test helpers: a minimal Hack CPU that runs the translator's assembly output,
+// as assembled by the project 6 assembler, and a runner for the subset of the
+// .tst/.cmp script language used by the project 7 and 8 test programs.
+use std::collections::HashSet;
+use std::env;
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use assembler_rs::assembler::{assemble_lines, Assembly};
+
+use crate::vm_translator::{translate_files, vm_files, TranslationOptions, TranslationOutput};
+
+// The full address space, including the execution counters above the keyboard
+const RAM_SIZE: usize = 32768;
+
+enum Instruction {
+    A(i16),
+    C { comp: u8, dest: u8, jump: u8 },
+}
+
+pub struct HackCpu {
+    rom: Vec<Instruction>,
+    pub ram: Vec<i16>,
+    a: i16,
+    d: i16,
+    pc: usize,
+}
+
+impl HackCpu {
+    pub fn new(asm: &[String]) -> Self {
+        HackCpu {
+            rom: decode(&assemble_lines(asm)),
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+        }
+    }
+
+    pub fn run(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        let Some(instruction) = self.rom.get(self.pc) else {
+            // Running off the end of ROM behaves like an infinite loop.
+            return;
+        };
+        match *instruction {
+            Instruction::A(value) => {
+                self.a = value;
+                self.pc += 1;
+            }
+            Instruction::C { comp, dest, jump } => {
+                let y = if comp & 0b1000000 != 0 {
+                    self.ram[self.a as u16 as usize]
+                } else {
+                    self.a
+                };
+                let out = alu(self.d, y, comp);
+                let address = self.a as u16 as usize;
+                if dest & 0b001 != 0 {
+                    self.ram[address] = out;
+                }
+                if dest & 0b100 != 0 {
+                    self.a = out;
+                }
+                if dest & 0b010 != 0 {
+                    self.d = out;
+                }
+                let taken = (jump & 0b100 != 0 && out < 0)
+                    || (jump & 0b010 != 0 && out == 0)
+                    || (jump & 0b001 != 0 && out > 0);
+                if taken {
+                    self.pc = address;
+                } else {
+                    self.pc += 1;
+                }
+            }
+        }
+    }
+}
+
+fn alu(x: i16, y: i16, comp: u8) -> i16 {
+    let (zx, nx, zy, ny, f, no) = (
+        comp & 0b100000 != 0,
+        comp & 0b010000 != 0,
+        comp & 0b001000 != 0,
+        comp & 0b000100 != 0,
+        comp & 0b000010 != 0,
+        comp & 0b000001 != 0,
+    );
+    let x = if zx { 0 } else { x };
+    let x = if nx { !x } else { x };
+    let y = if zy { 0 } else { y };
+    let y = if ny { !y } else { y };
+    let out = if f { x.wrapping_add(y) } else { x & y };
+    if no {
+        !out
+    } else {
+        out
+    }
+}
+
+fn decode(assembly: &Assembly) -> Vec<Instruction> {
+    // The assembler keeps the last definition of a label, which would hide
+    // a label that the translator generated twice
+    let mut labels = HashSet::new();
+    for (label, _) in &assembly.labels {
+        assert!(labels.insert(label), "Duplicate label: {label}");
+    }
+    assembly
+        .hack
+        .iter()
+        .map(|word| {
+            let word = u16::from_str_radix(word, 2).unwrap();
+            if word & 0x8000 == 0 {
+                Instruction::A(word as i16)
+            } else {
+                Instruction::C {
+                    comp: (word >> 6 & 0b1111111) as u8,
+                    dest: (word >> 3 & 0b111) as u8,
+                    jump: (word & 0b111) as u8,
+                }
+            }
+        })
+        .collect()
+}
+
+fn ram_address(name: &str) -> usize {
+    name.strip_prefix("RAM[")
+        .and_then(|name| name.strip_suffix(']'))
+        .and_then(|address| address.parse().ok())
+        .unwrap_or_else(|| panic!("Unsupported test script location: {name}"))
+}
+
+fn read_cmp_rows(cmp_file: &Path) -> Vec<Vec<i16>> {
+    // Header rows are skipped; only rows made of numbers are compared.
+    read_to_string(cmp_file)
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            line.split('|')
+                .map(str::trim)
+                .filter(|cell| !cell.is_empty())
+                .map(|cell| cell.parse::<i16>().ok())
+                .collect::<Option<Vec<i16>>>()
+                .filter(|row| !row.is_empty())
+        })
+        .collect()
+}
+
+/// The RAM settings, output list and last expected output row of the test
+/// script `<dir>/<name>.tst`, for programs that only run to completion.
+pub fn final_output(dir: &Path, name: &str) -> (Vec<(usize, i16)>, Vec<usize>, Vec<i16>) {
+    let script = read_to_string(dir.join(format!("{name}.tst"))).unwrap();
+    let tokens: Vec<&str> = script
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',' || c == ';'))
+        .filter(|token| !token.is_empty())
+        .collect();
+    let mut setup = Vec::new();
+    let mut output_list = Vec::new();
+    for (idx, token) in tokens.iter().enumerate() {
+        match *token {
+            "set" => setup.push((
+                ram_address(tokens[idx + 1]),
+                tokens[idx + 2].parse().unwrap(),
+            )),
+            "output-list" => {
+                output_list = tokens[idx + 1..]
+                    .iter()
+                    .take_while(|entry| entry.starts_with("RAM["))
+                    .map(|entry| ram_address(entry.split('%').next().unwrap()))
+                    .collect()
+            }
+            _ => (),
+        }
+    }
+    let expected = read_cmp_rows(&dir.join(format!("{name}.cmp")))
+        .pop()
+        .unwrap();
+    (setup, output_list, expected)
+}
+
+/// Runs the test script `<dir>/<name>.tst` against the given assembly and
+/// asserts that every `output` matches the corresponding row in the .cmp file.
+/// Returns the CPU in its final state.
+pub fn run_test_script(dir: &Path, name: &str, asm: &[String]) -> HackCpu {
+    let script = read_to_string(dir.join(format!("{name}.tst"))).unwrap();
+    let script: String = script
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    let expected_rows = read_cmp_rows(&dir.join(format!("{name}.cmp")));
+    let mut cpu = HackCpu::new(asm);
+    let mut output_list: Vec<usize> = Vec::new();
+    let mut outputs = 0;
+    let mut tokens = script
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .filter(|token| !token.is_empty())
+        .peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            "load" | "output-file" | "compare-to" => {
+                tokens.next();
+            }
+            "output-list" => {
+                output_list.clear();
+                while let Some(entry) = tokens.next_if(|entry| entry.starts_with("RAM[")) {
+                    output_list.push(ram_address(entry.split('%').next().unwrap()));
+                }
+            }
+            "set" => {
+                let location = ram_address(tokens.next().unwrap());
+                cpu.ram[location] = tokens.next().unwrap().parse().unwrap();
+            }
+            "repeat" => {
+                let cycles: usize = tokens.next().unwrap().parse().unwrap();
+                assert_eq!(tokens.next(), Some("{"));
+                assert_eq!(tokens.next(), Some("ticktock"));
+                assert_eq!(tokens.next(), Some("}"));
+                cpu.run(cycles);
+            }
+            "output" => {
+                let actual: Vec<i16> = output_list.iter().map(|&addr| cpu.ram[addr]).collect();
+                assert_eq!(actual, expected_rows[outputs], "{name}: output {outputs}");
+                outputs += 1;
+            }
+            _ => panic!("Unsupported test script command: {token}"),
+        }
+    }
+    assert_eq!(outputs, expected_rows.len(), "{name}: missing outputs");
+    cpu
+}
+
+/// RAM contents that a VM program can observe: everything except the
+/// translator's scratch registers R13-R15 and the unused stack above SP.
+/// With `skip_return_addresses`, the return addresses saved in each frame are
+/// left out too, since they are ROM addresses in assembly but instruction
+/// indices in the VM emulator.
+pub fn observable_ram(ram: &[i16], skip_return_addresses: bool) -> Vec<(usize, i16)> {
+    let sp = ram[0] as u16 as usize;
+    let mut return_addresses = Vec::new();
+    // Walk the saved LCL pointers from the innermost frame outwards
+    let mut lcl = ram[1] as u16 as usize;
+    while skip_return_addresses && (261..2048).contains(&lcl) {
+        return_addresses.push(lcl - 5);
+        let caller_lcl = ram[lcl - 4] as u16 as usize;
+        if caller_lcl >= lcl {
+            break;
+        }
+        lcl = caller_lcl;
+    }
+    ram.iter()
+        .copied()
+        .enumerate()
+        .filter(|(address, _)| {
+            !(13..=15).contains(address)
+                && !(sp..2048).contains(address)
+                && !return_addresses.contains(address)
+        })
+        .collect()
+}
+
+/// The RAM addresses of the inline slots that `asm` uses. Inlined functions
+/// keep their arguments and locals there, so the slots have no counterpart in
+/// code translated without inlining.
+pub fn inline_slots(asm: &[String]) -> Vec<usize> {
+    assemble_lines(asm)
+        .variables
+        .into_iter()
+        .filter(|(variable, _)| variable.starts_with("$inline."))
+        .map(|(_, address)| address as usize)
+        .collect()
+}
+
+/// A directory for the files a test writes, named after the test, the process
+/// and a count of the directories it has created, so that no two calls share
+/// one. It is removed on drop, even when the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let count = CREATED.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("{name}_{}_{count}", process::id()));
+        // Left behind by an earlier process with the same id
+        let _ = remove_dir_all(&path);
+        create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// Writes the given .vm files to a new temporary directory, translates them
+/// with each of `options` and runs the output for `cycles` CPU cycles,
+/// starting with SP = 256. `check` is called with the options and output of
+/// each translation and the CPU in its final state.
+pub fn run_vm_files(
+    name: &str,
+    files: &[(&str, &str)],
+    options: &[TranslationOptions],
+    cycles: usize,
+    check: impl Fn(&TranslationOptions, &TranslationOutput, &HackCpu),
+) {
+    let dir = TempDir::new(name);
+    for (file_name, program) in files {
+        write(dir.join(file_name), program).unwrap();
+    }
+    for options in options {
+        let output = translate_files(&vm_files(&dir), options).unwrap();
+        let mut cpu = HackCpu::new(&output.asm());
+        cpu.ram[0] = 256;
+        cpu.run(cycles);
+        check(options, &output, &cpu);
+    }
+}
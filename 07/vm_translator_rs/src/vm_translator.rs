@@ -1,7 +1,18 @@
+use crate::asm::AsmInstruction;
+use crate::call_graph::CallGraph;
+use crate::ir::Instruction;
+use crate::profile::{CounterKind, Counters};
+use crate::statics::{StaticLayout, STATIC_END, STATIC_START};
+use crate::{bytecode, inliner, optimizer};
+
+use assembler_rs::assembler::{assemble_lines, Assembly};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::fs::{read_to_string, write};
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MemorySegment {
     Local,
     Argument,
@@ -25,12 +36,30 @@ impl MemorySegment {
     }
 }
 
-mod parser {
+impl fmt::Display for MemorySegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MemorySegment::Local => "local",
+            MemorySegment::Argument => "argument",
+            MemorySegment::This => "this",
+            MemorySegment::That => "that",
+            MemorySegment::Constant => "constant",
+            MemorySegment::Static => "static",
+            MemorySegment::Pointer => "pointer",
+            MemorySegment::Temp => "temp",
+        };
+        write!(f, "{name}")
+    }
+}
+
+pub mod parser {
     // Takes a VM instruction and parses it into the type of instruction it is
     // as well as its individual components if necessary
     use super::MemorySegment;
+    use std::fmt;
+    use std::str::FromStr;
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Clone, Debug, PartialEq)]
     pub enum ParsedVMInstruction {
         Add,
         Sub,
@@ -41,324 +70,2371 @@ mod parser {
         And,
         Or,
         Not,
+        // Extensions to the VM language: multiplication, truncating division
+        // and remainder, and shifts left and (logically) right
+        Mul,
+        Div,
+        Mod,
+        Shl,
+        Shr,
         Pop { segment: MemorySegment, idx: u16 },
         Push { segment: MemorySegment, idx: u16 },
+        Label { label: String },
+        Goto { label: String },
+        IfGoto { label: String },
+        Function { name: String, n_vars: u16 },
+        Call { function: String, n_args: u16 },
+        Return,
     }
 
-    pub fn parse_instruction(instruction: &str) -> ParsedVMInstruction {
-        let split_instr: Vec<&str> = instruction.split(" ").collect();
-        match split_instr[0] {
-            "add" => ParsedVMInstruction::Add,
-            "sub" => ParsedVMInstruction::Sub,
-            "neg" => ParsedVMInstruction::Neg,
-            "eq" => ParsedVMInstruction::Eq,
-            "gt" => ParsedVMInstruction::Gt,
-            "lt" => ParsedVMInstruction::Lt,
-            "and" => ParsedVMInstruction::And,
-            "or" => ParsedVMInstruction::Or,
-            "not" => ParsedVMInstruction::Not,
-            "pop" => match split_instr[1] {
-                "local" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Local,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "argument" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Argument,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "this" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::This,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "that" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::That,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "static" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Static,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "pointer" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Pointer,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "temp" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Temp,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                _ => panic!("Invalid pop memory segment: {}", split_instr[1]),
-            },
-            "push" => match split_instr[1] {
-                "local" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Local,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "argument" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Argument,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "this" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::This,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "that" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::That,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "constant" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Constant,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "static" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Static,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "pointer" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Pointer,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "temp" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Temp,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                _ => panic!("Invalid push memory segment: {}", split_instr[1]),
-            },
-            _ => panic!("Invalid instruction type: {}", split_instr[0]),
+    impl fmt::Display for ParsedVMInstruction {
+        // Writes the instruction as a VM command
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                ParsedVMInstruction::Add => write!(f, "add"),
+                ParsedVMInstruction::Sub => write!(f, "sub"),
+                ParsedVMInstruction::Neg => write!(f, "neg"),
+                ParsedVMInstruction::Eq => write!(f, "eq"),
+                ParsedVMInstruction::Gt => write!(f, "gt"),
+                ParsedVMInstruction::Lt => write!(f, "lt"),
+                ParsedVMInstruction::And => write!(f, "and"),
+                ParsedVMInstruction::Or => write!(f, "or"),
+                ParsedVMInstruction::Not => write!(f, "not"),
+                ParsedVMInstruction::Mul => write!(f, "mul"),
+                ParsedVMInstruction::Div => write!(f, "div"),
+                ParsedVMInstruction::Mod => write!(f, "mod"),
+                ParsedVMInstruction::Shl => write!(f, "shl"),
+                ParsedVMInstruction::Shr => write!(f, "shr"),
+                ParsedVMInstruction::Pop { segment, idx } => write!(f, "pop {segment} {idx}"),
+                ParsedVMInstruction::Push { segment, idx } => write!(f, "push {segment} {idx}"),
+                ParsedVMInstruction::Label { label } => write!(f, "label {label}"),
+                ParsedVMInstruction::Goto { label } => write!(f, "goto {label}"),
+                ParsedVMInstruction::IfGoto { label } => write!(f, "if-goto {label}"),
+                ParsedVMInstruction::Function { name, n_vars } => {
+                    write!(f, "function {name} {n_vars}")
+                }
+                ParsedVMInstruction::Call { function, n_args } => {
+                    write!(f, "call {function} {n_args}")
+                }
+                ParsedVMInstruction::Return => write!(f, "return"),
+            }
+        }
+    }
+
+    impl FromStr for ParsedVMInstruction {
+        // Parses VM commands, so that Display and parse round-trip for them
+        type Err = String;
+
+        fn from_str(instruction: &str) -> Result<Self, Self::Err> {
+            parse_instruction(instruction)
+        }
+    }
+
+    impl FromStr for MemorySegment {
+        type Err = String;
+
+        fn from_str(segment: &str) -> Result<Self, Self::Err> {
+            parse_segment(segment)
+        }
+    }
+
+    // Largest index accepted for any segment: A-instructions hold 15 bits
+    const MAX_INDEX: u16 = 32767;
+    // Each file's statics are allocated in RAM[16..255]
+    pub const MAX_STATICS: u16 = 240;
+
+    pub fn parse_instruction(instruction: &str) -> Result<ParsedVMInstruction, String> {
+        let split_instr: Vec<&str> = instruction.split_whitespace().collect();
+        let Some((&command, args)) = split_instr.split_first() else {
+            return Err(String::from("Empty instruction"));
+        };
+        let instruction = match command {
+            "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" | "mul" | "div"
+            | "mod" | "shl" | "shr" | "return" => {
+                let [] = expect_args(command, args)?;
+                match command {
+                    "add" => ParsedVMInstruction::Add,
+                    "sub" => ParsedVMInstruction::Sub,
+                    "neg" => ParsedVMInstruction::Neg,
+                    "eq" => ParsedVMInstruction::Eq,
+                    "gt" => ParsedVMInstruction::Gt,
+                    "lt" => ParsedVMInstruction::Lt,
+                    "and" => ParsedVMInstruction::And,
+                    "or" => ParsedVMInstruction::Or,
+                    "not" => ParsedVMInstruction::Not,
+                    "mul" => ParsedVMInstruction::Mul,
+                    "div" => ParsedVMInstruction::Div,
+                    "mod" => ParsedVMInstruction::Mod,
+                    "shl" => ParsedVMInstruction::Shl,
+                    "shr" => ParsedVMInstruction::Shr,
+                    _ => ParsedVMInstruction::Return,
+                }
+            }
+            "pop" => {
+                let [segment, idx] = expect_args(command, args)?;
+                let segment = parse_segment(segment)?;
+                if segment == MemorySegment::Constant {
+                    return Err(String::from("Cannot pop to the constant segment"));
+                }
+                let idx = parse_index(&segment, idx)?;
+                ParsedVMInstruction::Pop { segment, idx }
+            }
+            "push" => {
+                let [segment, idx] = expect_args(command, args)?;
+                let segment = parse_segment(segment)?;
+                let idx = parse_index(&segment, idx)?;
+                ParsedVMInstruction::Push { segment, idx }
+            }
+            "label" => {
+                let [label] = expect_args(command, args)?;
+                ParsedVMInstruction::Label {
+                    label: parse_symbol(label)?,
+                }
+            }
+            "goto" => {
+                let [label] = expect_args(command, args)?;
+                ParsedVMInstruction::Goto {
+                    label: parse_symbol(label)?,
+                }
+            }
+            "if-goto" => {
+                let [label] = expect_args(command, args)?;
+                ParsedVMInstruction::IfGoto {
+                    label: parse_symbol(label)?,
+                }
+            }
+            "function" => {
+                let [name, n_vars] = expect_args(command, args)?;
+                ParsedVMInstruction::Function {
+                    name: parse_symbol(name)?,
+                    n_vars: parse_count(n_vars)?,
+                }
+            }
+            "call" => {
+                let [function, n_args] = expect_args(command, args)?;
+                ParsedVMInstruction::Call {
+                    function: parse_symbol(function)?,
+                    n_args: parse_count(n_args)?,
+                }
+            }
+            _ => return Err(format!("Invalid instruction type: {command}")),
+        };
+        Ok(instruction)
+    }
+
+    fn expect_args<'a, const N: usize>(
+        command: &str,
+        args: &[&'a str],
+    ) -> Result<[&'a str; N], String> {
+        <[&str; N]>::try_from(args).map_err(|_| {
+            format!(
+                "{command} expects {N} argument(s) but {} were given",
+                args.len()
+            )
+        })
+    }
+
+    fn parse_segment(segment: &str) -> Result<MemorySegment, String> {
+        match segment {
+            "local" => Ok(MemorySegment::Local),
+            "argument" => Ok(MemorySegment::Argument),
+            "this" => Ok(MemorySegment::This),
+            "that" => Ok(MemorySegment::That),
+            "constant" => Ok(MemorySegment::Constant),
+            "static" => Ok(MemorySegment::Static),
+            "pointer" => Ok(MemorySegment::Pointer),
+            "temp" => Ok(MemorySegment::Temp),
+            _ => Err(format!("Invalid memory segment: {segment}")),
+        }
+    }
+
+    fn parse_index(segment: &MemorySegment, idx: &str) -> Result<u16, String> {
+        match idx.parse::<u16>() {
+            Ok(idx) => check_index(segment, idx),
+            _ => Err(format!(
+                "Invalid {segment} index {idx}: must be between 0 and {}",
+                max_index(segment)
+            )),
+        }
+    }
+
+    fn max_index(segment: &MemorySegment) -> u16 {
+        match segment {
+            MemorySegment::Temp => 7,
+            MemorySegment::Pointer => 1,
+            MemorySegment::Static => MAX_STATICS - 1,
+            _ => MAX_INDEX,
+        }
+    }
+
+    pub(crate) fn check_index(segment: &MemorySegment, idx: u16) -> Result<u16, String> {
+        let max_idx = max_index(segment);
+        if idx <= max_idx {
+            Ok(idx)
+        } else {
+            Err(format!(
+                "Invalid {segment} index {idx}: must be between 0 and {max_idx}"
+            ))
+        }
+    }
+
+    fn parse_count(count: &str) -> Result<u16, String> {
+        match count.parse::<u16>() {
+            Ok(count) => check_count(count),
+            _ => Err(format!("Invalid count: {count}")),
         }
     }
+
+    pub(crate) fn check_count(count: u16) -> Result<u16, String> {
+        if count <= MAX_INDEX {
+            Ok(count)
+        } else {
+            Err(format!("Invalid count: {count}"))
+        }
+    }
+
+    pub(crate) fn parse_symbol(symbol: &str) -> Result<String, String> {
+        // Function and label names are made of letters, digits, '_', '.' and
+        // ':', and may not start with a digit.
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || "_.:".contains(c);
+        if symbol.starts_with(|c: char| c.is_ascii_digit()) || !symbol.chars().all(valid_char) {
+            return Err(format!("Invalid name: {symbol}"));
+        }
+        Ok(symbol.to_owned())
+    }
 }
 
 mod translator {
     // Given a parsed VM instruction, translates the instruction into its
     // valid Hack assembly code
     use super::parser::ParsedVMInstruction;
-    use super::MemorySegment;
+    use super::{MemorySegment, TRAP_ERROR_ADDRESS};
+    use crate::asm::{AsmInstruction, Comp, Dest, Jump};
+    use crate::ir::{Comparison, Instruction, Location};
+    use crate::profile::Counters;
+
+    // Shorthands for the instructions the translator emits: @symbol, @value,
+    // dest=comp, comp;jump and (label)
+    fn at(symbol: &str) -> AsmInstruction {
+        AsmInstruction::Symbol(symbol.to_owned())
+    }
+
+    fn at_value(value: u16) -> AsmInstruction {
+        AsmInstruction::Address(value)
+    }
+
+    fn assign(dest: Dest, comp: Comp) -> AsmInstruction {
+        AsmInstruction::Compute {
+            dest: Some(dest),
+            comp,
+            jump: None,
+        }
+    }
+
+    fn jump(comp: Comp, jump: Jump) -> AsmInstruction {
+        AsmInstruction::Compute {
+            dest: None,
+            comp,
+            jump: Some(jump),
+        }
+    }
+
+    fn target(name: &str) -> AsmInstruction {
+        AsmInstruction::Label(name.to_owned())
+    }
+
+    fn binary(comp: Comp) -> Vec<AsmInstruction> {
+        // Replaces x and y on top of the stack with comp, where D = y and
+        // M = x
+        vec![
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            assign(Dest::A, Comp::AMinusOne),
+            assign(Dest::M, comp),
+        ]
+    }
 
-    const ADD: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=M+D"];
-    const SUBTRACT: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=M-D"];
-    const NEG: &'static [&str] = &["@SP", "A=M-1", "M=-M"];
-    const AND: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=D&M"];
-    const OR: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=D|M"];
-    const NOT: &'static [&str] = &["@SP", "A=M-1", "M=!M"];
+    fn unary(comp: Comp) -> Vec<AsmInstruction> {
+        // Replaces the top of the stack with comp, where M is its value
+        vec![
+            at("SP"),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, comp),
+        ]
+    }
 
     const TEMP_OFFSET: u16 = 5;
+    // Inline slots are assembler variables named like statics
+    const INLINE_BASE: &str = "$inline";
+
+    // Bounds enforced by --checked: the stack occupies RAM[256..2047], and
+    // this/that must point into the heap or the screen
+    const STACK_BASE: u16 = 256;
+    const STACK_END: u16 = 2047;
+    const HEAP_BASE: u16 = 2048;
+    const SCREEN_END: u16 = 24575;
+
+    fn push_d() -> Vec<AsmInstruction> {
+        // Pushes the value in D onto the stack
+        vec![
+            at("SP"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
+        ]
+    }
+
+    fn return_to_caller() -> Vec<AsmInstruction> {
+        // R13 = frame, R14 = return address
+        let mut asm = vec![
+            at("LCL"),
+            assign(Dest::D, Comp::M),
+            at("R13"),
+            assign(Dest::M, Comp::D),
+            at_value(5),
+            assign(Dest::A, Comp::DMinusA),
+            assign(Dest::D, Comp::M),
+            at("R14"),
+            assign(Dest::M, Comp::D),
+            // *ARG = pop(), SP = ARG + 1
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at("ARG"),
+            assign(Dest::A, Comp::M),
+            assign(Dest::M, Comp::D),
+            at("ARG"),
+            assign(Dest::D, Comp::MPlusOne),
+            at("SP"),
+            assign(Dest::M, Comp::D),
+        ];
+        // Restore the caller's THAT, THIS, ARG and LCL from the frame
+        for seg_ptr in ["THAT", "THIS", "ARG", "LCL"] {
+            asm.extend([
+                at("R13"),
+                assign(Dest::AM, Comp::MMinusOne),
+                assign(Dest::D, Comp::M),
+                at(seg_ptr),
+                assign(Dest::M, Comp::D),
+            ]);
+        }
+        // goto return address
+        asm.extend([
+            at("R14"),
+            assign(Dest::A, Comp::M),
+            jump(Comp::Zero, Jump::JMP),
+        ]);
+        asm
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum SharedRoutine {
+        // Operations that --optimize-size emits once and jumps to, with the
+        // return address passed in D
+        Eq,
+        Gt,
+        Lt,
+        Call,
+        Return,
+        // Arithmetic extensions. Division and remainder are too long to
+        // inline, so they are always shared.
+        Mul,
+        Div,
+        Mod,
+        Shl,
+        Shr,
+    }
 
-    fn const_instr_to_vec(const_instr: &'static [&str]) -> Vec<String> {
-        const_instr.iter().map(|&s| s.to_string()).collect()
+    impl SharedRoutine {
+        pub fn for_instruction(instruction: &Instruction) -> Option<Self> {
+            match instruction.command()? {
+                ParsedVMInstruction::Eq => Some(SharedRoutine::Eq),
+                ParsedVMInstruction::Gt => Some(SharedRoutine::Gt),
+                ParsedVMInstruction::Lt => Some(SharedRoutine::Lt),
+                ParsedVMInstruction::Call { .. } => Some(SharedRoutine::Call),
+                ParsedVMInstruction::Return => Some(SharedRoutine::Return),
+                ParsedVMInstruction::Mul => Some(SharedRoutine::Mul),
+                ParsedVMInstruction::Div => Some(SharedRoutine::Div),
+                ParsedVMInstruction::Mod => Some(SharedRoutine::Mod),
+                ParsedVMInstruction::Shl => Some(SharedRoutine::Shl),
+                ParsedVMInstruction::Shr => Some(SharedRoutine::Shr),
+                _ => None,
+            }
+        }
+
+        pub fn always_shared(&self) -> bool {
+            matches!(self, SharedRoutine::Div | SharedRoutine::Mod)
+        }
+
+        pub fn name(&self) -> &str {
+            match self {
+                SharedRoutine::Eq => "eq",
+                SharedRoutine::Gt => "gt",
+                SharedRoutine::Lt => "lt",
+                SharedRoutine::Call => "call",
+                SharedRoutine::Return => "return",
+                SharedRoutine::Mul => "mul",
+                SharedRoutine::Div => "div",
+                SharedRoutine::Mod => "mod",
+                SharedRoutine::Shl => "shl",
+                SharedRoutine::Shr => "shr",
+            }
+        }
+
+        fn label(&self) -> String {
+            format!("${}", self.name())
+        }
+
+        pub fn asm(&self) -> Vec<AsmInstruction> {
+            let mut asm = vec![target(&self.label())];
+            match self {
+                SharedRoutine::Eq => asm.extend(shared_comp(self, Jump::JEQ)),
+                SharedRoutine::Gt => asm.extend(shared_comp(self, Jump::JGT)),
+                SharedRoutine::Lt => asm.extend(shared_comp(self, Jump::JLT)),
+                SharedRoutine::Call => {
+                    asm.extend(push_frame());
+                    asm.extend([
+                        // ARG = SP - 5 - R13
+                        at("R13"),
+                        assign(Dest::D, Comp::M),
+                        at_value(5),
+                        assign(Dest::D, Comp::DPlusA),
+                        at("SP"),
+                        assign(Dest::D, Comp::MMinusD),
+                        at("ARG"),
+                        assign(Dest::M, Comp::D),
+                        // LCL = SP
+                        at("SP"),
+                        assign(Dest::D, Comp::M),
+                        at("LCL"),
+                        assign(Dest::M, Comp::D),
+                        // goto R14
+                        at("R14"),
+                        assign(Dest::A, Comp::M),
+                        jump(Comp::Zero, Jump::JMP),
+                    ]);
+                }
+                SharedRoutine::Return => asm.extend(return_to_caller()),
+                SharedRoutine::Mul
+                | SharedRoutine::Div
+                | SharedRoutine::Mod
+                | SharedRoutine::Shl
+                | SharedRoutine::Shr => {
+                    // The return address is kept in the free stack slot
+                    // above the operands, and is RAM[SP + 1] once y is popped
+                    asm.extend([at("SP"), assign(Dest::A, Comp::M), assign(Dest::M, Comp::D)]);
+                    asm.extend(arithmetic(self, &self.label()));
+                    asm.extend(above_sp(1));
+                    asm.extend([assign(Dest::A, Comp::M), jump(Comp::Zero, Jump::JMP)]);
+                }
+            }
+            asm
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Trap {
+        // Runtime errors detected by --checked code, with their error codes
+        StackOverflow = 1,
+        StackUnderflow = 2,
+        ThisOutOfRange = 3,
+        ThatOutOfRange = 4,
+    }
+
+    impl Trap {
+        const ALL: [Trap; 4] = [
+            Trap::StackOverflow,
+            Trap::StackUnderflow,
+            Trap::ThisOutOfRange,
+            Trap::ThatOutOfRange,
+        ];
+
+        fn label(&self) -> &str {
+            match self {
+                Trap::StackOverflow => "$trap.overflow",
+                Trap::StackUnderflow => "$trap.underflow",
+                Trap::ThisOutOfRange => "$trap.this",
+                Trap::ThatOutOfRange => "$trap.that",
+            }
+        }
     }
 
-    pub fn translate(
+    #[derive(Clone)]
+    pub struct Context<'a> {
+        // Per-file and per-function state needed to translate an instruction
+        pub static_base: &'a str,
+        pub function_name: String,
+        // Local count of the current function, if inside one
+        pub n_vars: Option<u16>,
+        pub call_count: usize,
+        pub comparison_count: usize,
+        pub optimize_size: bool,
+        pub checked: bool,
+        // Execution counters to increment, if instrumenting
+        pub counters: Option<&'a Counters>,
+    }
+
+    impl<'a> Context<'a> {
+        pub fn new(static_base: &'a str, optimize_size: bool, checked: bool) -> Self {
+            // Labels outside of any function are scoped to the file instead.
+            Context {
+                static_base,
+                function_name: static_base.to_owned(),
+                n_vars: None,
+                call_count: 0,
+                comparison_count: 0,
+                optimize_size,
+                checked,
+                counters: None,
+            }
+        }
+
+        fn count(&self, name: &str) -> Vec<AsmInstruction> {
+            // Increments the named counter, if instrumenting
+            match self.counters {
+                Some(counters) => vec![
+                    at_value(counters.address(name).unwrap()),
+                    assign(Dest::M, Comp::MPlusOne),
+                ],
+                None => Vec::new(),
+            }
+        }
+    }
+
+    pub fn translate(instruction: Instruction, context: &mut Context) -> Vec<AsmInstruction> {
+        if !context.checked {
+            return translate_unchecked(instruction, context);
+        }
+        let is_function = matches!(
+            instruction,
+            Instruction::Command(ParsedVMInstruction::Function { .. })
+        );
+        let checks = checks(&instruction, context);
+        let mut asm = translate_unchecked(instruction, context);
+        // A function's checks follow its label, so they run on entry
+        let position = if is_function { 1 } else { 0 };
+        asm.splice(position..position, checks);
+        asm
+    }
+
+    fn stack_effect(instruction: &Instruction) -> (u16, u16) {
+        // The number of values an instruction pops from the working stack,
+        // and how far it can grow the stack past its starting point
+        let command = match instruction {
+            Instruction::Command(command) => command,
+            Instruction::Push(_) => return (0, 1),
+            Instruction::Pop(_) => return (1, 0),
+            Instruction::Move { .. } => return (0, 0),
+            Instruction::CompareGoto { .. } => return (2, 0),
+            // The arguments must be on the stack, and the call pushes a frame
+            Instruction::TailCall { n_args, .. } => return (*n_args, 5),
+        };
+        match command {
+            ParsedVMInstruction::Add
+            | ParsedVMInstruction::Sub
+            | ParsedVMInstruction::Eq
+            | ParsedVMInstruction::Gt
+            | ParsedVMInstruction::Lt
+            | ParsedVMInstruction::And
+            | ParsedVMInstruction::Or => (2, 0),
+            // The arithmetic extensions use the free slots above the stack
+            // for scratch values and their return address
+            ParsedVMInstruction::Mul | ParsedVMInstruction::Shl | ParsedVMInstruction::Shr => {
+                (2, 1)
+            }
+            ParsedVMInstruction::Div | ParsedVMInstruction::Mod => (2, 4),
+            ParsedVMInstruction::Neg
+            | ParsedVMInstruction::Not
+            | ParsedVMInstruction::Pop { .. }
+            | ParsedVMInstruction::IfGoto { .. }
+            | ParsedVMInstruction::Return => (1, 0),
+            ParsedVMInstruction::Push { .. } => (0, 1),
+            // The arguments must be on the stack, and the call pushes a frame
+            ParsedVMInstruction::Call { n_args, .. } => (*n_args, 5),
+            ParsedVMInstruction::Function { n_vars, .. } => (0, *n_vars),
+            ParsedVMInstruction::Label { .. } | ParsedVMInstruction::Goto { .. } => (0, 0),
+        }
+    }
+
+    fn checks(instruction: &Instruction, context: &Context) -> Vec<AsmInstruction> {
+        let mut asm = Vec::new();
+        let (pops, growth) = stack_effect(instruction);
+        if pops > 0 {
+            // The working stack starts after the current function's locals,
+            // or at the stack base outside of any function
+            match context.n_vars {
+                Some(n_vars) => asm.extend([
+                    at("LCL"),
+                    assign(Dest::D, Comp::M),
+                    at_value(n_vars + pops),
+                    assign(Dest::D, Comp::DPlusA),
+                    at("SP"),
+                    assign(Dest::D, Comp::MMinusD),
+                ]),
+                None => asm.extend([
+                    at("SP"),
+                    assign(Dest::D, Comp::M),
+                    at_value(STACK_BASE + pops),
+                    assign(Dest::D, Comp::DMinusA),
+                ]),
+            }
+            asm.extend(trap_if(Trap::StackUnderflow, Jump::JLT));
+        }
+        if growth > 0 {
+            asm.extend([
+                at("SP"),
+                assign(Dest::D, Comp::M),
+                at_value(STACK_END - growth),
+                assign(Dest::D, Comp::DMinusA),
+            ]);
+            asm.extend(trap_if(Trap::StackOverflow, Jump::JGT));
+        }
+        for location in instruction.locations() {
+            let (segment, trap, idx) = match location {
+                Location::Segment(segment @ MemorySegment::This, idx) => {
+                    (segment, Trap::ThisOutOfRange, idx)
+                }
+                Location::Segment(segment @ MemorySegment::That, idx) => {
+                    (segment, Trap::ThatOutOfRange, idx)
+                }
+                _ => continue,
+            };
+            // HEAP_BASE <= segment pointer + idx <= SCREEN_END
+            asm.extend([
+                at_value(*idx),
+                assign(Dest::D, Comp::A),
+                at(segment.seg_ptr()),
+                assign(Dest::D, Comp::DPlusM),
+                at_value(HEAP_BASE),
+                assign(Dest::D, Comp::DMinusA),
+            ]);
+            asm.extend(trap_if(trap, Jump::JLT));
+            asm.extend([
+                at_value(SCREEN_END - HEAP_BASE),
+                assign(Dest::D, Comp::DMinusA),
+            ]);
+            asm.extend(trap_if(trap, Jump::JGT));
+        }
+        asm
+    }
+
+    fn trap_if(trap: Trap, jmp_instr: Jump) -> Vec<AsmInstruction> {
+        vec![at(trap.label()), jump(Comp::D, jmp_instr)]
+    }
+
+    pub fn trap_routines() -> Vec<AsmInstruction> {
+        // Each trap loads its error code into D, stores it and halts. Like
+        // the shared routines, they are skipped over on the way in.
+        let mut asm = vec![at("$trap.end"), jump(Comp::Zero, Jump::JMP)];
+        for trap in Trap::ALL {
+            asm.extend([
+                target(trap.label()),
+                at_value(trap as u16),
+                assign(Dest::D, Comp::A),
+                at("$trap"),
+                jump(Comp::Zero, Jump::JMP),
+            ]);
+        }
+        asm.extend([
+            target("$trap"),
+            at_value(TRAP_ERROR_ADDRESS),
+            assign(Dest::M, Comp::D),
+            target("$trap.halt"),
+            at("$trap.halt"),
+            jump(Comp::Zero, Jump::JMP),
+            target("$trap.end"),
+        ]);
+        asm
+    }
+
+    fn translate_unchecked(instruction: Instruction, context: &mut Context) -> Vec<AsmInstruction> {
+        if let Some(routine) = SharedRoutine::for_instruction(&instruction) {
+            if context.optimize_size || routine.always_shared() {
+                return jump_to_shared_routine(instruction, routine, context);
+            }
+        }
+        match instruction {
+            Instruction::Command(command) => translate_command(command, context),
+            Instruction::Push(location) => push(location, context.static_base),
+            Instruction::Pop(location) => pop(location, context.static_base),
+            Instruction::Move { from, to } => move_value(from, to, context.static_base),
+            Instruction::CompareGoto { comparison, label } => {
+                let label = scoped_label(&context.function_name, &label);
+                match context.counters {
+                    Some(_) => counted_compare_goto(comparison, &label, context),
+                    None => compare_goto(comparison, &label),
+                }
+            }
+            Instruction::TailCall { function, n_args } => tail_call(&function, n_args),
+        }
+    }
+
+    fn tail_call(function: &str, n_args: u16) -> Vec<AsmInstruction> {
+        // R13 = n_args + 5, R14 = function address
+        vec![
+            at_value(n_args + 5),
+            assign(Dest::D, Comp::A),
+            at("R13"),
+            assign(Dest::M, Comp::D),
+            at(function),
+            assign(Dest::D, Comp::A),
+            at("R14"),
+            assign(Dest::M, Comp::D),
+            at("$tailcall"),
+            jump(Comp::Zero, Jump::JMP),
+        ]
+    }
+
+    pub fn tail_call_routine() -> Vec<AsmInstruction> {
+        // Replaces the current frame with one for the callee: the arguments
+        // are moved down to ARG, followed by a copy of the current frame's
+        // saved return address and pointers, so that the callee returns
+        // directly to our caller. Like the shared routines, it is skipped
+        // over on the way in.
+        let mut asm = vec![
+            at("$tailcall.end"),
+            jump(Comp::Zero, Jump::JMP),
+            target("$tailcall"),
+        ];
+        // Push a copy of the saved frame, which lies at LCL - 5..LCL - 1
+        for offset in (1..=5).rev() {
+            asm.extend([
+                at("LCL"),
+                assign(Dest::D, Comp::M),
+                at_value(offset),
+                assign(Dest::A, Comp::DMinusA),
+                assign(Dest::D, Comp::M),
+            ]);
+            asm.extend(push_d());
+        }
+        asm.extend([
+            // R13 = SP - n_args - 5, the first argument
+            at("R13"),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::D, Comp::MMinusD),
+            at("R13"),
+            assign(Dest::M, Comp::D),
+            // The destination is tracked in LCL, which is replaced anyway
+            at("ARG"),
+            assign(Dest::D, Comp::M),
+            at("LCL"),
+            assign(Dest::M, Comp::D),
+            // Copy the words from R13 up to SP to LCL in ascending order.
+            // The destination is below the source, so every word is copied
+            // before it is overwritten.
+            target("$tailcall.loop"),
+            at("R13"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at("LCL"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
+            at("R13"),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::D, Comp::DMinusM),
+            at("$tailcall.loop"),
+            jump(Comp::D, Jump::JLT),
+            // LCL now points just past the new frame, SP = LCL
+            at("LCL"),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::M, Comp::D),
+            // goto R14
+            at("R14"),
+            assign(Dest::A, Comp::M),
+            jump(Comp::Zero, Jump::JMP),
+            target("$tailcall.end"),
+        ]);
+        asm
+    }
+
+    fn translate_command(
         instruction: ParsedVMInstruction,
-        next_instr: usize,
-        static_base: &str,
-    ) -> Vec<String> {
+        context: &mut Context,
+    ) -> Vec<AsmInstruction> {
+        let static_base = context.static_base;
+        let function_name = context.function_name.as_str();
         match instruction {
-            ParsedVMInstruction::Add => const_instr_to_vec(ADD),
-            ParsedVMInstruction::Sub => const_instr_to_vec(SUBTRACT),
-            ParsedVMInstruction::Neg => const_instr_to_vec(NEG),
-            ParsedVMInstruction::Eq => logical_comp(next_instr, "JEQ"),
-            ParsedVMInstruction::Gt => logical_comp(next_instr, "JGT"),
-            ParsedVMInstruction::Lt => logical_comp(next_instr, "JLT"),
-            ParsedVMInstruction::And => const_instr_to_vec(AND),
-            ParsedVMInstruction::Or => const_instr_to_vec(OR),
-            ParsedVMInstruction::Not => const_instr_to_vec(NOT),
-            ParsedVMInstruction::Pop { segment, idx } => match segment {
-                MemorySegment::Local => basic_pop(segment, idx),
-                MemorySegment::Argument => basic_pop(segment, idx),
-                MemorySegment::This => basic_pop(segment, idx),
-                MemorySegment::That => basic_pop(segment, idx),
-                MemorySegment::Constant => panic!("Invalid instruction: pop constant"),
-                MemorySegment::Static => pop_static(idx, static_base),
-                MemorySegment::Pointer => pop_ptr(idx),
-                MemorySegment::Temp => pop_temp(idx),
+            ParsedVMInstruction::Add => binary(Comp::DPlusM),
+            ParsedVMInstruction::Sub => binary(Comp::MMinusD),
+            ParsedVMInstruction::Neg => unary(Comp::NegM),
+            ParsedVMInstruction::Eq => logical_comp(context, Jump::JEQ),
+            ParsedVMInstruction::Gt => logical_comp(context, Jump::JGT),
+            ParsedVMInstruction::Lt => logical_comp(context, Jump::JLT),
+            ParsedVMInstruction::And => binary(Comp::DAndM),
+            ParsedVMInstruction::Or => binary(Comp::DOrM),
+            ParsedVMInstruction::Not => unary(Comp::NotM),
+            ParsedVMInstruction::Mul => inline_arithmetic(SharedRoutine::Mul, context),
+            ParsedVMInstruction::Shl => inline_arithmetic(SharedRoutine::Shl, context),
+            ParsedVMInstruction::Shr => inline_arithmetic(SharedRoutine::Shr, context),
+            ParsedVMInstruction::Div | ParsedVMInstruction::Mod => {
+                unreachable!("{instruction} is always shared")
+            }
+            ParsedVMInstruction::Pop { segment, idx } => {
+                pop(Location::Segment(segment, idx), static_base)
+            }
+            ParsedVMInstruction::Push { segment, idx } => {
+                push(Location::Segment(segment, idx), static_base)
+            }
+            ParsedVMInstruction::Label { label } => {
+                vec![target(&scoped_label(function_name, &label))]
+            }
+            ParsedVMInstruction::Goto { label } => {
+                let label = scoped_label(function_name, &label);
+                let mut asm = context.count(&label);
+                asm.extend(goto(&label));
+                asm
+            }
+            ParsedVMInstruction::IfGoto { label } => {
+                let label = scoped_label(function_name, &label);
+                match context.counters {
+                    Some(_) => counted_if_goto(&label, context),
+                    None => if_goto(&label),
+                }
+            }
+            ParsedVMInstruction::Function { name, n_vars } => {
+                let mut asm = function(&name, n_vars);
+                // Calls and tail calls both enter past the label
+                asm.splice(1..1, context.count(&name));
+                context.function_name = name;
+                context.n_vars = Some(n_vars);
+                context.call_count = 0;
+                context.comparison_count = 0;
+                asm
+            }
+            ParsedVMInstruction::Call { function, n_args } => {
+                let return_label = generated_label(function_name, "ret", context.call_count);
+                context.call_count += 1;
+                call(&function, n_args, &return_label)
+            }
+            ParsedVMInstruction::Return => return_to_caller(),
+        }
+    }
+
+    fn pop(location: Location, static_base: &str) -> Vec<AsmInstruction> {
+        let (segment, idx) = match location {
+            Location::Segment(segment, idx) => (segment, idx),
+            Location::Inline(idx) => return pop_static(idx, INLINE_BASE),
+        };
+        match segment {
+            MemorySegment::Local => basic_pop(segment, idx),
+            MemorySegment::Argument => basic_pop(segment, idx),
+            MemorySegment::This => basic_pop(segment, idx),
+            MemorySegment::That => basic_pop(segment, idx),
+            MemorySegment::Constant => panic!("Invalid instruction: pop constant"),
+            MemorySegment::Static => pop_static(idx, static_base),
+            MemorySegment::Pointer => pop_ptr(idx),
+            MemorySegment::Temp => pop_temp(idx),
+        }
+    }
+
+    fn push(location: Location, static_base: &str) -> Vec<AsmInstruction> {
+        let (segment, idx) = match location {
+            Location::Segment(segment, idx) => (segment, idx),
+            Location::Inline(idx) => return push_static(idx, INLINE_BASE),
+        };
+        match segment {
+            MemorySegment::Local => basic_push(segment, idx),
+            MemorySegment::Argument => basic_push(segment, idx),
+            MemorySegment::This => basic_push(segment, idx),
+            MemorySegment::That => basic_push(segment, idx),
+            MemorySegment::Constant => push_const(idx),
+            MemorySegment::Static => push_static(idx, static_base),
+            MemorySegment::Pointer => push_ptr(idx),
+            MemorySegment::Temp => push_temp(idx),
+        }
+    }
+
+    enum Address<'a> {
+        // A symbol or number for @, or a segment pointer plus an index
+        Direct(AsmInstruction),
+        Indirect(&'a str, u16),
+    }
+
+    fn address<'a>(location: &'a Location, static_base: &str) -> Address<'a> {
+        match location {
+            Location::Segment(MemorySegment::Static, idx) => {
+                Address::Direct(at(&format!("{static_base}.{idx}")))
+            }
+            Location::Inline(idx) => Address::Direct(at(&format!("{INLINE_BASE}.{idx}"))),
+            Location::Segment(MemorySegment::Temp, idx) => {
+                Address::Direct(at_value(TEMP_OFFSET + idx))
+            }
+            Location::Segment(MemorySegment::Pointer, idx) => match idx {
+                0 => Address::Direct(at("THIS")),
+                1 => Address::Direct(at("THAT")),
+                _ => panic!("pointer index must be 0 or 1"),
             },
-            ParsedVMInstruction::Push { segment, idx } => match segment {
-                MemorySegment::Local => basic_push(segment, idx),
-                MemorySegment::Argument => basic_push(segment, idx),
-                MemorySegment::This => basic_push(segment, idx),
-                MemorySegment::That => basic_push(segment, idx),
-                MemorySegment::Constant => push_const(idx),
-                MemorySegment::Static => push_static(idx, static_base),
-                MemorySegment::Pointer => push_ptr(idx),
-                MemorySegment::Temp => push_temp(idx),
+            Location::Segment(segment, idx) => Address::Indirect(segment.seg_ptr(), *idx),
+        }
+    }
+
+    fn move_value(from: Location, to: Location, static_base: &str) -> Vec<AsmInstruction> {
+        let mut asm = Vec::new();
+        let to_address = match &to {
+            Location::Segment(MemorySegment::Constant, _) => {
+                panic!("Invalid instruction: pop constant")
+            }
+            _ => address(&to, static_base),
+        };
+        if let Address::Indirect(seg_ptr, to_idx) = to_address {
+            // R13 = seg_ptr + to_idx
+            asm.extend([
+                at_value(to_idx),
+                assign(Dest::D, Comp::A),
+                at(seg_ptr),
+                assign(Dest::D, Comp::DPlusM),
+                at("R13"),
+                assign(Dest::M, Comp::D),
+            ]);
+        }
+        // D = source value
+        match &from {
+            Location::Segment(MemorySegment::Constant, from_idx) => {
+                asm.extend([at_value(*from_idx), assign(Dest::D, Comp::A)]);
+            }
+            _ => match address(&from, static_base) {
+                Address::Direct(address) => asm.extend([address, assign(Dest::D, Comp::M)]),
+                Address::Indirect(seg_ptr, from_idx) => asm.extend([
+                    at_value(from_idx),
+                    assign(Dest::D, Comp::A),
+                    at(seg_ptr),
+                    assign(Dest::A, Comp::DPlusM),
+                    assign(Dest::D, Comp::M),
+                ]),
             },
         }
+        match to_address {
+            Address::Direct(address) => asm.extend([address, assign(Dest::M, Comp::D)]),
+            Address::Indirect(..) => asm.extend([
+                at("R13"),
+                assign(Dest::A, Comp::M),
+                assign(Dest::M, Comp::D),
+            ]),
+        }
+        asm
+    }
+
+    fn compare_goto(comparison: Comparison, label: &str) -> Vec<AsmInstruction> {
+        vec![
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            assign(Dest::A, Comp::AMinusOne),
+            assign(Dest::D, Comp::MMinusD),
+            at("SP"),
+            assign(Dest::M, Comp::MMinusOne),
+            at(label),
+            jump(Comp::D, comparison.jump()),
+        ]
+    }
+
+    pub fn bootstrap() -> Vec<AsmInstruction> {
+        // SP = 256, call Sys.init
+        let mut asm = vec![
+            at_value(256),
+            assign(Dest::D, Comp::A),
+            at("SP"),
+            assign(Dest::M, Comp::D),
+        ];
+        asm.extend(call("Sys.init", 0, "$bootstrap.ret"));
+        asm
+    }
+
+    pub fn skip_shared_routines(routines: &[SharedRoutine]) -> Vec<AsmInstruction> {
+        // Shared routines are placed in front of the program code, so they
+        // are wrapped in a jump that skips over them.
+        let mut asm = vec![at("$routines.end"), jump(Comp::Zero, Jump::JMP)];
+        for routine in routines {
+            asm.extend(routine.asm());
+        }
+        asm.push(target("$routines.end"));
+        asm
+    }
+
+    fn jump_to_shared_routine(
+        instruction: Instruction,
+        routine: SharedRoutine,
+        context: &mut Context,
+    ) -> Vec<AsmInstruction> {
+        let mut asm = Vec::new();
+        if let Instruction::Command(ParsedVMInstruction::Call { function, n_args }) = instruction {
+            // R13 = n_args, R14 = function address
+            asm.extend([
+                at_value(n_args),
+                assign(Dest::D, Comp::A),
+                at("R13"),
+                assign(Dest::M, Comp::D),
+                at(&function),
+                assign(Dest::D, Comp::A),
+                at("R14"),
+                assign(Dest::M, Comp::D),
+            ]);
+        }
+        if routine == SharedRoutine::Return {
+            // Return never comes back to the caller
+            asm.extend([at(&routine.label()), jump(Comp::Zero, Jump::JMP)]);
+            return asm;
+        }
+        let return_label = match routine {
+            SharedRoutine::Call => {
+                context.call_count += 1;
+                generated_label(&context.function_name, "ret", context.call_count - 1)
+            }
+            _ => {
+                context.comparison_count += 1;
+                generated_label(&context.function_name, "cmp", context.comparison_count - 1)
+            }
+        };
+        asm.extend([
+            at(&return_label),
+            assign(Dest::D, Comp::A),
+            at(&routine.label()),
+            jump(Comp::Zero, Jump::JMP),
+            target(&return_label),
+        ]);
+        asm
+    }
+
+    fn shared_comp(routine: &SharedRoutine, jmp_instr: Jump) -> Vec<AsmInstruction> {
+        let true_label = format!("{}.true", routine.label());
+        vec![
+            at("R13"),
+            assign(Dest::M, Comp::D),
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            assign(Dest::A, Comp::AMinusOne),
+            assign(Dest::D, Comp::MMinusD),
+            assign(Dest::M, Comp::MinusOne),
+            at(&true_label),
+            jump(Comp::D, jmp_instr),
+            at("SP"),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::Zero),
+            target(&true_label),
+            at("R13"),
+            assign(Dest::A, Comp::M),
+            jump(Comp::Zero, Jump::JMP),
+        ]
     }
 
-    fn logical_comp(next_instr: usize, jmp_instr: &str) -> Vec<String> {
+    fn pop_operands() -> Vec<AsmInstruction> {
+        // R13 = x, R14 = y, leaving SP at y's slot, which is free to use
         vec![
-            String::from("@SP"),
-            String::from("AM=M-1"),
-            String::from("D=M"),
-            String::from("A=A-1"),
-            String::from("D=M-D"),
-            String::from("M=-1"),
-            format!("@{}", next_instr + 11),
-            format!("D;{}", jmp_instr),
-            String::from("@SP"),
-            String::from("A=M-1"),
-            String::from("M=0"),
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at("R14"),
+            assign(Dest::M, Comp::D),
+            at("SP"),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at("R13"),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn basic_pop(segment: MemorySegment, idx: u16) -> Vec<String> {
+    fn above_sp(offset: usize) -> Vec<AsmInstruction> {
+        // Addresses RAM[SP + offset] without touching D
+        let mut asm = vec![at("SP"), assign(Dest::A, Comp::M)];
+        asm.extend((0..offset).map(|_| assign(Dest::A, Comp::APlusOne)));
+        asm
+    }
+
+    fn inline_arithmetic(routine: SharedRoutine, context: &mut Context) -> Vec<AsmInstruction> {
+        let prefix = generated_label(
+            &context.function_name,
+            routine.name(),
+            context.comparison_count,
+        );
+        context.comparison_count += 1;
+        arithmetic(&routine, &prefix)
+    }
+
+    fn arithmetic(routine: &SharedRoutine, prefix: &str) -> Vec<AsmInstruction> {
+        // Replaces x and y on top of the stack with the result. Labels are
+        // named after the prefix.
+        let at_local = |name: &str| at(&format!("{prefix}.{name}"));
+        let local_target = |name: &str| target(&format!("{prefix}.{name}"));
+        let mut asm = pop_operands();
+        match routine {
+            SharedRoutine::Mul => {
+                // result = 0, mask = 1 in y's slot. For each set bit of y,
+                // from the lowest: result += x shifted to the bit's position
+                asm.extend([
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                    assign(Dest::M, Comp::Zero),
+                    at("SP"),
+                    assign(Dest::A, Comp::M),
+                    assign(Dest::M, Comp::One),
+                ]);
+                asm.extend([local_target("loop"), at("R14"), assign(Dest::D, Comp::M)]);
+                asm.extend([at_local("end"), jump(Comp::D, Jump::JEQ)]);
+                asm.extend([
+                    at("SP"),
+                    assign(Dest::A, Comp::M),
+                    assign(Dest::D, Comp::M),
+                    at("R14"),
+                    assign(Dest::D, Comp::DAndM),
+                ]);
+                asm.extend([at_local("next"), jump(Comp::D, Jump::JEQ)]);
+                // y -= mask, result += x
+                asm.extend([
+                    at("SP"),
+                    assign(Dest::A, Comp::M),
+                    assign(Dest::D, Comp::M),
+                    at("R14"),
+                    assign(Dest::M, Comp::MMinusD),
+                ]);
+                asm.extend([
+                    at("R13"),
+                    assign(Dest::D, Comp::M),
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                    assign(Dest::M, Comp::DPlusM),
+                ]);
+                // x += x, mask += mask
+                asm.push(local_target("next"));
+                asm.extend([
+                    at("R13"),
+                    assign(Dest::D, Comp::M),
+                    assign(Dest::M, Comp::DPlusM),
+                    at("SP"),
+                    assign(Dest::A, Comp::M),
+                    assign(Dest::D, Comp::M),
+                    assign(Dest::M, Comp::DPlusM),
+                ]);
+                asm.extend([
+                    at_local("loop"),
+                    jump(Comp::Zero, Jump::JMP),
+                    local_target("end"),
+                ]);
+            }
+            SharedRoutine::Shl => {
+                // Shifting by y outside 0..15 gives 0
+                asm.extend([at("R14"), assign(Dest::D, Comp::M)]);
+                asm.extend([at_local("zero"), jump(Comp::D, Jump::JLT)]);
+                asm.extend([at_value(16), assign(Dest::D, Comp::DMinusA)]);
+                asm.extend([at_local("zero"), jump(Comp::D, Jump::JGE)]);
+                // x += x, y times
+                asm.extend([local_target("loop"), at("R14"), assign(Dest::D, Comp::M)]);
+                asm.extend([at_local("end"), jump(Comp::D, Jump::JEQ)]);
+                asm.extend([
+                    at("R14"),
+                    assign(Dest::M, Comp::DMinusOne),
+                    at("R13"),
+                    assign(Dest::D, Comp::M),
+                    assign(Dest::M, Comp::DPlusM),
+                ]);
+                asm.extend([at_local("loop"), jump(Comp::Zero, Jump::JMP)]);
+                asm.extend([local_target("zero"), at("R13"), assign(Dest::M, Comp::Zero)]);
+                asm.push(local_target("end"));
+                asm.extend([
+                    at("R13"),
+                    assign(Dest::D, Comp::M),
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                    assign(Dest::M, Comp::D),
+                ]);
+            }
+            SharedRoutine::Shr => {
+                // Shifting by y outside 0..15 gives 0
+                asm.extend([at("R14"), assign(Dest::D, Comp::M)]);
+                asm.extend([at_local("zero"), jump(Comp::D, Jump::JLT)]);
+                asm.extend([at_value(16), assign(Dest::D, Comp::DMinusA)]);
+                asm.extend([at_local("zero"), jump(Comp::D, Jump::JGE)]);
+                // The result is built from the top 16 - y bits of x, taking
+                // the highest bit of x and doubling x each time
+                asm.extend([
+                    at("R14"),
+                    assign(Dest::M, Comp::NegD),
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                    assign(Dest::M, Comp::Zero),
+                ]);
+                asm.extend([local_target("loop"), at("R14"), assign(Dest::D, Comp::M)]);
+                asm.extend([at_local("end"), jump(Comp::D, Jump::JEQ)]);
+                asm.extend([
+                    at("R14"),
+                    assign(Dest::M, Comp::DMinusOne),
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                    assign(Dest::D, Comp::M),
+                    assign(Dest::M, Comp::DPlusM),
+                ]);
+                asm.extend([at("R13"), assign(Dest::D, Comp::M)]);
+                asm.extend([at_local("shift"), jump(Comp::D, Jump::JGE)]);
+                asm.extend([
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                    assign(Dest::M, Comp::MPlusOne),
+                ]);
+                asm.push(local_target("shift"));
+                asm.extend([
+                    at("R13"),
+                    assign(Dest::D, Comp::M),
+                    assign(Dest::M, Comp::DPlusM),
+                ]);
+                asm.extend([at_local("loop"), jump(Comp::Zero, Jump::JMP)]);
+                asm.extend([
+                    local_target("zero"),
+                    at("SP"),
+                    assign(Dest::A, Comp::MMinusOne),
+                ]);
+                asm.extend([assign(Dest::M, Comp::Zero), local_target("end")]);
+            }
+            SharedRoutine::Div | SharedRoutine::Mod => asm.extend(divide(routine, prefix)),
+            _ => panic!("{} is not an arithmetic routine", routine.name()),
+        }
+        asm
+    }
+
+    fn divide(routine: &SharedRoutine, prefix: &str) -> Vec<AsmInstruction> {
+        // Long division of |x| by |y|, one bit of |x| at a time from the
+        // highest, with x and y kept in their slots for the signs. Uses
+        // RAM[SP + 2] for the remainder r, RAM[SP + 3] for the quotient q and
+        // RAM[SP + 4] for the bit count. Dividing by zero gives -1 and a
+        // remainder of x.
+        let at_local = |name: &str| at(&format!("{prefix}.{name}"));
+        let local_target = |name: &str| target(&format!("{prefix}.{name}"));
+        let jump_if = |name: &str, condition: Jump| vec![at_local(name), jump(Comp::D, condition)];
+        let mut asm = vec![at("R14"), assign(Dest::D, Comp::M)];
+        asm.extend(jump_if("zero", Jump::JEQ));
+        // R13 = |x|, R14 = |y|
+        for register in ["R13", "R14"] {
+            let positive = format!("{prefix}.{register}");
+            asm.extend([at(register), assign(Dest::D, Comp::M)]);
+            asm.extend([at(&positive), jump(Comp::D, Jump::JGE)]);
+            asm.extend([at(register), assign(Dest::M, Comp::NegD)]);
+            asm.push(target(&positive));
+        }
+        asm.extend(above_sp(2));
+        asm.push(assign(Dest::M, Comp::Zero));
+        asm.extend(above_sp(3));
+        asm.push(assign(Dest::M, Comp::Zero));
+        asm.extend([at_value(16), assign(Dest::D, Comp::A)]);
+        asm.extend(above_sp(4));
+        asm.push(assign(Dest::M, Comp::D));
+        asm.push(local_target("loop"));
+        asm.extend(above_sp(4));
+        asm.push(assign(Dest::D, Comp::M));
+        asm.extend(jump_if("done", Jump::JEQ));
+        asm.extend(above_sp(4));
+        asm.push(assign(Dest::M, Comp::DMinusOne));
+        // r += r, plus the highest bit of |x|, then |x| += |x| and q += q
+        asm.extend(above_sp(2));
+        asm.extend([
+            assign(Dest::D, Comp::M),
+            assign(Dest::M, Comp::DPlusM),
+            at("R13"),
+            assign(Dest::D, Comp::M),
+        ]);
+        asm.extend(jump_if("shift", Jump::JGE));
+        asm.extend(above_sp(2));
+        asm.push(assign(Dest::M, Comp::MPlusOne));
+        asm.push(local_target("shift"));
+        asm.extend([
+            at("R13"),
+            assign(Dest::D, Comp::M),
+            assign(Dest::M, Comp::DPlusM),
+        ]);
+        asm.extend(above_sp(3));
+        asm.extend([assign(Dest::D, Comp::M), assign(Dest::M, Comp::DPlusM)]);
+        // If r >= |y| as unsigned numbers: r -= |y|, q += 1. |y| is at most
+        // 0x8000, so r is larger whenever its highest bit is set.
+        asm.extend(above_sp(2));
+        asm.push(assign(Dest::D, Comp::M));
+        asm.extend(jump_if("subtract", Jump::JLT));
+        asm.extend([at("R14"), assign(Dest::D, Comp::M)]);
+        asm.extend(jump_if("loop", Jump::JLT));
+        asm.extend(above_sp(2));
+        asm.extend([
+            assign(Dest::D, Comp::M),
+            at("R14"),
+            assign(Dest::D, Comp::DMinusM),
+        ]);
+        asm.extend(jump_if("loop", Jump::JLT));
+        asm.push(local_target("subtract"));
+        asm.extend([at("R14"), assign(Dest::D, Comp::M)]);
+        asm.extend(above_sp(2));
+        asm.push(assign(Dest::M, Comp::MMinusD));
+        asm.extend(above_sp(3));
+        asm.push(assign(Dest::M, Comp::MPlusOne));
+        asm.extend([at_local("loop"), jump(Comp::Zero, Jump::JMP)]);
+        asm.push(local_target("done"));
+        if *routine == SharedRoutine::Div {
+            // R13 = q, negated if x and y have different signs
+            asm.extend(above_sp(3));
+            asm.extend([
+                assign(Dest::D, Comp::M),
+                at("R13"),
+                assign(Dest::M, Comp::D),
+                at("SP"),
+                assign(Dest::A, Comp::MMinusOne),
+                assign(Dest::D, Comp::M),
+            ]);
+            asm.extend(jump_if("negative", Jump::JLT));
+            asm.extend([at("SP"), assign(Dest::A, Comp::M), assign(Dest::D, Comp::M)]);
+            asm.extend(jump_if("store", Jump::JGE));
+            asm.extend([at_local("negate"), jump(Comp::Zero, Jump::JMP)]);
+            asm.push(local_target("negative"));
+            asm.extend([at("SP"), assign(Dest::A, Comp::M), assign(Dest::D, Comp::M)]);
+            asm.extend(jump_if("store", Jump::JLT));
+        } else {
+            // R13 = r, negated if x is negative
+            asm.extend(above_sp(2));
+            asm.extend([
+                assign(Dest::D, Comp::M),
+                at("R13"),
+                assign(Dest::M, Comp::D),
+                at("SP"),
+                assign(Dest::A, Comp::MMinusOne),
+                assign(Dest::D, Comp::M),
+            ]);
+            asm.extend(jump_if("store", Jump::JGE));
+        }
+        asm.extend([
+            local_target("negate"),
+            at("R13"),
+            assign(Dest::M, Comp::NegM),
+        ]);
+        asm.push(local_target("store"));
+        asm.extend([
+            at("R13"),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
+        ]);
+        asm.extend([at_local("end"), jump(Comp::Zero, Jump::JMP)]);
+        asm.push(local_target("zero"));
+        if *routine == SharedRoutine::Div {
+            asm.extend([
+                at("SP"),
+                assign(Dest::A, Comp::MMinusOne),
+                assign(Dest::M, Comp::MinusOne),
+            ]);
+        }
+        asm.push(local_target("end"));
+        asm
+    }
+
+    fn function(name: &str, n_vars: u16) -> Vec<AsmInstruction> {
+        let mut asm = vec![target(name)];
+        for _ in 0..n_vars {
+            asm.extend([
+                at("SP"),
+                assign(Dest::M, Comp::MPlusOne),
+                assign(Dest::A, Comp::MMinusOne),
+                assign(Dest::M, Comp::Zero),
+            ]);
+        }
+        asm
+    }
+
+    fn push_frame() -> Vec<AsmInstruction> {
+        // Pushes the return address in D followed by the caller's segment
+        // pointers
+        let mut asm = push_d();
+        for seg_ptr in ["LCL", "ARG", "THIS", "THAT"] {
+            asm.extend([at(seg_ptr), assign(Dest::D, Comp::M)]);
+            asm.extend(push_d());
+        }
+        asm
+    }
+
+    fn call(function: &str, n_args: u16, return_label: &str) -> Vec<AsmInstruction> {
+        let mut asm = vec![at(return_label), assign(Dest::D, Comp::A)];
+        asm.extend(push_frame());
+        asm.extend([
+            // ARG = SP - 5 - n_args
+            at("SP"),
+            assign(Dest::D, Comp::M),
+            at_value(n_args + 5),
+            assign(Dest::D, Comp::DMinusA),
+            at("ARG"),
+            assign(Dest::M, Comp::D),
+            // LCL = SP
+            at("SP"),
+            assign(Dest::D, Comp::M),
+            at("LCL"),
+            assign(Dest::M, Comp::D),
+            at(function),
+            jump(Comp::Zero, Jump::JMP),
+            target(return_label),
+        ]);
+        asm
+    }
+
+    pub fn scoped_label(function_name: &str, label: &str) -> String {
+        // Labels are only visible inside the function that declares them, so
+        // they are emitted as Function$label in the generated assembly.
+        format!("{function_name}${label}")
+    }
+
+    fn generated_label(function_name: &str, kind: &str, n: usize) -> String {
+        // Labels that the translator generates inside a function, such as
+        // return addresses, are named Function$kind$n. VM names cannot
+        // contain a $, so these never collide with a scoped label.
+        format!("{function_name}${kind}${n}")
+    }
+
+    fn goto(label: &str) -> Vec<AsmInstruction> {
+        vec![at(label), jump(Comp::Zero, Jump::JMP)]
+    }
+
+    fn if_goto(label: &str) -> Vec<AsmInstruction> {
+        vec![
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at(label),
+            jump(Comp::D, Jump::JNE),
+        ]
+    }
+
+    fn skip_label(context: &mut Context) -> String {
+        let label = generated_label(&context.function_name, "skip", context.comparison_count);
+        context.comparison_count += 1;
+        label
+    }
+
+    fn counted_if_goto(label: &str, context: &mut Context) -> Vec<AsmInstruction> {
+        // The jump is inverted to skip over the counter when not taken
+        let skip_label = skip_label(context);
+        let mut asm = vec![
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at(&skip_label),
+            jump(Comp::D, Jump::JEQ),
+        ];
+        asm.extend(context.count(label));
+        asm.extend(goto(label));
+        asm.push(target(&skip_label));
+        asm
+    }
+
+    fn counted_compare_goto(
+        comparison: Comparison,
+        label: &str,
+        context: &mut Context,
+    ) -> Vec<AsmInstruction> {
+        let skip_label = skip_label(context);
+        let mut asm = compare_goto(comparison.negate(), &skip_label);
+        asm.extend(context.count(label));
+        asm.extend(goto(label));
+        asm.push(target(&skip_label));
+        asm
+    }
+
+    fn logical_comp(context: &mut Context, jmp_instr: Jump) -> Vec<AsmInstruction> {
+        // The result is set to true and only overwritten with false if the
+        // jump to the unique end label is not taken.
+        let end_label = generated_label(&context.function_name, "cmp", context.comparison_count);
+        context.comparison_count += 1;
+        vec![
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            assign(Dest::A, Comp::AMinusOne),
+            assign(Dest::D, Comp::MMinusD),
+            assign(Dest::M, Comp::MinusOne),
+            at(&end_label),
+            jump(Comp::D, jmp_instr),
+            at("SP"),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::Zero),
+            target(&end_label),
+        ]
+    }
+
+    fn basic_pop(segment: MemorySegment, idx: u16) -> Vec<AsmInstruction> {
         let seg_ptr = segment.seg_ptr();
         vec![
-            format!("@{idx}"),
-            String::from("D=A"),
-            format!("@{seg_ptr}"),
-            String::from("D=D+M"),
-            String::from("@SP"),
-            String::from("AM=M-1"),
-            String::from("D=D+M"),
-            String::from("A=D-M"),
-            String::from("M=D-A"),
+            at_value(idx),
+            assign(Dest::D, Comp::A),
+            at(seg_ptr),
+            assign(Dest::D, Comp::DPlusM),
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::DPlusM),
+            assign(Dest::A, Comp::DMinusM),
+            assign(Dest::M, Comp::DMinusA),
         ]
     }
 
-    fn pop_temp(idx: u16) -> Vec<String> {
+    fn pop_temp(idx: u16) -> Vec<AsmInstruction> {
         let mem_addr = TEMP_OFFSET + idx;
         vec![
-            String::from("@SP"),
-            String::from("AM=M-1"),
-            String::from("D=M"),
-            format!("@{mem_addr}"),
-            String::from("M=D"),
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at_value(mem_addr),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn pop_ptr(idx: u16) -> Vec<String> {
+    fn pop_ptr(idx: u16) -> Vec<AsmInstruction> {
         let seg_ptr = match idx {
             0 => MemorySegment::This.seg_ptr(),
             1 => MemorySegment::That.seg_ptr(),
             _ => panic!("pop pointer instruction must have index 0 or 1"),
         };
         vec![
-            String::from("@SP"),
-            String::from("AM=M-1"),
-            String::from("D=M"),
-            format!("@{seg_ptr}"),
-            String::from("M=D"),
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at(seg_ptr),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn pop_static(idx: u16, static_base: &str) -> Vec<String> {
+    fn pop_static(idx: u16, static_base: &str) -> Vec<AsmInstruction> {
         vec![
-            String::from("@SP"),
-            String::from("AM=M-1"),
-            String::from("D=M"),
-            format!("@{static_base}.{idx}"),
-            String::from("M=D"),
+            at("SP"),
+            assign(Dest::AM, Comp::MMinusOne),
+            assign(Dest::D, Comp::M),
+            at(&format!("{static_base}.{idx}")),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn push_const(idx: u16) -> Vec<String> {
+    fn push_const(idx: u16) -> Vec<AsmInstruction> {
         vec![
-            format!("@{idx}"),
-            String::from("D=A"),
-            String::from("@SP"),
-            String::from("M=M+1"),
-            String::from("A=M-1"),
-            String::from("M=D"),
+            at_value(idx),
+            assign(Dest::D, Comp::A),
+            at("SP"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn basic_push(segment: MemorySegment, idx: u16) -> Vec<String> {
+    fn basic_push(segment: MemorySegment, idx: u16) -> Vec<AsmInstruction> {
         let seg_ptr = segment.seg_ptr();
         vec![
-            format!("@{idx}"),
-            String::from("D=A"),
-            format!("@{seg_ptr}"),
-            String::from("A=D+M"),
-            String::from("D=M"),
-            String::from("@SP"),
-            String::from("M=M+1"),
-            String::from("A=M-1"),
-            String::from("M=D"),
+            at_value(idx),
+            assign(Dest::D, Comp::A),
+            at(seg_ptr),
+            assign(Dest::A, Comp::DPlusM),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn push_temp(idx: u16) -> Vec<String> {
+    fn push_temp(idx: u16) -> Vec<AsmInstruction> {
         let mem_addr = TEMP_OFFSET + idx;
         vec![
-            format!("@{mem_addr}"),
-            String::from("D=M"),
-            String::from("@SP"),
-            String::from("M=M+1"),
-            String::from("A=M-1"),
-            String::from("M=D"),
+            at_value(mem_addr),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn push_ptr(idx: u16) -> Vec<String> {
+    fn push_ptr(idx: u16) -> Vec<AsmInstruction> {
         let seg_ptr = match idx {
             0 => MemorySegment::This.seg_ptr(),
             1 => MemorySegment::That.seg_ptr(),
             _ => panic!("push pointer instruction must have index 0 or 1"),
         };
         vec![
-            format!("@{seg_ptr}"),
-            String::from("D=M"),
-            String::from("@SP"),
-            String::from("M=M+1"),
-            String::from("A=M-1"),
-            String::from("M=D"),
+            at(seg_ptr),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
         ]
     }
 
-    fn push_static(idx: u16, static_base: &str) -> Vec<String> {
+    fn push_static(idx: u16, static_base: &str) -> Vec<AsmInstruction> {
         vec![
-            format!("@{static_base}.{idx}"),
-            String::from("D=M"),
-            String::from("@SP"),
-            String::from("M=M+1"),
-            String::from("A=M-1"),
-            String::from("M=D"),
+            at(&format!("{static_base}.{idx}")),
+            assign(Dest::D, Comp::M),
+            at("SP"),
+            assign(Dest::M, Comp::MPlusOne),
+            assign(Dest::A, Comp::MMinusOne),
+            assign(Dest::M, Comp::D),
         ]
     }
 }
 
-fn read_lines(infile: &Path) -> Vec<String> {
-    // Reads the lines of the infile, while ignoring comments and whitespace.
-    read_to_string(infile)
-        .unwrap()
+fn read_lines(infile: &Path) -> Vec<(usize, String)> {
+    numbered_lines(&read_to_string(infile).unwrap())
+}
+
+fn numbered_lines(source: &str) -> Vec<(usize, String)> {
+    // The lines of the source along with their 1-based line numbers, while
+    // ignoring comments and whitespace.
+    source
         .lines()
-        .filter_map(|line| strip_comment_and_whitespace(line))
+        .enumerate()
+        .filter_map(|(idx, line)| strip_comment_and_whitespace(line).map(|line| (idx + 1, line)))
         .collect()
 }
 
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    // The trimmed code of a line and the text after its "//", if any
+    match line.split_once("//") {
+        Some((code, comment)) => (code.trim(), Some(comment)),
+        None => (line.trim(), None),
+    }
+}
+
 fn strip_comment_and_whitespace(line: &str) -> Option<String> {
-    let line = line.split("//").next().unwrap().trim();
+    let (line, _) = split_comment(line);
     if line.is_empty() {
-        return None;
+        None
     } else {
-        return Some(line.to_owned());
+        Some(line.to_owned())
     }
 }
 
 pub fn write_lines(outfile: &PathBuf, asm_output: &[String]) {
-    write(outfile, asm_output.join("\n")).expect(&format!(
-        "Failed to write hack assembly output to {}",
-        outfile.to_str().unwrap()
-    ));
+    write(outfile, asm_output.join("\n")).unwrap_or_else(|_| {
+        panic!(
+            "Failed to write hack assembly output to {}",
+            outfile.to_str().unwrap()
+        )
+    });
+}
+
+// Where --checked code stores the error code of a failed check before halting:
+// 1 for stack overflow, 2 for stack underflow, 3 and 4 for this and that
+// accesses outside the heap and screen
+pub const TRAP_ERROR_ADDRESS: u16 = 15;
+
+#[derive(Debug)]
+pub struct TranslationOptions {
+    // Prepend code that sets SP = 256 and calls Sys.init
+    pub bootstrap: bool,
+    // Replace inline comparison, call and return code with jumps to shared
+    // routines
+    pub optimize_size: bool,
+    // Run the VM-level optimizer before translation
+    pub optimize: bool,
+    // With optimize, inline calls to leaf functions of at most this many VM
+    // commands; 0 disables inlining
+    pub inline_threshold: usize,
+    // Precede each VM command's assembly with a comment showing its source
+    pub annotate: bool,
+    // Check stack bounds and this/that accesses at runtime, halting with an
+    // error code in TRAP_ERROR_ADDRESS when a check fails
+    pub checked: bool,
+    // Leave out functions that cannot be reached from Sys.init
+    pub remove_unused: bool,
+    // Count function entries and taken branches in RAM from COUNTER_BASE on.
+    // Calls are not inlined, so that every call enters its function.
+    pub instrument: bool,
+    // Allocate the statics in the translator, each file's in one block,
+    // rather than leaving it to the assembler
+    pub pack_statics: bool,
+}
+
+impl Default for TranslationOptions {
+    fn default() -> Self {
+        TranslationOptions {
+            bootstrap: true,
+            optimize_size: false,
+            optimize: false,
+            inline_threshold: 8,
+            annotate: false,
+            checked: false,
+            remove_unused: false,
+            instrument: false,
+            pack_statics: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct OperationSize {
+    pub count: usize,
+    pub inline_words: usize,
+    pub shared_words: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct SizeReport {
+    // ROM words used by each operation type when expanded inline versus when
+    // using a shared routine (including the routine itself)
+    operations: BTreeMap<translator::SharedRoutine, OperationSize>,
+    // Words spent jumping over the shared routines
+    pub overhead_words: usize,
+}
+
+impl SizeReport {
+    pub fn operations(&self) -> impl Iterator<Item = (&str, &OperationSize)> {
+        self.operations
+            .iter()
+            .map(|(routine, size)| (routine.name(), size))
+    }
+
+    pub fn words_saved(&self) -> isize {
+        self.operations
+            .values()
+            .map(|size| size.inline_words as isize - size.shared_words as isize)
+            .sum::<isize>()
+            - self.overhead_words as isize
+    }
+}
+
+fn word_count(asm: &[AsmInstruction]) -> usize {
+    asm.iter()
+        .filter(|instruction| instruction.is_rom_word())
+        .count()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VmError {
+    pub file: String,
+    pub line: usize,
+    pub reason: String,
 }
 
-pub fn translate(infile: &Path) -> Vec<String> {
-    let lines = read_lines(infile);
-    let static_base = infile.file_stem().unwrap().to_str().unwrap();
-    let mut asm_output: Vec<String> = Vec::new();
-    for line in lines {
-        let instruction = parser::parse_instruction(&line);
-        let asm = translator::translate(instruction, asm_output.len(), static_base);
-        asm_output.extend(asm);
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.reason)
     }
-    asm_output
+}
+
+impl Error for VmError {}
+
+fn parse_lines(
+    file_name: &str,
+    lines: &[(usize, String)],
+) -> Result<Vec<parser::ParsedVMInstruction>, Vec<VmError>> {
+    // Parses every line, collecting all errors rather than stopping at the
+    // first one.
+    let mut instructions = Vec::new();
+    let mut errors = Vec::new();
+    for (line_number, line) in lines {
+        match parser::parse_instruction(line) {
+            Ok(instruction) => instructions.push(instruction),
+            Err(reason) => errors.push(VmError {
+                file: file_name.to_owned(),
+                line: *line_number,
+                reason,
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        Err(errors)
+    }
+}
+
+type Source = (Vec<(usize, String)>, Vec<parser::ParsedVMInstruction>);
+
+fn read_source(infile: &Path) -> Result<Source, Vec<VmError>> {
+    // The numbered lines of a .vm file and its instructions. A .vmb file has
+    // no text, so its lines are its instructions written as VM commands.
+    let file_name = infile.file_name().unwrap().to_str().unwrap();
+    if !bytecode::is_bytecode(infile) {
+        let lines = read_lines(infile);
+        let instructions = parse_lines(file_name, &lines)?;
+        return Ok((lines, instructions));
+    }
+    let instructions = bytecode::read_file(infile).map_err(|reason| {
+        vec![VmError {
+            file: file_name.to_owned(),
+            line: 0,
+            reason,
+        }]
+    })?;
+    Ok(instructions
+        .into_iter()
+        .map(|(line_number, instruction)| ((line_number, instruction.to_string()), instruction))
+        .unzip())
+}
+
+/// Parses a .vm or .vmb file, pairing each instruction with its 1-based line
+/// number.
+pub fn parse_file(
+    infile: &Path,
+) -> Result<Vec<(usize, parser::ParsedVMInstruction)>, Vec<VmError>> {
+    let (lines, instructions) = read_source(infile)?;
+    Ok(lines
+        .into_iter()
+        .map(|(line_number, _)| line_number)
+        .zip(instructions)
+        .collect())
+}
+
+pub fn parse_str(
+    file_name: &str,
+    source: &str,
+) -> Result<Vec<(usize, parser::ParsedVMInstruction)>, Vec<VmError>> {
+    // Like parse_file, for VM code that is already in memory. The file name
+    // is only used in errors.
+    let lines = numbered_lines(source);
+    let instructions = parse_lines(file_name, &lines)?;
+    Ok(lines
+        .into_iter()
+        .map(|(line_number, _)| line_number)
+        .zip(instructions)
+        .collect())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TranslationOutput {
+    pub instructions: Vec<AsmInstruction>,
+    // The VM command each assembly instruction was generated from, if any
+    pub source_map: Vec<Option<SourceLocation>>,
+    pub size_report: SizeReport,
+    pub call_graph: CallGraph,
+    // Functions left out by remove_unused
+    pub removed_functions: Vec<String>,
+    // Execution counters, with instrument
+    pub counters: Counters,
+    // Every variable in the static segment, with the address it gets
+    pub statics: StaticLayout,
+}
+
+impl TranslationOutput {
+    fn mapped_instructions(&self) -> impl Iterator<Item = (usize, usize, &SourceLocation)> {
+        // The assembly line index, ROM address and VM source location of
+        // every instruction generated from a VM command
+        self.instructions
+            .iter()
+            .zip(&self.source_map)
+            .enumerate()
+            .filter(|(_, (instruction, _))| instruction.is_rom_word())
+            .enumerate()
+            .filter_map(|(rom_address, (idx, (_, location)))| {
+                location
+                    .as_ref()
+                    .map(|location| (idx, rom_address, location))
+            })
+    }
+
+    pub fn source_map_lines(&self) -> Vec<String> {
+        // One line per mapped instruction: the 1-based assembly line, its ROM
+        // address and the VM source location
+        let mut lines = vec![String::from("// asm_line rom_address vm_source")];
+        lines.extend(
+            self.mapped_instructions()
+                .map(|(idx, rom_address, location)| {
+                    format!("{} {rom_address} {location}", idx + 1)
+                }),
+        );
+        lines
+    }
+
+    pub fn asm(&self) -> Vec<String> {
+        // The assembly as lines of text
+        self.instructions
+            .iter()
+            .map(AsmInstruction::to_string)
+            .collect()
+    }
+
+    pub fn assemble(&self) -> Assembly {
+        assemble_lines(&self.asm())
+    }
+
+    pub fn debug_map_lines(&self, assembly: &Assembly) -> Vec<String> {
+        // The VM source location of each ROM address, followed by the
+        // assembler's symbols: labels with their ROM address and variables
+        // (including statics) with their RAM address
+        let mut lines = vec![String::from("// rom_address vm_source")];
+        lines.extend(
+            self.mapped_instructions()
+                .map(|(_, rom_address, location)| format!("{rom_address} {location}")),
+        );
+        lines.push(String::from("// label rom_address"));
+        lines.extend(
+            assembly
+                .labels
+                .iter()
+                .map(|(label, address)| format!("{label} {address}")),
+        );
+        lines.push(String::from("// variable ram_address"));
+        lines.extend(
+            assembly
+                .variables
+                .iter()
+                .map(|(variable, address)| format!("{variable} {address}")),
+        );
+        // Packed statics are addressed directly, so the assembler never sees
+        // their symbols
+        if self.statics.is_packed() {
+            lines.extend(
+                self.statics
+                    .variables()
+                    .iter()
+                    .filter(|variable| variable.file_static().is_some())
+                    .map(|variable| format!("{} {}", variable.symbol, variable.address)),
+            );
+        }
+        lines
+    }
+}
+
+pub fn vm_files(infile_or_directory: &Path) -> Vec<PathBuf> {
+    // Returns the infile itself, or every .vm and .vmb file in the directory
+    // in a stable order. A .vmb file is left out if the .vm file it was
+    // encoded from is present.
+    if !infile_or_directory.is_dir() {
+        return vec![infile_or_directory.to_path_buf()];
+    }
+    let paths: Vec<PathBuf> = infile_or_directory
+        .read_dir()
+        .unwrap()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    let is_text = |path: &Path| path.extension().is_some_and(|ext| ext == "vm");
+    let mut vm_files: Vec<PathBuf> = paths
+        .iter()
+        .filter(|path| {
+            is_text(path)
+                || bytecode::is_bytecode(path) && !paths.contains(&path.with_extension("vm"))
+        })
+        .cloned()
+        .collect();
+    vm_files.sort();
+    vm_files
+}
+
+pub fn translate_files(
+    infiles: &[PathBuf],
+    options: &TranslationOptions,
+) -> Result<TranslationOutput, Vec<VmError>> {
+    let mut errors = Vec::new();
+    let mut sources = Vec::new();
+    for infile in infiles {
+        match read_source(infile) {
+            Ok((lines, instructions)) => sources.push((infile.clone(), lines, instructions)),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    translate_sources(sources, options)
+}
+
+pub fn translate_program(
+    files: &[(&str, &str)],
+    options: &TranslationOptions,
+) -> Result<Vec<AsmInstruction>, Vec<VmError>> {
+    // Translates VM code that is already in memory, given as the name of
+    // each file (such as Main.vm, which names its statics) and its text
+    let mut errors = Vec::new();
+    let mut sources = Vec::new();
+    for &(file_name, source) in files {
+        let lines = numbered_lines(source);
+        match parse_lines(file_name, &lines) {
+            Ok(instructions) => sources.push((PathBuf::from(file_name), lines, instructions)),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(translate_sources(sources, options)?.instructions)
+}
+
+// A file with its numbered source lines and their instructions
+type ParsedSource = (
+    PathBuf,
+    Vec<(usize, String)>,
+    Vec<parser::ParsedVMInstruction>,
+);
+
+fn translate_sources(
+    sources: Vec<ParsedSource>,
+    options: &TranslationOptions,
+) -> Result<TranslationOutput, Vec<VmError>> {
+    // Each instruction is paired with the index of its line
+    let (sources, mut files): (Vec<_>, Vec<Vec<_>>) = sources
+        .into_iter()
+        .map(|(infile, lines, instructions)| {
+            let instructions = instructions.into_iter().map(Instruction::from);
+            ((infile, lines), instructions.zip(0..).collect())
+        })
+        .unzip();
+    let errors = undefined_calls(&sources, &files);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    if options.optimize && options.inline_threshold > 0 && !options.instrument {
+        files = inliner::inline_calls(files, options.inline_threshold);
+    }
+    let mut call_graph = CallGraph::default();
+    for ((infile, _), instructions) in sources.iter().zip(&files) {
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        call_graph.add_file(
+            file_name,
+            instructions
+                .iter()
+                .filter_map(|(instruction, _)| instruction.command()),
+        );
+    }
+    let removed_functions: Vec<String> = if options.remove_unused {
+        call_graph
+            .unreachable()
+            .into_iter()
+            .map(String::from)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Each instruction is paired with its origins: the indices of the lines
+    // it was translated from
+    let files: Vec<Vec<(Instruction, Vec<usize>)>> = files
+        .into_iter()
+        .map(|instructions| {
+            let (instructions, line_indices): (Vec<_>, Vec<usize>) =
+                remove_functions(instructions, &removed_functions)
+                    .into_iter()
+                    .unzip();
+            let instructions = if options.optimize {
+                optimizer::optimize_with_origins(instructions)
+            } else {
+                instructions
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, instruction)| (instruction, vec![idx]))
+                    .collect()
+            };
+            instructions
+                .into_iter()
+                .map(|(instruction, origins)| {
+                    let mut origins: Vec<usize> =
+                        origins.iter().map(|&idx| line_indices[idx]).collect();
+                    // Inlined commands share the line of their call
+                    origins.dedup();
+                    (instruction, origins)
+                })
+                .collect()
+        })
+        .collect();
+    let counters = if options.instrument {
+        Some(allocate_counters(&sources, &files)?)
+    } else {
+        None
+    };
+
+    let mut program: Vec<AsmInstruction> = Vec::new();
+    let mut program_sources: Vec<Option<SourceLocation>> = Vec::new();
+    let mut report = SizeReport::default();
+    let mut shared_routines = BTreeSet::new();
+    let mut uses_tail_calls = false;
+    for ((infile, lines), instructions) in sources.into_iter().zip(files) {
+        // Each file gets its own static namespace, named after the file.
+        let static_base = infile.file_stem().unwrap().to_str().unwrap();
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        let mut context =
+            translator::Context::new(static_base, options.optimize_size, options.checked);
+        context.counters = counters.as_ref();
+        for (instruction, origins) in instructions {
+            let routine = translator::SharedRoutine::for_instruction(&instruction)
+                .filter(|routine| options.optimize_size || routine.always_shared());
+            uses_tail_calls |= matches!(instruction, Instruction::TailCall { .. });
+            // Routines without an inline form are left out of the size report
+            let reported =
+                routine.filter(|routine| options.optimize_size && !routine.always_shared());
+            let inline_words = match reported {
+                Some(_) => {
+                    let mut inline_context = context.clone();
+                    inline_context.optimize_size = false;
+                    word_count(&translator::translate(
+                        instruction.clone(),
+                        &mut inline_context,
+                    ))
+                }
+                _ => 0,
+            };
+            let asm = translator::translate(instruction, &mut context);
+            shared_routines.extend(routine);
+            if let Some(routine) = reported {
+                let size = report.operations.entry(routine).or_default();
+                size.count += 1;
+                size.inline_words += inline_words;
+                size.shared_words += word_count(&asm);
+            }
+            let location = SourceLocation {
+                file: file_name.to_owned(),
+                line: lines[origins[0]].0,
+            };
+            if options.annotate {
+                for &origin in &origins {
+                    let (line_number, line) = &lines[origin];
+                    program.push(AsmInstruction::Comment(format!(
+                        " {file_name}:{line_number}: {line}"
+                    )));
+                    program_sources.push(Some(location.clone()));
+                }
+            }
+            program_sources.extend(asm.iter().map(|_| Some(location.clone())));
+            program.extend(asm);
+        }
+    }
+    let mut asm_output: Vec<AsmInstruction> = Vec::new();
+    if options.bootstrap {
+        asm_output.extend(translator::bootstrap());
+    }
+    if !shared_routines.is_empty() {
+        let routines: Vec<translator::SharedRoutine> = shared_routines.into_iter().collect();
+        let routines_asm = translator::skip_shared_routines(&routines);
+        report.overhead_words = word_count(&routines_asm);
+        for routine in &routines {
+            let routine_words = word_count(&routine.asm());
+            if let Some(size) = report.operations.get_mut(routine) {
+                size.shared_words += routine_words;
+            }
+            report.overhead_words -= routine_words;
+        }
+        asm_output.extend(routines_asm);
+    }
+    if uses_tail_calls {
+        asm_output.extend(translator::tail_call_routine());
+    }
+    if options.checked {
+        asm_output.extend(translator::trap_routines());
+    }
+    let mut source_map = vec![None; asm_output.len()];
+    asm_output.extend(program);
+    source_map.extend(program_sources);
+    let statics = allocate_statics(&mut asm_output, &source_map, options.pack_statics)?;
+    Ok(TranslationOutput {
+        instructions: asm_output,
+        source_map,
+        size_report: report,
+        call_graph,
+        removed_functions,
+        counters: counters.unwrap_or_default(),
+        statics,
+    })
+}
+
+fn undefined_calls(
+    sources: &[(PathBuf, Vec<(usize, String)>)],
+    files: &[Vec<(Instruction, usize)>],
+) -> Vec<VmError> {
+    // Calls to functions that no file defines. The assembler would silently
+    // allocate their names as variables.
+    let defined: HashSet<&str> = files
+        .iter()
+        .flatten()
+        .filter_map(|(instruction, _)| match instruction.command() {
+            Some(parser::ParsedVMInstruction::Function { name, .. }) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut errors = Vec::new();
+    for ((infile, lines), instructions) in sources.iter().zip(files) {
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        for (instruction, line_idx) in instructions {
+            if let Some(parser::ParsedVMInstruction::Call { function, .. }) = instruction.command()
+            {
+                if !defined.contains(function.as_str()) {
+                    errors.push(VmError {
+                        file: file_name.to_owned(),
+                        line: lines[*line_idx].0,
+                        reason: format!("Undefined function: {function}"),
+                    });
+                }
+            }
+        }
+    }
+    errors
+}
+
+fn allocate_statics(
+    asm: &mut [AsmInstruction],
+    source_map: &[Option<SourceLocation>],
+    packed: bool,
+) -> Result<StaticLayout, Vec<VmError>> {
+    // Lays out the variables the assembler allocates, and fails if they do
+    // not fit in the static segment instead of letting the last ones overlap
+    // the stack
+    let lines: Vec<String> = asm.iter().map(AsmInstruction::to_string).collect();
+    let variables = assemble_lines(&lines).variables;
+    let layout = StaticLayout::new(variables.iter().map(|(symbol, _)| symbol.as_str()), packed);
+    // Only the bootstrap's symbols have no source, and they come first
+    let overflow = layout.overflow().iter().find_map(|variable| {
+        let at = AsmInstruction::Symbol(variable.symbol.clone());
+        asm.iter()
+            .zip(source_map)
+            .find_map(|(instruction, location)| location.as_ref().filter(|_| *instruction == at))
+            .map(|location| (variable, location))
+    });
+    if let Some((variable, location)) = overflow {
+        return Err(vec![VmError {
+            file: location.file.clone(),
+            line: location.line,
+            reason: format!(
+                "Too many statics: {} needs RAM[{}], past the static segment RAM[{STATIC_START}..{}] ({} variables in total)",
+                variable.symbol,
+                variable.address,
+                STATIC_END - 1,
+                layout.variables().len()
+            ),
+        }]);
+    }
+    if packed {
+        for instruction in asm.iter_mut() {
+            let packed_static = match instruction {
+                AsmInstruction::Symbol(symbol) => layout
+                    .address(symbol)
+                    .and_then(|address| layout.variable_at(address))
+                    .filter(|variable| variable.file_static().is_some()),
+                _ => None,
+            };
+            if let Some(variable) = packed_static {
+                *instruction = AsmInstruction::Address(variable.address);
+            }
+        }
+    }
+    Ok(layout)
+}
+
+fn allocate_counters(
+    sources: &[(PathBuf, Vec<(usize, String)>)],
+    files: &[Vec<(Instruction, Vec<usize>)>],
+) -> Result<Counters, Vec<VmError>> {
+    // A counter for every function and every branch target, in the order
+    // they appear in the program
+    let mut counters = Counters::default();
+    for ((infile, lines), instructions) in sources.iter().zip(files) {
+        let file_name = infile.file_name().unwrap().to_str().unwrap();
+        let mut function_name = infile.file_stem().unwrap().to_str().unwrap().to_owned();
+        for (instruction, origins) in instructions {
+            let (kind, name) = match instruction {
+                Instruction::Command(parser::ParsedVMInstruction::Function { name, .. }) => {
+                    function_name = name.clone();
+                    (CounterKind::Function, name.clone())
+                }
+                Instruction::Command(
+                    parser::ParsedVMInstruction::Goto { label }
+                    | parser::ParsedVMInstruction::IfGoto { label },
+                )
+                | Instruction::CompareGoto { label, .. } => (
+                    CounterKind::Branch,
+                    translator::scoped_label(&function_name, label),
+                ),
+                _ => continue,
+            };
+            counters.add(kind, &name).map_err(|reason| {
+                vec![VmError {
+                    file: file_name.to_owned(),
+                    line: lines[origins[0]].0,
+                    reason,
+                }]
+            })?;
+        }
+    }
+    Ok(counters)
+}
+
+fn remove_functions(
+    instructions: Vec<(Instruction, usize)>,
+    removed: &[String],
+) -> Vec<(Instruction, usize)> {
+    // Drops the bodies of the removed functions
+    let mut in_removed_function = false;
+    instructions
+        .into_iter()
+        .filter(|(instruction, _)| {
+            if let Some(parser::ParsedVMInstruction::Function { name, .. }) = instruction.command()
+            {
+                in_removed_function = removed.contains(name);
+            }
+            !in_removed_function
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parser::{parse_instruction, ParsedVMInstruction};
-    use super::MemorySegment;
+    use super::parser::{parse_instruction, ParsedVMInstruction, MAX_STATICS};
+    use super::{
+        parse_file, parse_lines, parse_str, translate_files, translate_program, vm_files,
+        word_count, MemorySegment, SourceLocation, TranslationOptions, TranslationOutput,
+        TRAP_ERROR_ADDRESS,
+    };
+    use crate::asm::AsmInstruction;
+    use crate::bytecode;
+    use crate::test_util::{
+        inline_slots, observable_ram, run_test_script, run_vm_files, HackCpu, TempDir,
+    };
+
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs::{read_to_string, remove_file, write};
+    use std::mem::discriminant;
+
+    fn translate_and_run_test_script(test_dir: &str, name: &str, bootstrap: bool) {
+        // Runs the test program in every translation mode. Optimized output
+        // must also leave the same observable state as unoptimized output,
+        // apart from the inline slots.
+        let test_dir = env::current_dir().unwrap().join(test_dir);
+        for optimize_size in [false, true] {
+            let mut reference_ram = None;
+            for (optimize, checked) in [(false, false), (true, false), (false, true), (true, true)]
+            {
+                let options = TranslationOptions {
+                    bootstrap,
+                    optimize_size,
+                    optimize,
+                    checked,
+                    ..Default::default()
+                };
+                let asm_output = translate_files(&vm_files(&test_dir), &options)
+                    .unwrap()
+                    .asm();
+                let cpu = run_test_script(&test_dir, name, &asm_output);
+                let ram = observable_ram(&cpu.ram, false);
+                match &reference_ram {
+                    None => reference_ram = Some(ram),
+                    Some(reference_ram) => {
+                        let inline_slots = inline_slots(&asm_output);
+                        let differences: Vec<_> = ram
+                            .iter()
+                            .zip(reference_ram)
+                            .filter(|(actual, expected)| {
+                                actual != expected && !inline_slots.contains(&actual.0)
+                            })
+                            .collect();
+                        assert!(
+                            ram.len() == reference_ram.len() && differences.is_empty(),
+                            "{name}: RAM differs: {differences:?}"
+                        )
+                    }
+                }
+            }
+        }
+    }
 
     #[test]
     fn test_parse_valid_instruction() {
@@ -379,23 +2455,843 @@ mod tests {
             ),
             ("add", ParsedVMInstruction::Add),
             ("sub", ParsedVMInstruction::Sub),
+            (
+                "label LOOP_START",
+                ParsedVMInstruction::Label {
+                    label: String::from("LOOP_START"),
+                },
+            ),
+            (
+                "goto END",
+                ParsedVMInstruction::Goto {
+                    label: String::from("END"),
+                },
+            ),
+            (
+                "if-goto LOOP_START",
+                ParsedVMInstruction::IfGoto {
+                    label: String::from("LOOP_START"),
+                },
+            ),
+            (
+                "function Main.fibonacci 2",
+                ParsedVMInstruction::Function {
+                    name: String::from("Main.fibonacci"),
+                    n_vars: 2,
+                },
+            ),
+            (
+                "call Main.fibonacci 1",
+                ParsedVMInstruction::Call {
+                    function: String::from("Main.fibonacci"),
+                    n_args: 1,
+                },
+            ),
+            ("return", ParsedVMInstruction::Return),
         ];
 
         for test in test_cases {
             let parsed_instruction = parse_instruction(test.0);
-            assert_eq!(parsed_instruction, test.1);
+            assert_eq!(parsed_instruction, Ok(test.1));
         }
     }
 
     #[test]
-    #[should_panic]
     fn test_parse_invalid_instruction() {
-        let _parsed_instruction = parse_instruction("gte");
+        let test_cases = vec![
+            ("gte", "Invalid instruction type: gte"),
+            (
+                "push constant",
+                "push expects 2 argument(s) but 1 were given",
+            ),
+            (
+                "push constant 1 2",
+                "push expects 2 argument(s) but 3 were given",
+            ),
+            ("add 1", "add expects 0 argument(s) but 1 were given"),
+            ("pop constant 0", "Cannot pop to the constant segment"),
+            ("push heap 0", "Invalid memory segment: heap"),
+            (
+                "push constant 32768",
+                "Invalid constant index 32768: must be between 0 and 32767",
+            ),
+            (
+                "push constant -1",
+                "Invalid constant index -1: must be between 0 and 32767",
+            ),
+            (
+                "pop temp 8",
+                "Invalid temp index 8: must be between 0 and 7",
+            ),
+            (
+                "push pointer 2",
+                "Invalid pointer index 2: must be between 0 and 1",
+            ),
+            (
+                "pop static 240",
+                "Invalid static index 240: must be between 0 and 239",
+            ),
+            ("goto 1LOOP", "Invalid name: 1LOOP"),
+            ("call Main.main x", "Invalid count: x"),
+        ];
+
+        for test in test_cases {
+            assert_eq!(parse_instruction(test.0), Err(String::from(test.1)));
+        }
+    }
+
+    #[test]
+    fn test_parse_whitespace() {
+        let parsed_instruction = parse_instruction("push\tlocal   3");
+        let expected_instruction = ParsedVMInstruction::Push {
+            segment: MemorySegment::Local,
+            idx: 3,
+        };
+        assert_eq!(parsed_instruction, Ok(expected_instruction));
+    }
+
+    #[test]
+    fn test_parse_lines_reports_all_errors() {
+        let lines = vec![
+            (1, String::from("push constant 1")),
+            (2, String::from("pop constant 0")),
+            (4, String::from("add")),
+            (5, String::from("push pointer 2")),
+        ];
+        let errors = parse_lines("Main.vm", &lines).unwrap_err();
+        let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Main.vm:2: Cannot pop to the constant segment",
+                "Main.vm:5: Invalid pointer index 2: must be between 0 and 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_functions() {
+        // A call must reach a function defined in one of the files
+        let test_dir = TempDir::new("vm_translator_rs_undefined_functions");
+        write(
+            test_dir.join("Main.vm"),
+            "function Main.main 0\ncall Math.abs 1\ncall Main.main 0\ncall Main.draw 0",
+        )
+        .unwrap();
+        let errors: Vec<String> = translate_files(&vm_files(&test_dir), &Default::default())
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "Main.vm:2: Undefined function: Math.abs",
+                "Main.vm:4: Undefined function: Main.draw",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generated_labels() {
+        // User labels may look like the labels the translator generates
+        let program = [
+            "function Sys.init 0",
+            "call Main.seven 0",
+            "pop temp 0",
+            "label ret.0",
+            "goto cmp.0",
+            "label skip.0",
+            "push temp 1",
+            "push temp 1",
+            "mul",
+            "pop temp 2",
+            "goto mul.0.loop",
+            "label cmp.0",
+            "push temp 1",
+            "push constant 1",
+            "add",
+            "pop temp 1",
+            "push temp 1",
+            "push constant 3",
+            "lt",
+            "if-goto ret.0",
+            "goto skip.0",
+            "label mul.0.loop",
+            "label END",
+            "goto END",
+            "function Main.seven 0",
+            "push constant 7",
+            "return",
+        ]
+        .join("\n");
+        for (optimize, optimize_size, instrument) in [
+            (false, false, false),
+            (true, false, false),
+            (false, true, false),
+            (false, false, true),
+        ] {
+            let options = TranslationOptions {
+                optimize,
+                optimize_size,
+                instrument,
+                ..Default::default()
+            };
+            let instructions = translate_program(&[("Main.vm", &program)], &options).unwrap();
+            let asm: Vec<String> = instructions.iter().map(ToString::to_string).collect();
+            let mut cpu = HackCpu::new(&asm);
+            cpu.run(2000);
+            assert_eq!(cpu.ram[5..8], [7, 3, 9]);
+        }
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_SimpleAdd() {
+        translate_and_run_test_script("../StackArithmetic/SimpleAdd", "SimpleAdd", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_StackTest() {
+        translate_and_run_test_script("../StackArithmetic/StackTest", "StackTest", false);
+    }
+
+    #[test]
+    fn test_comparison_is_position_independent() {
+        // Comparisons must keep working when other code is placed before them.
+        let test_dir = env::current_dir()
+            .unwrap()
+            .join("../StackArithmetic/StackTest");
+        let options = TranslationOptions {
+            bootstrap: false,
+            ..Default::default()
+        };
+        let mut asm_output: Vec<String> = ["@0", "D=A"]
+            .repeat(7)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        asm_output.extend(
+            translate_files(&vm_files(&test_dir), &options)
+                .unwrap()
+                .asm(),
+        );
+        run_test_script(&test_dir, "StackTest", &asm_output);
+    }
+
+    #[test]
+    fn test_optimize_size_report() {
+        let test_dir = env::current_dir()
+            .unwrap()
+            .join("../../08/FunctionCalls/FibonacciElement");
+        let options = TranslationOptions {
+            optimize_size: true,
+            ..Default::default()
+        };
+        let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+        let (asm_output, report) = (output.instructions, output.size_report);
+        let inline_output = translate_files(&vm_files(&test_dir), &TranslationOptions::default())
+            .unwrap()
+            .instructions;
+        let operations: Vec<(&str, usize)> = report
+            .operations()
+            .map(|(name, size)| (name, size.count))
+            .collect();
+        assert_eq!(operations, vec![("lt", 1), ("call", 3), ("return", 2)]);
+        assert_eq!(
+            report.words_saved(),
+            word_count(&inline_output) as isize - word_count(&asm_output) as isize
+        );
+    }
+
+    #[test]
+    fn test_annotate() {
+        let test_dir = env::current_dir()
+            .unwrap()
+            .join("../../08/ProgramFlow/BasicLoop");
+        let options = TranslationOptions {
+            bootstrap: false,
+            annotate: true,
+            ..Default::default()
+        };
+        let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+        assert_eq!(output.asm()[0], "// BasicLoop.vm:9: push constant 0");
+        assert_eq!(output.instructions.len(), output.source_map.len());
+        let location = SourceLocation {
+            file: String::from("BasicLoop.vm"),
+            line: 9,
+        };
+        assert_eq!(output.source_map[1], Some(location));
+        assert_eq!(output.source_map_lines()[1], "2 0 BasicLoop.vm:9");
+        // Comments must not change the program
+        run_test_script(&test_dir, "BasicLoop", &output.asm());
+    }
+
+    #[test]
+    fn test_checked_traps() {
+        let test_cases = [
+            // Popping more values than were pushed
+            ("push constant 1\nadd", false, 2),
+            // Popping into the locals of the current function
+            ("function Sys.init 2\npop local 0", true, 2),
+            // Unbounded recursion
+            ("function Sys.init 0\ncall Sys.init 0", true, 1),
+            ("push constant 0\npop pointer 0\npush this 0", false, 3),
+            (
+                "push constant 30000\npop pointer 1\npush constant 1\npop that 0",
+                false,
+                4,
+            ),
+            // Heap and screen accesses are allowed
+            (
+                "push constant 24575\npop pointer 1\npush that 0\npush constant 2048\npop pointer 0\npop this 0",
+                false,
+                0,
+            ),
+        ];
+        for (program, bootstrap, error_code) in test_cases {
+            let options = [false, true].map(|optimize| TranslationOptions {
+                bootstrap,
+                optimize,
+                checked: true,
+                ..Default::default()
+            });
+            run_vm_files(
+                "vm_translator_rs_checked",
+                &[("Test.vm", program)],
+                &options,
+                20000,
+                |options, _, cpu| {
+                    assert_eq!(
+                        cpu.ram[TRAP_ERROR_ADDRESS as usize], error_code,
+                        "{program} ({options:?})"
+                    )
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn test_remove_unused() {
+        let main = "function Main.unused 0\npush constant 1\nreturn\n\
+                    function Main.main 0\npush constant 7\npop static 0\npush constant 0\nreturn";
+        let sys = "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END";
+        let options = TranslationOptions {
+            annotate: true,
+            remove_unused: true,
+            ..Default::default()
+        };
+        run_vm_files(
+            "vm_translator_rs_remove_unused",
+            &[("Main.vm", main), ("Sys.vm", sys)],
+            &[options],
+            1000,
+            |_, output, cpu| {
+                assert_eq!(output.removed_functions, ["Main.unused"]);
+                assert!(!output
+                    .instructions
+                    .contains(&AsmInstruction::Label(String::from("Main.unused"))));
+                // Annotations still refer to the original source lines
+                assert!(output
+                    .asm()
+                    .contains(&String::from("// Main.vm:5: push constant 7")));
+                assert_eq!(cpu.ram[16], 7);
+            },
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_extensions() {
+        // Each operation on pairs of edge case values, with the results stored
+        // from RAM[3000] on. The programs are kept separate to fit into ROM.
+        let values: [i16; 10] = [0, 1, -1, 3, -7, 255, 12345, -12345, 32767, -32768];
+        type Operation = fn(i16, i16) -> i16;
+        let operations: [(&str, Operation); 5] = [
+            ("mul", i16::wrapping_mul),
+            ("div", |x, y| if y == 0 { -1 } else { x.wrapping_div(y) }),
+            ("mod", |x, y| if y == 0 { x } else { x.wrapping_rem(y) }),
+            ("shl", |x, y| {
+                (x as u16).checked_shl(y as u16 as u32).unwrap_or(0) as i16
+            }),
+            ("shr", |x, y| {
+                (x as u16).checked_shr(y as u16 as u32).unwrap_or(0) as i16
+            }),
+        ];
+        let push = |value: i16| match value {
+            -32768 => String::from("push constant 32767\nneg\npush constant 1\nsub"),
+            _ if value < 0 => format!("push constant {}\nneg", -value),
+            _ => format!("push constant {value}"),
+        };
+        let options = [
+            (false, false, false),
+            (false, false, true),
+            (false, true, false),
+            (false, true, true),
+            (true, false, false),
+            (true, true, false),
+        ]
+        .map(|(optimize, optimize_size, checked)| TranslationOptions {
+            bootstrap: false,
+            optimize,
+            optimize_size,
+            checked,
+            ..Default::default()
+        });
+        for (name, operation) in operations {
+            let mut program = vec![String::from("push constant 3000\npop pointer 1")];
+            let mut expected = Vec::new();
+            for x in values {
+                for y in values.iter().copied().chain([2, 15, 16, 17]) {
+                    program.extend([push(x), push(y), String::from(name)]);
+                    program.push(format!("pop that {}", expected.len()));
+                    expected.push(operation(x, y));
+                }
+            }
+            run_vm_files(
+                "vm_translator_rs_arithmetic",
+                &[("Arithmetic.vm", &program.join("\n"))],
+                &options,
+                1_000_000,
+                |options, _, cpu| {
+                    let actual = &cpu.ram[3000..3000 + expected.len()];
+                    let mismatches: Vec<_> = actual
+                        .iter()
+                        .zip(&expected)
+                        .enumerate()
+                        .filter(|(_, (actual, expected))| actual != expected)
+                        .collect();
+                    assert!(
+                        mismatches.is_empty(),
+                        "{name} ({options:?}): {mismatches:?}"
+                    );
+                    assert_eq!(cpu.ram[0], 256);
+                    assert_eq!(cpu.ram[TRAP_ERROR_ADDRESS as usize], 0);
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn test_instruction_round_trip() {
+        let lines = [
+            "add",
+            "shr",
+            "push constant 7",
+            "pop static 3",
+            "push pointer 1",
+            "label WHILE_END0",
+            "goto Main.main:x",
+            "if-goto LOOP",
+            "function Main.main 2",
+            "call Math.multiply 2",
+            "return",
+        ];
+        for line in lines {
+            let instruction: ParsedVMInstruction = line.parse().unwrap();
+            assert_eq!(instruction.to_string(), line);
+        }
+        for segment in [
+            "local", "argument", "this", "that", "constant", "static", "pointer", "temp",
+        ] {
+            assert_eq!(
+                segment.parse::<MemorySegment>().unwrap().to_string(),
+                segment
+            );
+        }
+    }
+
+    struct Rng(u32);
+
+    impl Rng {
+        fn below(&mut self, bound: u32) -> u32 {
+            // xorshift32, so that failures are reproducible
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 % bound
+        }
+
+        fn pick<T: Clone>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len() as u32) as usize].clone()
+        }
+
+        fn symbol(&mut self) -> String {
+            let first: Vec<char> = ('a'..='z').chain('A'..='Z').chain("_.:".chars()).collect();
+            let rest: Vec<char> = first.iter().copied().chain('0'..='9').collect();
+            let len = self.below(12);
+            let mut symbol = String::from(self.pick(&first));
+            symbol.extend((0..len).map(|_| self.pick(&rest)));
+            symbol
+        }
+
+        fn count(&mut self) -> u16 {
+            self.below(32768) as u16
+        }
+
+        fn segment_and_index(&mut self, segments: &[MemorySegment]) -> (MemorySegment, u16) {
+            let segment = self.pick(segments);
+            let n_indices = match segment {
+                MemorySegment::Temp => 8,
+                MemorySegment::Pointer => 2,
+                MemorySegment::Static => MAX_STATICS as u32,
+                _ => 32768,
+            };
+            let idx = self.below(n_indices) as u16;
+            (segment, idx)
+        }
+
+        fn instruction(&mut self) -> ParsedVMInstruction {
+            let segments = [
+                MemorySegment::Local,
+                MemorySegment::Argument,
+                MemorySegment::This,
+                MemorySegment::That,
+                MemorySegment::Constant,
+                MemorySegment::Static,
+                MemorySegment::Pointer,
+                MemorySegment::Temp,
+            ];
+            match self.below(22) {
+                0 => ParsedVMInstruction::Add,
+                1 => ParsedVMInstruction::Sub,
+                2 => ParsedVMInstruction::Neg,
+                3 => ParsedVMInstruction::Eq,
+                4 => ParsedVMInstruction::Gt,
+                5 => ParsedVMInstruction::Lt,
+                6 => ParsedVMInstruction::And,
+                7 => ParsedVMInstruction::Or,
+                8 => ParsedVMInstruction::Not,
+                9 => ParsedVMInstruction::Mul,
+                10 => ParsedVMInstruction::Div,
+                11 => ParsedVMInstruction::Mod,
+                12 => ParsedVMInstruction::Shl,
+                13 => ParsedVMInstruction::Shr,
+                14 => {
+                    // Anything but the constant segment can be popped to
+                    let segments: Vec<MemorySegment> = segments
+                        .into_iter()
+                        .filter(|segment| *segment != MemorySegment::Constant)
+                        .collect();
+                    let (segment, idx) = self.segment_and_index(&segments);
+                    ParsedVMInstruction::Pop { segment, idx }
+                }
+                15 => {
+                    let (segment, idx) = self.segment_and_index(&segments);
+                    ParsedVMInstruction::Push { segment, idx }
+                }
+                16 => ParsedVMInstruction::Label {
+                    label: self.symbol(),
+                },
+                17 => ParsedVMInstruction::Goto {
+                    label: self.symbol(),
+                },
+                18 => ParsedVMInstruction::IfGoto {
+                    label: self.symbol(),
+                },
+                19 => ParsedVMInstruction::Function {
+                    name: self.symbol(),
+                    n_vars: self.count(),
+                },
+                20 => ParsedVMInstruction::Call {
+                    function: self.symbol(),
+                    n_args: self.count(),
+                },
+                _ => ParsedVMInstruction::Return,
+            }
+        }
+    }
+
+    #[test]
+    fn test_instruction_round_trip_property() {
+        // Every instruction that can be built, written as a VM command,
+        // parses back to itself
+        let mut rng = Rng(0x2545_f491);
+        let mut variants = HashSet::new();
+        for _ in 0..5000 {
+            let instruction = rng.instruction();
+            let line = instruction.to_string();
+            assert_eq!(line.parse(), Ok(instruction.clone()), "{line}");
+            variants.insert(discriminant(&instruction));
+        }
+        assert_eq!(variants.len(), 22);
+    }
+
+    #[test]
+    fn test_translate_program() {
+        // Translating in memory gives the same assembly as translating the
+        // files, as typed instructions
+        let test_dir = env::current_dir()
+            .unwrap()
+            .join("../../08/FunctionCalls/FibonacciElement");
+        let infiles = vm_files(&test_dir);
+        let sources: Vec<(String, String)> = infiles
+            .iter()
+            .map(|infile| {
+                let file_name = infile.file_name().unwrap().to_str().unwrap();
+                (file_name.to_owned(), read_to_string(infile).unwrap())
+            })
+            .collect();
+        let files: Vec<(&str, &str)> = sources
+            .iter()
+            .map(|(file_name, source)| (file_name.as_str(), source.as_str()))
+            .collect();
+        let options = TranslationOptions {
+            optimize: true,
+            annotate: true,
+            ..Default::default()
+        };
+        let instructions = translate_program(&files, &options).unwrap();
+        let asm: Vec<String> = instructions.iter().map(ToString::to_string).collect();
+        assert_eq!(asm, translate_files(&infiles, &options).unwrap().asm());
+        assert!(instructions.contains(&AsmInstruction::Label(String::from("Main.fibonacci"))));
+        run_test_script(&test_dir, "FibonacciElement", &asm);
+
+        let (file_name, source) = sources.iter().find(|(name, _)| name == "Main.vm").unwrap();
+        let parsed = parse_str(file_name, source).unwrap();
+        assert_eq!(parsed, parse_file(&test_dir.join("Main.vm")).unwrap());
+
+        let errors =
+            translate_program(&[("Main.vm", "// comment\npush nowhere 1")], &options).unwrap_err();
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["Main.vm:2: Invalid memory segment: nowhere"]
+        );
+    }
+
+    #[test]
+    fn test_bytecode_input() {
+        // StaticsTest encoded as .vmb files translates to the same assembly,
+        // and a directory prefers a .vm file over its encoded copy
+        let source_dir = env::current_dir()
+            .unwrap()
+            .join("../../08/FunctionCalls/StaticsTest");
+        let test_dir = TempDir::new("vm_translator_rs_bytecode");
+        for infile in vm_files(&source_dir) {
+            let outfile = test_dir
+                .join(infile.file_name().unwrap())
+                .with_extension("vmb");
+            bytecode::write_file(&outfile, &parse_file(&infile).unwrap()).unwrap();
+        }
+        write(test_dir.join("Sys.vm"), "function Sys.init 0").unwrap();
+        let infiles: Vec<String> = vm_files(&test_dir)
+            .iter()
+            .map(|infile| infile.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(infiles, ["Class1.vmb", "Class2.vmb", "Sys.vm"]);
+        remove_file(test_dir.join("Sys.vm")).unwrap();
+
+        let options = TranslationOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let expected = translate_files(&vm_files(&source_dir), &options).unwrap();
+        let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+        assert_eq!(output.instructions, expected.instructions);
+        // Locations refer to the lines of the original .vm files
+        let lines = |output: &TranslationOutput| -> Vec<usize> {
+            output
+                .source_map
+                .iter()
+                .flatten()
+                .map(|location| location.line)
+                .collect()
+        };
+        assert_eq!(lines(&output), lines(&expected));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // Recursion far deeper than the stack allows without tail calls,
+        // including mutual recursion between functions with different
+        // argument counts
+        let program = [
+            "function Sys.init 0",
+            "push constant 3333",
+            "pop pointer 0",
+            "push constant 0",
+            "push constant 3000",
+            "call Sys.count 2",
+            "pop static 0",
+            "push constant 2001",
+            "call Sys.even 1",
+            "pop static 1",
+            "push constant 2000",
+            "call Sys.even 1",
+            "pop static 2",
+            "push pointer 0",
+            "pop static 3",
+            "label END",
+            "goto END",
+            // count(acc, n) = n == 0 ? acc : count(acc + 1, n - 1)
+            "function Sys.count 1",
+            "push argument 1",
+            "pop pointer 0",
+            "push argument 1",
+            "if-goto RECURSE",
+            "push argument 0",
+            "return",
+            "label RECURSE",
+            "push argument 0",
+            "push constant 1",
+            "add",
+            "push argument 1",
+            "push constant 1",
+            "sub",
+            "call Sys.count 2",
+            "return",
+            // even(n) = n == 0 || odd(n - 1, 7)
+            "function Sys.even 0",
+            "push argument 0",
+            "if-goto NONZERO",
+            "push constant 1",
+            "return",
+            "label NONZERO",
+            "push argument 0",
+            "push constant 1",
+            "sub",
+            "push constant 7",
+            "call Sys.odd 2",
+            "return",
+            // odd(n, _) = n != 0 && even(n - 1)
+            "function Sys.odd 1",
+            "push argument 0",
+            "if-goto NONZERO",
+            "push constant 0",
+            "return",
+            "label NONZERO",
+            "push argument 0",
+            "push constant 1",
+            "sub",
+            "call Sys.even 1",
+            "return",
+        ]
+        .join("\n");
+        let options = [(false, false), (false, true), (true, false), (true, true)].map(
+            |(optimize_size, optimize)| TranslationOptions {
+                optimize_size,
+                optimize,
+                checked: true,
+                ..Default::default()
+            },
+        );
+        run_vm_files(
+            "vm_translator_rs_tail_calls",
+            &[("Sys.vm", &program)],
+            &options,
+            3_000_000,
+            |options, _, cpu| {
+                if options.optimize {
+                    assert_eq!(cpu.ram[TRAP_ERROR_ADDRESS as usize], 0, "{options:?}");
+                    assert_eq!(cpu.ram[16..20], [3000, 0, 1, 3333], "{options:?}");
+                    // Back in Sys.init with an empty working stack
+                    assert_eq!(cpu.ram[0], 261, "{options:?}");
+                } else {
+                    assert_eq!(cpu.ram[TRAP_ERROR_ADDRESS as usize], 1, "{options:?}");
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn test_assemble_debug_map() {
+        let test_dir = env::current_dir()
+            .unwrap()
+            .join("../../08/FunctionCalls/StaticsTest");
+        let options = TranslationOptions {
+            annotate: true,
+            ..Default::default()
+        };
+        let output = translate_files(&vm_files(&test_dir), &options).unwrap();
+        let assembly = output.assemble();
+        assert_eq!(assembly.hack.len(), word_count(&output.instructions));
+        let debug_map = output.debug_map_lines(&assembly);
+        // ROM addresses before the first mapped one belong to the bootstrap,
+        // and `function Class1.set 0` only declares a label
+        let bootstrap_words = word_count(&super::translator::bootstrap());
+        assert_eq!(debug_map[1], format!("{bootstrap_words} Class1.vm:8"));
+        let class1_set = assembly
+            .labels
+            .iter()
+            .find(|(label, _)| label == "Class1.set")
+            .unwrap();
+        assert!(debug_map.contains(&format!("Class1.set {}", class1_set.1)));
+        // Statics are allocated in order of first use
+        let statics: Vec<&str> = debug_map
+            .iter()
+            .skip_while(|line| *line != "// variable ram_address")
+            .skip(1)
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            statics,
+            ["Class1.0 16", "Class1.1 17", "Class2.0 18", "Class2.1 19"]
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_BasicTest() {
+        translate_and_run_test_script("../MemoryAccess/BasicTest", "BasicTest", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_PointerTest() {
+        translate_and_run_test_script("../MemoryAccess/PointerTest", "PointerTest", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_StaticTest() {
+        translate_and_run_test_script("../MemoryAccess/StaticTest", "StaticTest", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_BasicLoop() {
+        translate_and_run_test_script("../../08/ProgramFlow/BasicLoop", "BasicLoop", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_FibonacciSeries() {
+        translate_and_run_test_script(
+            "../../08/ProgramFlow/FibonacciSeries",
+            "FibonacciSeries",
+            false,
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_SimpleFunction() {
+        translate_and_run_test_script(
+            "../../08/FunctionCalls/SimpleFunction",
+            "SimpleFunction",
+            false,
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_NestedCall() {
+        translate_and_run_test_script("../../08/FunctionCalls/NestedCall", "NestedCall", false);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn test_FibonacciElement() {
+        translate_and_run_test_script(
+            "../../08/FunctionCalls/FibonacciElement",
+            "FibonacciElement",
+            true,
+        );
     }
 
     #[test]
-    #[should_panic]
-    fn test_parse_invalid_push_instruction() {
-        let _parsed_instruction = parse_instruction("push constant");
+    #[allow(non_snake_case)]
+    fn test_StaticsTest() {
+        translate_and_run_test_script("../../08/FunctionCalls/StaticsTest", "StaticsTest", true);
     }
 }